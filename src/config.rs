@@ -0,0 +1,188 @@
+//! Defaults for the command line options, read from a config file.
+//!
+//! The value of an option is taken from, in order of precedence:
+//!
+//! 1. the command line,
+//! 2. the config file,
+//! 3. the built-in default of the option.
+//!
+//! The config file is a TOML file whose top-level keys set the global
+//! options, and whose tables set the options of the pipeline of the same
+//! name, e.g.
+//!
+//! ```toml
+//! name = "unit1"
+//!
+//! [visual_vocab]
+//! name = "Jane Doe"
+//! period = "3"
+//! row = 4
+//! ```
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::Command;
+use spanish_pipeline::error::CliError;
+use toml::{Table, Value};
+
+/// The name of the config file searched for in the current and home
+/// directories
+pub const CONFIG_FILE_NAME: &str = "spanish-pipeline.toml";
+
+/// Represents a loaded config file
+#[derive(Debug, Default)]
+pub struct Config {
+    path: PathBuf,
+    table: Table,
+}
+
+impl Config {
+    /// Load the config file at `path`, or if `None`, the first config file
+    /// found in the current directory then the home directory. An empty
+    /// config is returned if no config file is found.
+    pub fn load(path: Option<&Path>) -> Result<Self, CliError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Self::find() {
+                Some(path) => path,
+                None => return Ok(Self::default()),
+            },
+        };
+        let content = fs::read_to_string(&path).map_err(|e| {
+            CliError::new(&format!(
+                "failed to read config file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        Self::parse(path, &content)
+    }
+
+    /// Parse the content of the config file at `path`
+    pub fn parse(path: PathBuf, content: &str) -> Result<Self, CliError> {
+        let table = content.parse::<Table>().map_err(|e| {
+            CliError::new(&format!(
+                "failed to parse config file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        Ok(Self { path, table })
+    }
+
+    /// Find the config file in the current directory then the home directory
+    fn find() -> Option<PathBuf> {
+        [std::env::current_dir().ok(), dirs::home_dir()]
+            .into_iter()
+            .flatten()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file())
+    }
+
+    /// Set the defaults of the options of `command` from the top-level keys
+    /// of the config, or from the table `section` if given
+    pub fn apply(
+        &self,
+        mut command: Command,
+        section: Option<&str>,
+    ) -> Result<Command, CliError> {
+        let table = match section {
+            Some(section) => match self.table.get(section) {
+                Some(Value::Table(table)) => table,
+                Some(_) => {
+                    return Err(self.error(section, "expected a table"));
+                }
+                None => return Ok(command),
+            },
+            None => &self.table,
+        };
+        for (key, value) in table {
+            let key_path = match section {
+                Some(section) => format!("{}.{}", section, key),
+                None => key.to_owned(),
+            };
+            let value = match value {
+                Value::String(value) => value.to_owned(),
+                Value::Integer(value) => value.to_string(),
+                Value::Float(value) => value.to_string(),
+                Value::Boolean(value) => value.to_string(),
+                // pipeline tables are applied to the pipelines themselves
+                Value::Table(_) if section.is_none() => continue,
+                _ => return Err(self.error(&key_path, "unsupported value")),
+            };
+            let id = key.replace('-', "_");
+            if !command.get_arguments().any(|arg| arg.get_id() == &id) {
+                return Err(self.error(&key_path, "unknown option"));
+            }
+            command = command
+                .mut_arg(id, |arg| arg.default_value(value).required(false));
+        }
+        Ok(command)
+    }
+
+    fn error(&self, key: &str, message: &str) -> CliError {
+        CliError::new(&format!(
+            "invalid config {} in {}: {}",
+            key,
+            self.path.display(),
+            message
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::{CommandFactory, FromArgMatches, Parser};
+
+    use super::*;
+
+    #[derive(Debug, Parser)]
+    struct Options {
+        #[clap(short, long, default_value = "3")]
+        row: u32,
+        #[clap(long)]
+        no_ml: bool,
+        name: String,
+    }
+
+    fn parse(config: &str, args: &[&str]) -> Result<Options, String> {
+        let config = Config::parse(PathBuf::from("test.toml"), config)
+            .map_err(|e| e.to_string())?;
+        let command = config
+            .apply(Options::command(), Some("options"))
+            .map_err(|e| e.to_string())?;
+        let matches = command
+            .try_get_matches_from(args)
+            .map_err(|e| e.to_string())?;
+        Options::from_arg_matches(&matches).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let config = "[options]\nrow = 4\nno-ml = true\nname = \"Jane\"\n";
+        let options = parse(config, &["options"]).unwrap();
+        assert_eq!(options.row, 4);
+        assert!(options.no_ml);
+        assert_eq!(options.name, "Jane");
+
+        let options = parse(config, &["options", "-r", "5", "John"]).unwrap();
+        assert_eq!(options.row, 5);
+        assert_eq!(options.name, "John");
+
+        let options = parse("", &["options", "John"]).unwrap();
+        assert_eq!(options.row, 3);
+        assert!(!options.no_ml);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        assert!(parse("[options]\ncolumn = 4\n", &["options"])
+            .unwrap_err()
+            .contains("options.column"));
+        assert!(parse("options = 4\n", &["options"]).is_err());
+        assert!(parse("[options\n", &["options"]).is_err());
+    }
+}
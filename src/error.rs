@@ -4,12 +4,25 @@ use std::{error::Error, fmt::Display};
 #[derive(Debug)]
 pub struct CliError {
     message: String,
+    source: Option<Box<dyn Error + Send + Sync>>,
 }
 
 impl CliError {
     pub fn new(message: &str) -> Self {
         Self {
             message: message.to_string(),
+            source: None,
+        }
+    }
+
+    /// Create an error caused by `source`, e.g. a failed request
+    pub fn with_source(
+        message: &str,
+        source: impl Error + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            message: message.to_string(),
+            source: Some(Box::new(source)),
         }
     }
 }
@@ -24,4 +37,8 @@ impl Error for CliError {
     fn description(&self) -> &str {
         &self.message
     }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|x| x as _)
+    }
 }
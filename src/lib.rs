@@ -0,0 +1,8 @@
+//! This project aims to create a simple web crawler to faciliate the completion of Spanish homework.
+//!
+//! The pipelines can be used as a library by constructing them with their
+//! `new` functions and chaining their [`pipeline::Pipeline::run`]s.
+
+pub mod error;
+pub mod pipeline;
+pub mod spider;
@@ -1,30 +1,179 @@
 //! This project aims to create a simple web crawler to faciliate the completion of Spanish homework.
 
-pub mod error;
-pub mod pipeline;
-pub mod spider;
+mod config;
 
-use clap::Parser;
+use std::{
+    error::Error,
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use clap::{
+    error::{ContextKind, ContextValue, ErrorKind},
+    Arg, Command, CommandFactory, FromArgMatches, Parser, ValueEnum,
+};
+use config::Config;
 use fern::colors::{Color, ColoredLevelConfig};
-use log::info;
-use pipeline::Pipeline;
+use log::{info, warn};
+use spanish_pipeline::{
+    error::CliError,
+    pipeline::{
+        anki::AnkiPipeline, define::DefinePipeline,
+        dictionary::DictionaryPipeline, enrich::EnrichPipeline,
+        load::LoadPipeline, notion::NotionPipeline,
+        transform::TransformPipeline, visual_vocab::VisualVocabPipeline,
+        Interrupt, Pipeline, PipelineError, PipelineErrorKind, PipelineIO,
+        RunContext,
+    },
+    spider::{spanish_dict::load_keyword_model, SpiderError, SpiderErrorKind},
+};
+
+/// Describes how to parse a pipeline from its arguments
+struct PipelineSpec {
+    name: &'static str,
+    command: fn() -> Command,
+    parse: fn(Command, &[String]) -> Result<Box<dyn Pipeline>, clap::Error>,
+}
+
+/// Parse a pipeline from its arguments, the first being its name, with the
+/// options defined by `command`
+fn parse_pipeline<P: Pipeline + Parser + 'static>(
+    mut command: Command,
+    args: &[String],
+) -> Result<Box<dyn Pipeline>, clap::Error> {
+    let matches = command.try_get_matches_from_mut(args)?;
+    let pipeline =
+        P::from_arg_matches(&matches).map_err(|e| e.format(&mut command))?;
+    Ok(Box::new(pipeline))
+}
 
-const PIPELINES: [&str; 3] = ["load", "visual_vocab", "transform"];
+const PIPELINES: [PipelineSpec; 8] = [
+    PipelineSpec {
+        name: "load",
+        command: LoadPipeline::command,
+        parse: parse_pipeline::<LoadPipeline>,
+    },
+    PipelineSpec {
+        name: "visual_vocab",
+        command: VisualVocabPipeline::command,
+        parse: parse_pipeline::<VisualVocabPipeline>,
+    },
+    PipelineSpec {
+        name: "enrich",
+        command: EnrichPipeline::command,
+        parse: parse_pipeline::<EnrichPipeline>,
+    },
+    PipelineSpec {
+        name: "define",
+        command: DefinePipeline::command,
+        parse: parse_pipeline::<DefinePipeline>,
+    },
+    PipelineSpec {
+        name: "dictionary",
+        command: DictionaryPipeline::command,
+        parse: parse_pipeline::<DictionaryPipeline>,
+    },
+    PipelineSpec {
+        name: "transform",
+        command: TransformPipeline::command,
+        parse: parse_pipeline::<TransformPipeline>,
+    },
+    PipelineSpec {
+        name: "anki",
+        command: AnkiPipeline::command,
+        parse: parse_pipeline::<AnkiPipeline>,
+    },
+    PipelineSpec {
+        name: "notion",
+        command: NotionPipeline::command,
+        parse: parse_pipeline::<NotionPipeline>,
+    },
+];
+
+/// Represents the formats of the logs
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// `[target] [LEVEL] message`, colored on the terminal
+    Pretty,
+    /// One JSON object per record
+    Json,
+}
 
 #[derive(Parser)]
+#[command(
+    subcommand_value_name = "PIPELINE",
+    subcommand_help_heading = "Pipelines",
+    after_help = "Pipelines are run in order, each receiving the output of \
+                  the previous one, e.g. `load vocab.docx transform -o pdf`. \
+                  A `--` makes the following argument a value rather than \
+                  a pipeline.\n\n\
+                  On Ctrl-C, the running pipeline stops and its output so \
+                  far is dumped, e.g. the visual_vocab sheet of the cards \
+                  gathered, the other pipelines finishing their work first. \
+                  A second Ctrl-C stops at once.\n\n\
+                  Exits with 2 on invalid arguments or input, 3 on network \
+                  failures, 4 on model failures, 5 on typst failures, 6 on \
+                  file errors, 130 on interrupt and 1 otherwise."
+)]
 struct Cli {
     /// The name of the group of output files.
     #[clap(short, long, default_value = "default")]
     name: String,
 
+    /// The base directory of the output files.
+    #[clap(long, env = "SPANISH_PIPELINE_OUT", default_value = "./out")]
+    out_dir: PathBuf,
+
     /// The log level.
     #[clap(short, long, default_value = "info")]
     level: log::LevelFilter,
 
+    /// The log levels of specific targets, overriding the log level, e.g.
+    /// spanish_dict=debug,image_search=warn.
+    #[clap(long, value_parser = parse_log_target, value_delimiter = ',')]
+    log: Vec<(String, log::LevelFilter)>,
+
     /// Quiet mode.
     #[clap(short, long)]
     quiet: bool,
 
+    /// The format of the logs. Colors are disabled in json.
+    #[clap(long, default_value = "pretty")]
+    log_format: LogFormat,
+
+    /// Also write the logs to this file, which is rotated once it grows
+    /// large. Unlike the other logs, it is written in quiet mode.
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+
+    /// Log the work of each pipeline without any network I/O or writing
+    /// files.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// The largest number of flashcards passed from one pipeline to the
+    /// next, the rest being dropped.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_flashcards: Option<u64>,
+
+    /// Write the final output to stdout instead of the output directory,
+    /// e.g. to pipe it into another program. All the logs go to stderr.
+    #[clap(long)]
+    stdout: bool,
+
+    /// The config file providing the defaults of the options. Defaults to
+    /// spanish-pipeline.toml in the current directory, then the home
+    /// directory.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Write a sample deck explaining the format of flashcard files to
+    /// flashcard.yml in the current directory. The pipelines are optional.
+    #[clap(long)]
+    init: bool,
+
     #[clap(skip)]
     pipelines: Vec<Box<dyn Pipeline>>,
 }
@@ -33,8 +182,17 @@ impl std::fmt::Debug for Cli {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Cli")
             .field("name", &self.name)
+            .field("out_dir", &self.out_dir)
             .field("level", &self.level)
+            .field("log", &self.log)
             .field("quiet", &self.quiet)
+            .field("log_format", &self.log_format)
+            .field("log_file", &self.log_file)
+            .field("dry_run", &self.dry_run)
+            .field("max_flashcards", &self.max_flashcards)
+            .field("stdout", &self.stdout)
+            .field("config", &self.config)
+            .field("init", &self.init)
             .field(
                 "pipelines",
                 &self.pipelines.iter().map(|p| p.name()).collect::<Vec<_>>(),
@@ -43,111 +201,758 @@ impl std::fmt::Debug for Cli {
     }
 }
 
+/// Parse a `target=level` pair of the log levels of specific targets
+fn parse_log_target(arg: &str) -> Result<(String, log::LevelFilter), String> {
+    let (target, level) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected target=level, got {}", arg))?;
+    let level = level
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid log level {} for {}", level, target))?;
+    Ok((target.trim().to_string(), level))
+}
+
+/// The size above which the log file is rotated
+const LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The number of rotated log files kept
+const LOG_FILE_KEEP: usize = 3;
+
+/// Rotate the log file if it has grown too large, moving `path` to `path.1`,
+/// `path.1` to `path.2` and so on, dropping the oldest
+fn rotate_log_file(path: &Path) -> std::io::Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() >= LOG_FILE_MAX_SIZE => {}
+        _ => return Ok(()),
+    }
+    let rotated = |i: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", i));
+        PathBuf::from(name)
+    };
+    for i in (1..LOG_FILE_KEEP).rev() {
+        if rotated(i).exists() {
+            std::fs::rename(rotated(i), rotated(i + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated(1))
+}
+
+/// The sample deck written by `--init`
+const INIT_TEMPLATE: &str = include_str!("templates/flashcard.yml");
+
+/// The file that `--init` writes the sample deck to
+const INIT_PATH: &str = "flashcard.yml";
+
+/// Write the sample deck to `path`, refusing to overwrite an existing file
+fn write_init_template(path: &Path) -> std::io::Result<()> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?
+        .write_all(INIT_TEMPLATE.as_bytes())
+}
+
+/// Drop the flashcards of an output beyond the first `max`
+fn limit_flashcards(output: PipelineIO, max: Option<u64>) -> PipelineIO {
+    match (output, max) {
+        (PipelineIO::Flashcard(mut flashcards), Some(max))
+            if flashcards.len() as u64 > max =>
+        {
+            info!(target: "main", "Keeping the first {} of {} flashcards", max, flashcards.len());
+            flashcards.truncate(max as usize);
+            PipelineIO::Flashcard(flashcards)
+        }
+        (output, _) => output,
+    }
+}
+
+/// Return the prefix of the logs naming the running pipeline, e.g.
+/// `[2/3 visual_vocab] `
+fn stage_prefix() -> String {
+    RunContext::current()
+        .map(|context| format!("[{}] ", context))
+        .unwrap_or_default()
+}
+
+/// Format a log record as a JSON object
+fn format_json(
+    out: fern::FormatCallback,
+    message: &std::fmt::Arguments,
+    record: &log::Record,
+) {
+    let record = serde_json::json!({
+        "timestamp": humantime::format_rfc3339_millis(
+            std::time::SystemTime::now()
+        ).to_string(),
+        "target": record.target(),
+        "level": record.level().as_str(),
+        "message": message.to_string(),
+        "stage": RunContext::current().map(|context| context.to_string()),
+    });
+    out.finish(format_args!("{}", record))
+}
+
+/// Return the command of the global options, listing the pipelines
+fn global_command() -> Command {
+    Cli::command().subcommands(
+        PIPELINES
+            .iter()
+            .map(|spec| (spec.command)().name(spec.name)),
+    )
+}
+
+/// Check whether `arg` is an option of `command` whose value is the next
+/// argument
+fn takes_value(command: &Command, arg: &str) -> bool {
+    let takes_value =
+        |arg: Option<&Arg>| arg.is_some_and(|x| x.get_action().takes_values());
+    if let Some(long) = arg.strip_prefix("--") {
+        !long.contains('=')
+            && takes_value(
+                command.get_arguments().find(|x| x.get_long() == Some(long)),
+            )
+    } else if let Some(shorts) = arg.strip_prefix('-') {
+        // in a cluster of short flags, only the last one can take the next
+        // argument as its value
+        let mut shorts = shorts.chars().peekable();
+        while let Some(short) = shorts.next() {
+            let arg = command
+                .get_arguments()
+                .find(|x| x.get_short() == Some(short));
+            if takes_value(arg) {
+                return shorts.peek().is_none();
+            }
+            if arg.is_none() {
+                return false;
+            }
+        }
+        false
+    } else {
+        false
+    }
+}
+
+/// Split the arguments into those of the global options and those of each
+/// pipeline. A pipeline name only starts a new pipeline where it is neither
+/// the value of the preceding option nor escaped by a preceding `--`.
+fn split_arguments(
+    args: &[String],
+) -> (Vec<String>, Vec<(&'static PipelineSpec, Vec<String>)>) {
+    let mut global = args.iter().take(1).cloned().collect::<Vec<_>>();
+    let mut pipelines: Vec<(&PipelineSpec, Vec<String>)> = Vec::new();
+    let mut command = global_command();
+    let mut is_value = false;
+    for arg in args.iter().skip(1) {
+        let spec = PIPELINES.iter().find(|spec| spec.name == arg);
+        if let (Some(spec), false) = (spec, is_value) {
+            command = (spec.command)();
+            pipelines.push((spec, vec![arg.to_owned()]));
+            continue;
+        }
+
+        let current = match pipelines.last_mut() {
+            Some((_, args)) => args,
+            None => &mut global,
+        };
+        current.push(arg.to_owned());
+        is_value = !is_value && (arg == "--" || takes_value(&command, arg));
+    }
+    (global, pipelines)
+}
+
+/// Check whether `arg` is one of the options of `command`
+fn has_option(command: &Command, arg: &str) -> bool {
+    if let Some(long) = arg.strip_prefix("--") {
+        let long = long.split('=').next().unwrap_or_default();
+        command.get_arguments().any(|x| x.get_long() == Some(long))
+    } else if let Some(short) = arg.strip_prefix('-') {
+        let short = short.chars().next();
+        command.get_arguments().any(|x| x.get_short() == short)
+    } else {
+        false
+    }
+}
+
+/// Point an unexpected argument of the pipeline `spec` at the pipeline of
+/// the chain that it is an option of, as it was most likely put after the
+/// wrong pipeline
+fn misplaced_argument(
+    err: clap::Error,
+    spec: &PipelineSpec,
+    pipelines: &[(&'static PipelineSpec, Vec<String>)],
+) -> clap::Error {
+    if err.kind() != ErrorKind::UnknownArgument {
+        return err;
+    }
+    let Some(ContextValue::String(arg)) = err.get(ContextKind::InvalidArg)
+    else {
+        return err;
+    };
+    let owner = pipelines.iter().find(|(other, _)| {
+        other.name != spec.name && has_option(&(other.command)(), arg)
+    });
+    match owner {
+        Some((owner, _)) => clap::Error::raw(
+            ErrorKind::UnknownArgument,
+            format!(
+                "unexpected argument '{}' for {}, it is an option of {} and \
+                 must come right after it\n",
+                arg, spec.name, owner.name
+            ),
+        ),
+        None => err,
+    }
+}
+
+/// Report an invalid config file as a command line error
+fn config_error(err: CliError) -> clap::Error {
+    clap::Error::raw(ErrorKind::InvalidValue, format!("{}\n", err))
+}
+
+/// Parses the given command line arguments and returns the corresponding
+/// pipelines, taking the defaults of the options from the config file.
+fn parse_arguments_from(args: &[String]) -> Result<Cli, clap::Error> {
+    let (global, _) = split_arguments(args);
+    let matches = global_command().try_get_matches_from(global)?;
+    let cli = Cli::from_arg_matches(&matches)?;
+    let config = Config::load(cli.config.as_deref()).map_err(config_error)?;
+    parse_arguments_with(&config, args)
+}
+
+/// Parses the given command line arguments with the defaults of the options
+/// taken from `config`.
+fn parse_arguments_with(
+    config: &Config,
+    args: &[String],
+) -> Result<Cli, clap::Error> {
+    let (global, pipelines) = split_arguments(args);
+    let matches = config
+        .apply(global_command(), None)
+        .map_err(config_error)?
+        .try_get_matches_from(global)?;
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    if pipelines.is_empty() && !cli.init {
+        return Err(global_command().error(
+            ErrorKind::MissingSubcommand,
+            format!(
+                "at least one pipeline is required: {}",
+                PIPELINES.map(|spec| spec.name).join(", ")
+            ),
+        ));
+    }
+    cli.pipelines = pipelines
+        .iter()
+        .map(|(spec, args)| {
+            let command = config
+                .apply((spec.command)(), Some(spec.name))
+                .map_err(config_error)?;
+            (spec.parse)(command, args)
+                .map_err(|err| misplaced_argument(err, spec, &pipelines))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(cli)
+}
+
 /// Parses the command line arguments and returns the corresponding pipelines.
-#[allow(unused_assignments)]
 fn parse_arguments() -> Cli {
     let args = std::env::args().collect::<Vec<String>>();
-    let mut pipelines = Vec::new();
-    let mut i = 1;
+    parse_arguments_from(&args).unwrap_or_else(|err| err.exit())
+}
 
-    // parse the cli arguments
-    let start = i;
-    while i < args.len() && !PIPELINES.contains(&args[i].as_str()) {
-        i += 1;
-    }
-    let mut cli =
-        Cli::parse_from([&["".to_string()], &args[start..i]].concat());
-
-    // parse the pipelines
-    while i < args.len() {
-        let pipeline = &args[i];
-        if !PIPELINES.contains(&pipeline.as_str()) {
-            Cli::parse_from(&["", "--help"]);
-            unreachable!("should have printed help");
-        }
-        let start = i;
-        i += 1;
-        while i < args.len() && !PIPELINES.contains(&args[i].as_str()) {
-            i += 1;
-        }
-        let args = &args[start..i];
-
-        let result: Box<dyn Pipeline> = match pipeline.as_str() {
-            "load" => Box::new(pipeline::load::LoadPipeline::parse_from(args)),
-            "visual_vocab" => Box::new(
-                pipeline::visual_vocab::VisualVocabPipeline::parse_from(args),
-            ),
-            "transform" => Box::new(
-                pipeline::transform::TransformPipeline::parse_from(args),
-            ),
-            _ => unreachable!(),
-        };
+/// The exit code of a failure of no particular class
+const EXIT_FAILURE: u8 = 1;
 
-        pipelines.push(result);
-    }
-    if pipelines.is_empty() {
-        Cli::parse_from(&["", "--help"]);
-        unreachable!("should have printed help");
+/// The exit code of invalid arguments or input, the same as clap's
+const EXIT_USAGE: u8 = 2;
+
+/// The exit code of a failed request to SpanishDict or the image search
+const EXIT_NETWORK: u8 = 3;
+
+/// The exit code of a machine learning model that failed to load
+const EXIT_MODEL: u8 = 4;
+
+/// The exit code of typst failing to compile the flashcards
+const EXIT_TYPST: u8 = 5;
+
+/// The exit code of a failure to read or write a file
+const EXIT_IO: u8 = 6;
+
+/// Return the exit code of the class of `err`, looking through the errors
+/// that caused it until one of a known class
+fn exit_code(err: &(dyn Error + 'static)) -> u8 {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<SpiderError>() {
+            match err.kind() {
+                SpiderErrorKind::Model => return EXIT_MODEL,
+                SpiderErrorKind::Network => return EXIT_NETWORK,
+                _ => {}
+            }
+        }
+        if let Some(err) = err.downcast_ref::<PipelineError>() {
+            if err.kind() == PipelineErrorKind::Typst {
+                return EXIT_TYPST;
+            }
+        }
+        if err.is::<reqwest::Error>() {
+            return EXIT_NETWORK;
+        }
+        if err.is::<rust_bert::RustBertError>() {
+            return EXIT_MODEL;
+        }
+        if err.is::<std::io::Error>() {
+            return EXIT_IO;
+        }
+        // an error caused by another is of the class of its cause
+        if err.is::<CliError>() && err.source().is_none() {
+            return EXIT_USAGE;
+        }
+        source = err.source();
     }
-    cli.pipelines = pipelines;
-    cli
+    EXIT_FAILURE
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from(exit_code(err.as_ref()))
+        }
+    }
+}
+
+/// Run the pipelines of the command line arguments
+async fn run() -> Result<(), Box<dyn Error>> {
     // parse the cli arguments
     let Cli {
         name,
+        out_dir,
         level,
+        log,
         pipelines,
         quiet,
+        log_format,
+        log_file,
+        dry_run,
+        max_flashcards,
+        stdout,
+        init,
+        ..
     } = parse_arguments();
     let colors = ColoredLevelConfig::new()
         .info(Color::Green)
         .warn(Color::Yellow)
         .error(Color::Magenta);
-    let mut dispatch =
-        fern::Dispatch::new().format(move |out, message, record| {
-            // if terminal
-            out.finish(format_args!(
-                "[{}] [{}] {}",
-                record.target(),
-                colors.color(record.level()),
-                message
-            ))
-        });
-    dispatch = match quiet {
-        true => dispatch.level(log::LevelFilter::Off),
-        false => dispatch
-            .level(level)
-            .chain(
-                fern::Dispatch::new()
-                    .level(log::LevelFilter::Warn)
-                    .chain(std::io::stderr()),
+    let mut dispatch = log.into_iter().fold(
+        fern::Dispatch::new().level(level),
+        |dispatch, (target, level)| dispatch.level_for(target, level),
+    );
+    if !quiet {
+        let console = match log_format {
+            LogFormat::Pretty => {
+                fern::Dispatch::new().format(move |out, message, record| {
+                    // if terminal
+                    out.finish(format_args!(
+                        "{}[{}] [{}] {}",
+                        stage_prefix(),
+                        record.target(),
+                        colors.color(record.level()),
+                        message
+                    ))
+                })
+            }
+            LogFormat::Json => fern::Dispatch::new().format(format_json),
+        };
+        dispatch = if stdout {
+            // stdout is kept clean for the output
+            dispatch.chain(console.chain(std::io::stderr()))
+        } else {
+            dispatch.chain(
+                console
+                    .chain(
+                        fern::Dispatch::new()
+                            .level(log::LevelFilter::Warn)
+                            .chain(std::io::stderr()),
+                    )
+                    // the levels above are already filtered, so that targets
+                    // set to debug reach stdout, while warnings only go to
+                    // stderr
+                    .chain(
+                        fern::Dispatch::new()
+                            .filter(|metadata| {
+                                metadata.level() > log::Level::Warn
+                            })
+                            .chain(std::io::stdout()),
+                    ),
             )
-            .chain(
-                fern::Dispatch::new()
-                    .level(log::LevelFilter::Info)
-                    .chain(std::io::stdout()),
-            ),
-    };
+        };
+    }
+    if let Some(log_file) = log_file {
+        rotate_log_file(&log_file)?;
+        let file = match log_format {
+            LogFormat::Pretty => {
+                fern::Dispatch::new().format(|out, message, record| {
+                    out.finish(format_args!(
+                        "{} {}[{}] [{}] {}",
+                        humantime::format_rfc3339_seconds(
+                            std::time::SystemTime::now()
+                        ),
+                        stage_prefix(),
+                        record.target(),
+                        record.level(),
+                        message
+                    ))
+                })
+            }
+            LogFormat::Json => fern::Dispatch::new().format(format_json),
+        };
+        dispatch = dispatch.chain(file.chain(fern::log_file(log_file)?));
+    }
     dispatch.apply()?;
 
     info!(target: "main", "logger initialized");
 
-    // run the pipelines
+    if init {
+        let path = Path::new(INIT_PATH);
+        if dry_run {
+            info!(target: "main", "would write a sample deck to {}", path.display());
+        } else {
+            write_init_template(path).map_err(|e| {
+                CliError::new(&format!(
+                    "failed to write {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            info!(target: "main", "wrote a sample deck to {}, load it with `load {}`", path.display(), path.display());
+        }
+        if pipelines.is_empty() {
+            return Ok(());
+        }
+    }
+
+    // fail before running anything if the models cannot load
+    if !dry_run && pipelines.iter().any(|x| x.needs_models()) {
+        load_keyword_model().await.map_err(|e| {
+            CliError::with_source(
+                &format!("the models are needed before running: {}", e),
+                e,
+            )
+        })?;
+    }
+
+    // run the pipelines. On interrupt, the running pipeline returns what it
+    // has so far, which is dumped in place of the final output.
     let mut input = None;
-    for pipeline in pipelines {
-        info!(target: "main", "running pipeline: {}", pipeline.name());
-        input = Some(pipeline.run(input).await?);
-        info!(target: "main", "finished pipeline: {}", pipeline.name());
+    let mut interrupted = false;
+    let interrupt = Interrupt::default();
+    let mut stats = vec![];
+    let start = Instant::now();
+    let total = pipelines.len();
+    for (i, mut pipeline) in pipelines.into_iter().enumerate() {
+        pipeline.set_out_dir(&out_dir.join(&name));
+        pipeline.set_interrupt(interrupt.clone());
+        let context = RunContext {
+            stage: i + 1,
+            total,
+            name: pipeline.name(),
+        };
+        let stage = async {
+            info!(target: "main", "running pipeline: {}", pipeline.name());
+            let output = if dry_run {
+                pipeline.dry_run(input.take())
+            } else {
+                pipeline.run(input.take())
+            };
+            tokio::pin!(output);
+            let output = tokio::select! {
+                output = &mut output => output,
+                _ = tokio::signal::ctrl_c() => {
+                    warn!(target: "main", "interrupted while running pipeline: {}, keeping its output so far, press Ctrl-C again to stop at once", pipeline.name());
+                    interrupted = true;
+                    interrupt.interrupt();
+                    tokio::select! {
+                        output = output => output,
+                        _ = tokio::signal::ctrl_c() => {
+                            warn!(target: "main", "stopped without dumping the output");
+                            std::process::exit(130);
+                        }
+                    }
+                }
+            };
+            if output.is_ok() && !interrupted {
+                info!(target: "main", "finished pipeline: {}", pipeline.name());
+            }
+            output
+        };
+        let output = context.scope(stage).await;
+        input = Some(limit_flashcards(output?, max_flashcards));
+        // each stage reports its own counters, the cards of a stage being
+        // the flashcards of the next
+        if let Some(x) = pipeline.stats() {
+            stats.push(format!("{}: {}", pipeline.name(), x));
+        }
+        if interrupted {
+            break;
+        }
+    }
+    if !interrupted {
+        info!(target: "main", "finished");
     }
-    info!(target: "main", "finished");
+    stats.push(format!(
+        "took {}",
+        humantime::format_duration(Duration::from_secs(
+            start.elapsed().as_secs_f64().round() as u64
+        ))
+    ));
+    info!(target: "main", "{}", stats.join("; "));
 
     // dump the output
     if let Some(output) = input {
-        output.dump(&name)?;
-        info!(target: "main", "dumped output");
+        if stdout {
+            if dry_run {
+                info!(target: "main", "would write output to stdout");
+                return Ok(());
+            }
+            output.write_to(&mut std::io::stdout().lock())?;
+        } else if dry_run {
+            for path in output.dump_paths(&out_dir, &name)? {
+                info!(target: "main", "would dump output to {}", path.display());
+            }
+            return Ok(());
+        } else {
+            for path in output.dump(&out_dir, &name)? {
+                info!(target: "main", "dumped output to {}", path.display());
+            }
+        }
+    }
+    if interrupted {
+        // the conventional exit status of a process killed by SIGINT
+        std::process::exit(130);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use spanish_pipeline::pipeline::Flashcard;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        parse_with_config("", args)
+    }
+
+    fn parse_with_config(
+        config: &str,
+        args: &[&str],
+    ) -> Result<Cli, clap::Error> {
+        let config = Config::parse(PathBuf::from("test.toml"), config)
+            .map_err(config_error)?;
+        let args = args.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        parse_arguments_with(&config, &args)
+    }
+
+    fn names(cli: &Cli) -> Vec<&'static str> {
+        cli.pipelines.iter().map(|x| x.name()).collect()
+    }
+
+    #[test]
+    fn test_chain_pipelines() {
+        let cli = parse(&["sp", "-n", "unit1", "load", "transform"]).unwrap();
+        assert_eq!(cli.name, "unit1");
+        assert_eq!(names(&cli), ["load", "transform"]);
+    }
+
+    #[test]
+    fn test_flag_value_colliding_with_pipeline() {
+        let cli =
+            parse(&["sp", "--name", "load", "load", "transform"]).unwrap();
+        assert_eq!(cli.name, "load");
+        assert_eq!(names(&cli), ["load", "transform"]);
+
+        let cli = parse(&["sp", "load", "transform", "-n", "load"]).unwrap();
+        assert_eq!(names(&cli), ["load", "transform"]);
+
+        let cli = parse(&["sp", "load", "transform", "-n", "load", "load"]);
+        assert_eq!(names(&cli.unwrap()), ["load", "transform", "load"]);
+
+        let cli =
+            parse(&["sp", "load", "visual_vocab", "--", "load", "3"]).unwrap();
+        assert_eq!(names(&cli), ["load", "visual_vocab"]);
+    }
+
+    #[test]
+    fn test_unknown_arguments() {
+        let err =
+            parse(&["sp", "load", "transform", "--frobnicate"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownArgument);
+        assert!(err.to_string().contains("'--frobnicate'"));
+
+        let err = parse(&["sp", "load", "transform", "extra"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownArgument);
+        assert!(err.to_string().contains("'extra'"));
+
+        let err = parse(&["sp", "--frobnicate", "load"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownArgument);
+
+        // an option of an earlier pipeline put after a later one
+        let err = parse(&["sp", "load", "enrich", "transform", "--senses=2"])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownArgument);
+        assert!(err
+            .to_string()
+            .contains("'--senses' for transform, it is an option of enrich"));
+    }
+
+    #[test]
+    fn test_missing_pipeline() {
+        let err = parse(&["sp", "-n", "unit1"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingSubcommand);
+    }
+
+    #[test]
+    fn test_init() {
+        let cli = parse(&["sp", "--init"]).unwrap();
+        assert!(cli.init);
+        assert!(cli.pipelines.is_empty());
+
+        let flashcards =
+            serde_yaml::from_str::<Vec<Flashcard>>(INIT_TEMPLATE).unwrap();
+        assert_eq!(flashcards.len(), 6);
+        assert_eq!(flashcards[2].tags, ["unidad 1", "animales"]);
+        assert_eq!(flashcards[4].definition, "1. bank\n2. bench");
+        assert_eq!(flashcards[4].tags, ["unidad 2", "ciudad"]);
+        assert_eq!(flashcards[5].note.as_deref(), Some("Nado en el mar."));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(INIT_PATH);
+        write_init_template(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), INIT_TEMPLATE);
+        // an existing deck is never overwritten
+        std::fs::write(&path, "- [casa, house]\n").unwrap();
+        assert!(write_init_template(&path).is_err());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "- [casa, house]\n"
+        );
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let config = "name = \"unit1\"\n[visual_vocab]\nname = \"Jane\"\n\
+                      period = \"3\"\nrow = 4\n";
+        let cli = parse_with_config(config, &["sp", "visual_vocab"]).unwrap();
+        assert_eq!(cli.name, "unit1");
+        assert_eq!(names(&cli), ["visual_vocab"]);
+
+        let cli = parse_with_config(config, &["sp", "-n", "unit2", "load"]);
+        assert_eq!(cli.unwrap().name, "unit2");
+
+        let config = "[visual_vocab]\nrows = 4\n";
+        assert!(parse_with_config(config, &["sp", "visual_vocab"]).is_err());
+    }
+
+    #[test]
+    fn test_limit_flashcards() {
+        let flashcards = |n: usize| {
+            PipelineIO::Flashcard(
+                (0..n).map(|i| Flashcard::new(&i.to_string(), "")).collect(),
+            )
+        };
+        let words = |output: PipelineIO| match output {
+            PipelineIO::Flashcard(flashcards) => {
+                flashcards.into_iter().map(|x| x.word).collect::<Vec<_>>()
+            }
+            _ => panic!("expected flashcards"),
+        };
+        assert_eq!(words(limit_flashcards(flashcards(5), Some(2))), ["0", "1"]);
+        assert_eq!(words(limit_flashcards(flashcards(2), Some(5))).len(), 2);
+        assert_eq!(words(limit_flashcards(flashcards(5), None)).len(), 5);
+        assert!(matches!(
+            limit_flashcards(PipelineIO::Stdout("luz".to_string()), Some(1)),
+            PipelineIO::Stdout(_)
+        ));
+
+        let cli =
+            parse(&["sp", "--max-flashcards", "10", "transform"]).unwrap();
+        assert_eq!(cli.max_flashcards, Some(10));
+        assert!(parse(&["sp", "--max-flashcards", "0", "transform"]).is_err());
+    }
+
+    #[test]
+    fn test_log_targets() {
+        let cli = parse(&[
+            "sp",
+            "--log",
+            "spanish_dict=debug,image_search=warn",
+            "load",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.log,
+            [
+                ("spanish_dict".to_string(), log::LevelFilter::Debug),
+                ("image_search".to_string(), log::LevelFilter::Warn),
+            ]
+        );
+        assert!(parse(&["sp", "--log", "spanish_dict", "load"]).is_err());
+        assert!(parse(&["sp", "--log", "spanish_dict=loud", "load"]).is_err());
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let code = |err: Box<dyn Error>| exit_code(err.as_ref());
+        assert_eq!(code(Box::new(CliError::new("no words"))), EXIT_USAGE);
+        // an unknown word is not a network failure
+        let err = SpiderError::with_kind(SpiderErrorKind::NotFound, "xyzzy");
+        assert_eq!(code(Box::new(err)), EXIT_FAILURE);
+        let err = SpiderError::with_kind(SpiderErrorKind::Network, "offline");
+        assert_eq!(code(Box::new(err)), EXIT_NETWORK);
+        let err = SpiderError::with_kind(SpiderErrorKind::Model, "no model");
+        assert_eq!(code(Box::new(err)), EXIT_MODEL);
+        let err = SpiderError::with_kind(SpiderErrorKind::Model, "no model");
+        let err = CliError::with_source("the models are needed", err);
+        assert_eq!(code(Box::new(err)), EXIT_MODEL);
+        let err = PipelineError::with_kind(PipelineErrorKind::Typst, "typst");
+        assert_eq!(code(Box::new(err)), EXIT_TYPST);
+        assert_eq!(code(Box::new(PipelineError::new("empty"))), EXIT_FAILURE);
+        // the class of a pipeline error is that of its cause
+        let cause = SpiderError::with_kind(SpiderErrorKind::Network, "offline");
+        let err = PipelineError::with_source("no definition", cause);
+        assert_eq!(code(Box::new(err)), EXIT_NETWORK);
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "gone");
+        assert_eq!(code(Box::new(err)), EXIT_IO);
+        let cause = std::io::Error::new(std::io::ErrorKind::NotFound, "gone");
+        let err = CliError::with_source("failed to fetch", cause);
+        assert_eq!(code(Box::new(err)), EXIT_IO);
+        assert_eq!(code("failed".into()), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn test_rotate_log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.log");
+        std::fs::write(&path, "small").unwrap();
+        rotate_log_file(&path).unwrap();
+        assert!(path.exists());
+
+        for _ in 0..LOG_FILE_KEEP + 1 {
+            let file = std::fs::File::create(&path).unwrap();
+            file.set_len(LOG_FILE_MAX_SIZE).unwrap();
+            rotate_log_file(&path).unwrap();
+            assert!(!path.exists());
+        }
+        assert!(dir.path().join("run.log.1").exists());
+        assert!(dir
+            .path()
+            .join(format!("run.log.{}", LOG_FILE_KEEP))
+            .exists());
+        let rotated = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(rotated, LOG_FILE_KEEP);
+    }
+}
@@ -0,0 +1,202 @@
+use async_trait::async_trait;
+use clap::Parser;
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use super::{Flashcard, Pipeline, PipelineError, PipelineIO};
+use crate::spider::CLIENT;
+
+/// The version of the AnkiConnect API spoken by this pipeline
+const ANKI_CONNECT_VERSION: u32 = 6;
+
+/// A pipeline for pushing flashcards into a running Anki via AnkiConnect
+#[derive(Debug, Parser)]
+pub struct AnkiPipeline {
+    /// The deck to add the notes to
+    #[clap(short, long, default_value = "Spanish")]
+    deck: String,
+
+    /// The note type of the added notes
+    #[clap(short, long, default_value = "Basic")]
+    model: String,
+
+    /// The field of the note type that holds the word
+    #[clap(long, default_value = "Front")]
+    front_field: String,
+
+    /// The field of the note type that holds the definition
+    #[clap(long, default_value = "Back")]
+    back_field: String,
+
+    /// The address of the AnkiConnect server
+    #[clap(short, long, default_value = "http://localhost:8765")]
+    url: String,
+
+    /// Print the intended actions without sending them to Anki
+    #[clap(long)]
+    dry_run: bool,
+}
+
+impl AnkiPipeline {
+    /// Create a pipeline adding notes of the note type `model` to `deck`
+    /// through the AnkiConnect server on localhost
+    pub fn new(deck: &str, model: &str) -> Self {
+        Self {
+            deck: deck.to_string(),
+            model: model.to_string(),
+            front_field: "Front".to_string(),
+            back_field: "Back".to_string(),
+            url: "http://localhost:8765".to_string(),
+            dry_run: false,
+        }
+    }
+
+    /// Invoke an AnkiConnect action and return its result
+    async fn invoke(
+        &self,
+        action: &str,
+        params: Value,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let request = json!({
+            "action": action,
+            "version": ANKI_CONNECT_VERSION,
+            "params": params,
+        });
+        let mut response: Value = CLIENT
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| {
+                PipelineError::with_source(
+                    &format!(
+                        "failed to reach AnkiConnect at {}: {}",
+                        self.url, e
+                    ),
+                    e,
+                )
+            })?
+            .json()
+            .await?;
+        if let Some(error) = response["error"].as_str() {
+            return Err(PipelineError::new(&format!(
+                "AnkiConnect {} failed: {}",
+                action, error
+            ))
+            .into());
+        }
+        Ok(response["result"].take())
+    }
+
+    /// Convert a flashcard into an AnkiConnect note
+    fn to_note(&self, card: &Flashcard) -> Value {
+        json!({
+            "deckName": self.deck,
+            "modelName": self.model,
+            "fields": {
+                self.front_field.as_str(): card.word,
+                self.back_field.as_str(): card.definition,
+            },
+            "options": { "allowDuplicate": false },
+        })
+    }
+
+    /// Log the actions that adding the notes would take
+    fn log_actions(&self, notes: &[Value]) {
+        info!(target: "anki", "Would create deck {} if it does not exist", self.deck);
+        for note in notes {
+            info!(target: "anki", "Would add note {}", note);
+        }
+    }
+}
+
+#[async_trait]
+impl Pipeline for AnkiPipeline {
+    async fn run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let flashcards = match input {
+            Some(PipelineIO::Flashcard(flashcard)) => flashcard,
+            _ => {
+                return Err(Box::new(PipelineError::new(
+                    "input is not a flashcard",
+                )))
+            }
+        };
+        let notes = flashcards
+            .iter()
+            .map(|x| self.to_note(x))
+            .collect::<Vec<_>>();
+        let dropped = flashcards.iter().filter(|x| x.note.is_some()).count();
+        if dropped > 0 {
+            warn!(target: "anki", "Left out the notes of {} flashcards, which have no field in the Anki notes", dropped);
+        }
+
+        if self.dry_run {
+            self.log_actions(&notes);
+            return Ok(PipelineIO::Flashcard(flashcards));
+        }
+
+        let decks = self.invoke("deckNames", json!({})).await?;
+        let deck_exists = decks.as_array().is_some_and(|decks| {
+            decks.iter().any(|x| x.as_str() == Some(self.deck.as_str()))
+        });
+        if !deck_exists {
+            info!(target: "anki", "Creating deck {}", self.deck);
+            self.invoke("createDeck", json!({ "deck": self.deck }))
+                .await?;
+        }
+
+        let addable = self
+            .invoke("canAddNotes", json!({ "notes": notes }))
+            .await?;
+        let addable = addable
+            .as_array()
+            .ok_or("AnkiConnect canAddNotes returned an unexpected result")?;
+        let notes = notes
+            .into_iter()
+            .zip(addable)
+            .filter_map(|(note, ok)| {
+                ok.as_bool().unwrap_or(false).then_some(note)
+            })
+            .collect::<Vec<_>>();
+        let skipped = flashcards.len() - notes.len();
+
+        let added = if notes.is_empty() {
+            0
+        } else {
+            self.invoke("addNotes", json!({ "notes": notes }))
+                .await?
+                .as_array()
+                .map_or(0, |ids| ids.iter().filter(|x| !x.is_null()).count())
+        };
+        info!(target: "anki", "Added {} notes to {}, skipped {} duplicates", added, self.deck, skipped);
+
+        Ok(PipelineIO::Flashcard(flashcards))
+    }
+
+    async fn dry_run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let flashcards = match input {
+            Some(PipelineIO::Flashcard(flashcard)) => flashcard,
+            _ => {
+                return Err(Box::new(PipelineError::new(
+                    "input is not a flashcard",
+                )))
+            }
+        };
+        let notes = flashcards
+            .iter()
+            .map(|x| self.to_note(x))
+            .collect::<Vec<_>>();
+        self.log_actions(&notes);
+        Ok(PipelineIO::Flashcard(flashcards))
+    }
+
+    fn name(&self) -> &'static str {
+        "anki"
+    }
+}
@@ -0,0 +1,106 @@
+use async_trait::async_trait;
+use clap::Parser;
+use log::info;
+
+use super::{Pipeline, PipelineError, PipelineIO, SearchAttempts};
+use crate::spider::spanish_dict::{search_vocab, SearchStrategy};
+
+/// A pipeline for looking up the definitions of a word in SpanishDict
+#[derive(Debug, Parser)]
+pub struct DefinePipeline {
+    /// The word to look up
+    word: String,
+
+    /// Output the dictionary entry as JSON
+    #[clap(long)]
+    json: bool,
+
+    /// Copy the dictionary entry to the clipboard instead of printing it
+    #[clap(short, long)]
+    clipboard: bool,
+
+    /// Skip the machine learning models, not retrying an unknown word with
+    /// its keywords
+    #[clap(long)]
+    no_ml: bool,
+
+    /// Retry an unknown word with its dictionary form, e.g. "correr" for
+    /// "corriendo"
+    #[clap(long)]
+    lemmatize: bool,
+    /// Only look up the words in the small dictionary bundled for working
+    /// without network, e.g. for demos
+    #[clap(long)]
+    offline: bool,
+
+    #[clap(flatten)]
+    search_attempts: SearchAttempts,
+}
+
+impl DefinePipeline {
+    /// Create a pipeline printing the dictionary entry of `word`
+    pub fn new(word: &str) -> Self {
+        Self {
+            word: word.to_string(),
+            json: false,
+            clipboard: false,
+            no_ml: false,
+            lemmatize: false,
+            offline: false,
+            search_attempts: SearchAttempts::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Pipeline for DefinePipeline {
+    async fn run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        if input.is_some() {
+            return Err(Box::new(PipelineError::new(
+                "define does not accept input",
+            )));
+        }
+
+        let strategy = SearchStrategy {
+            offline: self.offline,
+            ..self.search_attempts.strategy(!self.no_ml, self.lemmatize)
+        };
+        let entry = search_vocab(&self.word, strategy).await?;
+        let text = if self.json {
+            serde_json::to_string_pretty(&entry)?
+        } else {
+            entry.to_string()
+        };
+
+        if self.clipboard {
+            Ok(PipelineIO::Clipboard(text))
+        } else {
+            Ok(PipelineIO::Stdout(text))
+        }
+    }
+
+    async fn dry_run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        if input.is_some() {
+            return Err(Box::new(PipelineError::new(
+                "define does not accept input",
+            )));
+        }
+
+        info!(target: "define", "Would look up {}", self.word);
+        Ok(PipelineIO::Stdout(String::new()))
+    }
+
+    fn name(&self) -> &'static str {
+        "define"
+    }
+
+    fn needs_models(&self) -> bool {
+        !self.no_ml
+    }
+}
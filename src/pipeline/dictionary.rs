@@ -0,0 +1,128 @@
+use async_trait::async_trait;
+use clap::Parser;
+use log::{info, warn};
+
+use super::{Pipeline, PipelineError, PipelineIO, SearchAttempts};
+use crate::spider::spanish_dict::{search_vocab_batch, SearchStrategy};
+
+/// A pipeline for looking up the full SpanishDict entries of the words of
+/// flashcards, keeping their groups, definitions, and examples
+#[derive(Debug, Parser)]
+pub struct DictionaryPipeline {
+    /// The number of words looked up at once
+    #[clap(
+        short = 'j',
+        long,
+        default_value = "4",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    concurrency: usize,
+
+    /// Skip the machine learning models, not retrying unknown words with
+    /// their keywords
+    #[clap(long)]
+    no_ml: bool,
+
+    /// Retry unknown words with their dictionary forms, e.g. "correr" for
+    /// "corriendo"
+    #[clap(long)]
+    lemmatize: bool,
+    /// Only look up the words in the small dictionary bundled for working
+    /// without network, e.g. for demos
+    #[clap(long)]
+    offline: bool,
+
+    #[clap(flatten)]
+    search_attempts: SearchAttempts,
+}
+
+impl DictionaryPipeline {
+    /// Create a pipeline looking up the dictionary entries of flashcards
+    pub fn new() -> Self {
+        Self {
+            concurrency: 4,
+            no_ml: false,
+            lemmatize: false,
+            offline: false,
+            search_attempts: SearchAttempts::default(),
+        }
+    }
+}
+
+impl Default for DictionaryPipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Pipeline for DictionaryPipeline {
+    async fn run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let words = match input {
+            Some(PipelineIO::Flashcard(flashcard)) => {
+                flashcard.into_iter().map(|x| x.word).collect::<Vec<_>>()
+            }
+            _ => {
+                return Err(Box::new(PipelineError::new(
+                    "input is not a flashcard",
+                )))
+            }
+        };
+
+        info!(target: "dictionary", "Looking up {} words", words.len());
+        let results = search_vocab_batch(
+            &words,
+            self.concurrency,
+            SearchStrategy {
+                offline: self.offline,
+                ..self.search_attempts.strategy(!self.no_ml, self.lemmatize)
+            },
+        )
+        .await;
+
+        let entries = results
+            .into_iter()
+            .filter_map(|(word, result)| match result {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    warn!(target: "dictionary", "Failed to look up {}: {}", word, err);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        info!(target: "dictionary", "Found {} of {} words", entries.len(), words.len());
+
+        Ok(PipelineIO::Dictionary(entries))
+    }
+
+    async fn dry_run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let flashcards = match input {
+            Some(PipelineIO::Flashcard(flashcard)) => flashcard,
+            _ => {
+                return Err(Box::new(PipelineError::new(
+                    "input is not a flashcard",
+                )))
+            }
+        };
+
+        for card in &flashcards {
+            info!(target: "dictionary", "Would look up the entry of {}", card.word);
+        }
+
+        Ok(PipelineIO::Dictionary(vec![]))
+    }
+
+    fn name(&self) -> &'static str {
+        "dictionary"
+    }
+
+    fn needs_models(&self) -> bool {
+        !self.no_ml
+    }
+}
@@ -2,3 +2,18 @@
 pub fn cm(cm: f32) -> u32 {
     (cm * 360_000.0) as u32
 }
+
+/// Convert inches to English metric unit
+pub fn inch(inch: f32) -> u32 {
+    (inch * 914_400.0) as u32
+}
+
+/// Convert points to the half points of font sizes
+pub fn half_points(pt: f32) -> usize {
+    (pt * 2.0).round() as usize
+}
+
+/// Convert English metric unit to twentieths of a point
+pub fn twip(emu: u32) -> u32 {
+    emu / 635
+}
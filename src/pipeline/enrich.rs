@@ -0,0 +1,596 @@
+use async_trait::async_trait;
+use clap::{Parser, ValueEnum};
+use log::{debug, info, warn};
+
+use super::{
+    visual_vocab::deep_search_batch, Flashcard, Pipeline, PipelineError,
+    PipelineIO, SearchAttempts,
+};
+use crate::{
+    error::CliError,
+    spider::spanish_dict::{
+        search_vocab_batch, DictionaryDefinition, DictionaryEntry,
+        DictionaryExample, SearchStrategy,
+    },
+};
+
+/// A pipeline for filling in the definitions of flashcards from SpanishDict
+#[derive(Debug, Parser)]
+pub struct EnrichPipeline {
+    /// Replace the definitions that are already filled in
+    #[clap(long)]
+    overwrite: bool,
+
+    /// The number of words looked up at once
+    #[clap(
+        short = 'j',
+        long,
+        default_value = "4",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    concurrency: usize,
+
+    /// Skip the machine learning models, not retrying unknown words with
+    /// their keywords
+    #[clap(long)]
+    no_ml: bool,
+
+    /// Retry unknown words with their dictionary forms, e.g. "correr" for
+    /// "corriendo"
+    #[clap(long)]
+    lemmatize: bool,
+
+    /// Only look up the words in the small dictionary bundled for working
+    /// without network, e.g. for demos
+    #[clap(long)]
+    offline: bool,
+
+    #[clap(flatten)]
+    search_attempts: SearchAttempts,
+
+    /// The number of senses of a word put on its flashcard as a numbered
+    /// list, each with its part of speech
+    #[clap(
+        long,
+        default_value = "1",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    senses: usize,
+
+    /// How the definition of a flashcard with a single sense is chosen
+    /// among the glosses of the word
+    #[clap(long, value_enum, default_value = "first")]
+    gloss: GlossStrategy,
+
+    /// Fill in the empty notes of the flashcards from the dictionary, shown
+    /// e.g. by transform --back-notes
+    #[clap(long, value_enum)]
+    note: Option<NoteSource>,
+}
+
+/// What the note of a flashcard is filled in with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NoteSource {
+    /// The first example sentence of the word, with its translation
+    Example,
+    /// The part of speech of the first sense of the word
+    PartOfSpeech,
+}
+
+/// How the gloss put on a flashcard is chosen among the glosses of a word
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GlossStrategy {
+    /// The first gloss, which SpanishDict lists as the most common
+    First,
+    /// The shortest gloss
+    Shortest,
+    /// The gloss closest in meaning to the most common sense of the word,
+    /// i.e. to the translation of its first example sentence
+    Semantic,
+}
+
+impl EnrichPipeline {
+    /// Create a pipeline filling in the empty definitions of flashcards, or
+    /// every definition if `overwrite` is set
+    pub fn new(overwrite: bool) -> Self {
+        Self {
+            overwrite,
+            concurrency: 4,
+            no_ml: false,
+            lemmatize: false,
+            offline: false,
+            search_attempts: SearchAttempts::default(),
+            senses: 1,
+            gloss: GlossStrategy::First,
+            note: None,
+        }
+    }
+
+    /// Return how the glosses are chosen, falling back to the first gloss
+    /// when the semantic ranking is asked for without the models
+    fn gloss_strategy(&self) -> GlossStrategy {
+        match self.gloss {
+            GlossStrategy::Semantic if self.no_ml => GlossStrategy::First,
+            x => x,
+        }
+    }
+
+    /// Reject a gloss strategy combined with several senses, as the
+    /// strategies only choose among the glosses of a single sense
+    fn validate(&self) -> Result<(), CliError> {
+        if self.senses > 1 && self.gloss != GlossStrategy::First {
+            let gloss = self.gloss.to_possible_value().unwrap();
+            return Err(CliError::new(&format!(
+                "--gloss {} cannot be combined with --senses {}",
+                gloss.get_name(),
+                self.senses
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check whether the definition of the flashcard should be looked up
+    fn needs_definition(&self, card: &Flashcard) -> bool {
+        self.overwrite || card.definition.trim().is_empty()
+    }
+
+    /// Check whether the note of the flashcard should be looked up
+    fn needs_note(&self, card: &Flashcard) -> bool {
+        self.note.is_some() && (self.overwrite || card.note.is_none())
+    }
+
+    /// Check whether the flashcard should be looked up in the dictionary
+    fn needs_lookup(&self, card: &Flashcard) -> bool {
+        self.needs_definition(card) || self.needs_note(card)
+    }
+}
+
+/// Return the first English gloss of a dictionary entry
+fn gloss(entry: &DictionaryEntry) -> Option<&str> {
+    glosses(entry).first().map(|(definition, _)| *definition)
+}
+
+/// Return the non-empty glosses of a dictionary entry, each with its group
+fn glosses(entry: &DictionaryEntry) -> Vec<(&str, Option<&str>)> {
+    entry
+        .definitions
+        .iter()
+        .map(|x| match x {
+            DictionaryDefinition::Definition { definition } => {
+                (definition.trim(), None)
+            }
+            DictionaryDefinition::DefinitionAndGroup { group, definition }
+            | DictionaryDefinition::DefinitionAndGroupWithExample {
+                group,
+                definition,
+                ..
+            } => (definition.trim(), Some(group.trim())),
+        })
+        .filter(|(definition, _)| !definition.is_empty())
+        .collect()
+}
+
+/// Return the shortest gloss of a dictionary entry, the first one on ties
+fn shortest_gloss(entry: &DictionaryEntry) -> Option<&str> {
+    glosses(entry)
+        .into_iter()
+        .map(|(definition, _)| definition)
+        .min_by_key(|x| x.chars().count())
+}
+
+/// Return the first example sentence of a dictionary entry, which
+/// illustrates its most common sense
+fn first_example(entry: &DictionaryEntry) -> Option<&DictionaryExample> {
+    entry.definitions.iter().find_map(|x| match x {
+        DictionaryDefinition::DefinitionAndGroupWithExample {
+            examples,
+            ..
+        } => examples.first(),
+        _ => None,
+    })
+}
+
+/// Return the text that the glosses of a dictionary entry are ranked
+/// against: the English translation of its first example, or the example
+/// itself, or the headword when it has no example
+fn sense_text(entry: &DictionaryEntry) -> &str {
+    match first_example(entry) {
+        Some(DictionaryExample::ExampleAndTranslation {
+            translation, ..
+        }) => translation,
+        Some(DictionaryExample::Example { example }) => example,
+        None => entry.correction.as_deref().unwrap_or(&entry.word),
+    }
+}
+
+/// Return the gloss of each dictionary entry closest in meaning to its
+/// first example, ranking every entry in a single batch
+async fn semantic_glosses(entries: &[&DictionaryEntry]) -> Vec<Option<String>> {
+    let queries = entries.iter().map(|x| sense_text(x)).collect::<Vec<_>>();
+    let contents = entries
+        .iter()
+        .map(|x| glosses(x).iter().map(|(x, _)| x.to_string()).collect())
+        .collect::<Vec<Vec<_>>>();
+    let searches = queries
+        .iter()
+        .zip(&contents)
+        .map(|(query, contents)| (*query, contents.as_slice()))
+        .collect::<Vec<_>>();
+    let (method, ranks) = deep_search_batch(&searches, 1, -1.0).await;
+    debug!(target: "enrich", "Ranked the glosses by {}", method);
+    ranks
+        .into_iter()
+        .zip(contents)
+        .map(|(rank, mut contents)| {
+            let &(i, _) = rank.first()?;
+            Some(contents.swap_remove(i))
+        })
+        .collect()
+}
+
+/// Return the note of a flashcard from a dictionary entry
+fn note(entry: &DictionaryEntry, source: NoteSource) -> Option<String> {
+    match source {
+        NoteSource::Example => first_example(entry).map(|x| x.to_string()),
+        NoteSource::PartOfSpeech => glosses(entry)
+            .into_iter()
+            .find_map(|(_, group)| group.filter(|x| !x.is_empty()))
+            .map(str::to_string),
+    }
+}
+
+/// Return the definition of a flashcard from a dictionary entry. With more
+/// than one sense, up to `count` senses are listed with their groups, e.g.
+/// "1. bank (masculine noun)\n2. bench (masculine noun)".
+fn definition(entry: &DictionaryEntry, count: usize) -> Option<String> {
+    let senses = glosses(entry).into_iter().take(count).collect::<Vec<_>>();
+    if senses.len() <= 1 {
+        return gloss(entry).map(str::to_string);
+    }
+    Some(
+        senses
+            .iter()
+            .enumerate()
+            .map(|(i, (definition, group))| match group {
+                Some(group) if !group.is_empty() => {
+                    format!("{}. {} ({})", i + 1, definition, group)
+                }
+                _ => format!("{}. {}", i + 1, definition),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+#[async_trait]
+impl Pipeline for EnrichPipeline {
+    async fn run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let mut flashcards = match input {
+            Some(PipelineIO::Flashcard(flashcard)) => flashcard,
+            _ => {
+                return Err(Box::new(PipelineError::new(
+                    "input is not a flashcard",
+                )))
+            }
+        };
+        self.validate()?;
+
+        let words = flashcards
+            .iter()
+            .filter(|x| self.needs_lookup(x))
+            .map(|x| x.word.to_owned())
+            .collect::<Vec<_>>();
+        info!(target: "enrich", "Looking up {} words", words.len());
+        if self.gloss != self.gloss_strategy() {
+            warn!(target: "enrich", "Taking the first glosses, as --no-ml disables the semantic ranking");
+        }
+        let results = search_vocab_batch(
+            &words,
+            self.concurrency,
+            SearchStrategy {
+                offline: self.offline,
+                ..self.search_attempts.strategy(!self.no_ml, self.lemmatize)
+            },
+        )
+        .await;
+
+        let entries = results
+            .iter()
+            .filter_map(|(_, x)| x.as_ref().ok())
+            .collect::<Vec<_>>();
+        let mut chosen = match self.gloss_strategy() {
+            GlossStrategy::Semantic if self.senses == 1 => {
+                semantic_glosses(&entries).await
+            }
+            _ => vec![],
+        }
+        .into_iter();
+
+        let mut enriched = 0;
+        let mut results = results.into_iter();
+        for card in flashcards.iter_mut() {
+            if !self.needs_lookup(card) {
+                continue;
+            }
+            let Some((word, result)) = results.next() else {
+                break;
+            };
+            let noted = match (&result, self.note) {
+                (Ok(entry), Some(source)) if self.needs_note(card) => {
+                    let found = note(entry, source);
+                    let noted = found.is_some();
+                    card.note = found.or(card.note.take());
+                    noted
+                }
+                _ => false,
+            };
+            // the semantic glosses are in the order of the entries found,
+            // so one is taken for every entry even if its card keeps its
+            // definition
+            let definition = result.as_ref().map(|x| {
+                match (self.senses, self.gloss_strategy()) {
+                    (1, GlossStrategy::Shortest) => {
+                        shortest_gloss(x).map(str::to_string)
+                    }
+                    (1, GlossStrategy::Semantic) => chosen.next().flatten(),
+                    _ => definition(x, self.senses),
+                }
+            });
+            match definition {
+                Ok(Some(definition)) if self.needs_definition(card) => {
+                    card.definition = definition;
+                    enriched += 1;
+                }
+                Ok(_) if noted => enriched += 1,
+                Ok(None) if self.needs_definition(card) => {
+                    warn!(target: "enrich", "No definition found for {}", word)
+                }
+                Ok(_) => {
+                    warn!(target: "enrich", "No note found for {}", word)
+                }
+                Err(err) => {
+                    warn!(target: "enrich", "Failed to look up {}: {}", word, err)
+                }
+            }
+        }
+        info!(target: "enrich", "Enriched {} flashcards, left {} unchanged", enriched, flashcards.len() - enriched);
+
+        Ok(PipelineIO::Flashcard(flashcards))
+    }
+
+    async fn dry_run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let flashcards = match input {
+            Some(PipelineIO::Flashcard(flashcard)) => flashcard,
+            _ => {
+                return Err(Box::new(PipelineError::new(
+                    "input is not a flashcard",
+                )))
+            }
+        };
+        self.validate()?;
+
+        for card in flashcards.iter().filter(|x| self.needs_lookup(x)) {
+            info!(target: "enrich", "Would look up the definition of {}", card.word);
+        }
+
+        Ok(PipelineIO::Flashcard(flashcards))
+    }
+
+    fn name(&self) -> &'static str {
+        "enrich"
+    }
+
+    fn needs_models(&self) -> bool {
+        !self.no_ml
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_gloss() {
+        let entry = DictionaryEntry {
+            word: "luz".to_string(),
+            definitions: vec![
+                DictionaryDefinition::DefinitionAndGroup {
+                    group: "noun".to_string(),
+                    definition: " ".to_string(),
+                },
+                DictionaryDefinition::Definition {
+                    definition: "light".to_string(),
+                },
+            ],
+            correction: None,
+        };
+        assert_eq!(gloss(&entry), Some("light"));
+    }
+
+    #[test]
+    fn test_definition_senses() {
+        let entry = DictionaryEntry {
+            word: "banco".to_string(),
+            definitions: vec![
+                DictionaryDefinition::DefinitionAndGroup {
+                    group: "masculine noun".to_string(),
+                    definition: "bank".to_string(),
+                },
+                DictionaryDefinition::DefinitionAndGroupWithExample {
+                    group: "masculine noun".to_string(),
+                    definition: "bench".to_string(),
+                    examples: vec![],
+                },
+                DictionaryDefinition::Definition {
+                    definition: " ".to_string(),
+                },
+                DictionaryDefinition::Definition {
+                    definition: "school".to_string(),
+                },
+            ],
+            correction: None,
+        };
+        assert_eq!(definition(&entry, 1).unwrap(), "bank");
+        assert_eq!(
+            definition(&entry, 2).unwrap(),
+            "1. bank (masculine noun)\n2. bench (masculine noun)"
+        );
+        assert_eq!(
+            definition(&entry, 5).unwrap(),
+            "1. bank (masculine noun)\n2. bench (masculine noun)\n3. school"
+        );
+
+        let entry = DictionaryEntry {
+            word: "luz".to_string(),
+            definitions: vec![DictionaryDefinition::Definition {
+                definition: "light".to_string(),
+            }],
+            correction: None,
+        };
+        assert_eq!(definition(&entry, 3).unwrap(), "light");
+    }
+
+    #[test]
+    fn test_shortest_gloss() {
+        let entry = DictionaryEntry {
+            word: "coche".to_string(),
+            definitions: vec![
+                DictionaryDefinition::Definition {
+                    definition: "automobile".to_string(),
+                },
+                DictionaryDefinition::Definition {
+                    definition: " ".to_string(),
+                },
+                DictionaryDefinition::Definition {
+                    definition: "car".to_string(),
+                },
+                DictionaryDefinition::Definition {
+                    definition: "cab".to_string(),
+                },
+            ],
+            correction: None,
+        };
+        assert_eq!(shortest_gloss(&entry), Some("car"));
+    }
+
+    #[test]
+    fn test_gloss_strategy() {
+        let pipeline =
+            EnrichPipeline::parse_from(["enrich", "--gloss", "semantic"]);
+        assert_eq!(pipeline.gloss_strategy(), GlossStrategy::Semantic);
+        let pipeline = EnrichPipeline::parse_from([
+            "enrich", "--gloss", "semantic", "--no-ml",
+        ]);
+        assert_eq!(pipeline.gloss_strategy(), GlossStrategy::First);
+        assert_eq!(EnrichPipeline::new(false).gloss, GlossStrategy::First);
+    }
+
+    #[test]
+    fn test_needs_models() {
+        assert!(EnrichPipeline::parse_from(["enrich"]).needs_models());
+        assert!(
+            !EnrichPipeline::parse_from(["enrich", "--no-ml"]).needs_models()
+        );
+    }
+
+    #[test]
+    fn test_note() {
+        let entry = DictionaryEntry {
+            word: "nadar".to_string(),
+            definitions: vec![
+                DictionaryDefinition::Definition {
+                    definition: "to float".to_string(),
+                },
+                DictionaryDefinition::DefinitionAndGroupWithExample {
+                    group: "intransitive verb".to_string(),
+                    definition: "to swim".to_string(),
+                    examples: vec![DictionaryExample::ExampleAndTranslation {
+                        example: "Nado cada día.".to_string(),
+                        translation: "I swim every day.".to_string(),
+                    }],
+                },
+            ],
+            correction: None,
+        };
+        assert_eq!(
+            note(&entry, NoteSource::Example).unwrap(),
+            "Nado cada día. — I swim every day."
+        );
+        assert_eq!(
+            note(&entry, NoteSource::PartOfSpeech).unwrap(),
+            "intransitive verb"
+        );
+
+        let entry = DictionaryEntry {
+            word: "luz".to_string(),
+            definitions: vec![DictionaryDefinition::Definition {
+                definition: "light".to_string(),
+            }],
+            correction: None,
+        };
+        assert_eq!(note(&entry, NoteSource::Example), None);
+        assert_eq!(note(&entry, NoteSource::PartOfSpeech), None);
+    }
+
+    #[test]
+    fn test_sense_text() {
+        let entry = |examples| DictionaryEntry {
+            word: "banco".to_string(),
+            definitions: vec![
+                DictionaryDefinition::Definition {
+                    definition: "bench".to_string(),
+                },
+                DictionaryDefinition::DefinitionAndGroupWithExample {
+                    group: "masculine noun".to_string(),
+                    definition: "bank".to_string(),
+                    examples,
+                },
+            ],
+            correction: None,
+        };
+        let translated =
+            entry(vec![DictionaryExample::ExampleAndTranslation {
+                example: "Fui al banco a sacar dinero.".to_string(),
+                translation: "I went to the bank to get money.".to_string(),
+            }]);
+        assert_eq!(sense_text(&translated), "I went to the bank to get money.");
+        assert_eq!(sense_text(&entry(vec![])), "banco");
+    }
+
+    #[test]
+    fn test_validate() {
+        let validate = |args: &[&str]| {
+            let args = ["enrich"].iter().chain(args);
+            EnrichPipeline::parse_from(args).validate()
+        };
+        assert!(validate(&["--gloss", "semantic"]).is_ok());
+        assert!(validate(&["--senses", "3"]).is_ok());
+        assert!(validate(&["--gloss", "shortest", "--senses", "2"]).is_err());
+    }
+
+    #[test]
+    fn test_needs_definition() {
+        let card = |definition: &str| Flashcard::new("luz", definition);
+        let pipeline = EnrichPipeline::new(false);
+        assert!(pipeline.needs_definition(&card("")));
+        assert!(!pipeline.needs_definition(&card("light")));
+        assert!(EnrichPipeline::new(true).needs_definition(&card("light")));
+
+        // the cards with a definition are still looked up for their notes
+        let pipeline =
+            EnrichPipeline::parse_from(["enrich", "--note", "example"]);
+        assert!(pipeline.needs_lookup(&card("light")));
+        let noted = Flashcard {
+            note: Some("La luz es blanca.".to_string()),
+            ..card("light")
+        };
+        assert!(!pipeline.needs_lookup(&noted));
+        assert!(!EnrichPipeline::new(false).needs_lookup(&card("light")));
+    }
+}
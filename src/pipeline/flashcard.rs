@@ -1,14 +1,45 @@
 use serde::{
     de::Error,
-    ser::{Serialize, SerializeSeq, Serializer},
+    ser::{Serialize, SerializeMap, SerializeSeq, Serializer},
     Deserialize, Deserializer,
 };
 
 /// Represents the flashcard output of a pipeline stage.
-#[derive(Debug, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Flashcard {
     pub word: String,
     pub definition: String,
+    /// The categories of the flashcard, e.g. the unit or chapter
+    pub tags: Vec<String>,
+    /// A second line of the back of the flashcard, e.g. an example sentence
+    /// or the part of speech
+    pub note: Option<String>,
+}
+
+impl Flashcard {
+    /// Create a flashcard of `word` and `definition`, without tags or a note
+    pub fn new(word: &str, definition: &str) -> Self {
+        Self {
+            word: word.to_string(),
+            definition: definition.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Check whether the flashcard has the tag, ignoring case
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let tag = tag.trim().to_lowercase();
+        self.tags.iter().any(|x| x.trim().to_lowercase() == tag)
+    }
+}
+
+/// Split a comma separated list of tags
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_string)
+        .collect()
 }
 
 impl Serialize for Flashcard {
@@ -16,28 +47,107 @@ impl Serialize for Flashcard {
     where
         S: Serializer,
     {
-        let mut seq = serializer.serialize_seq(Some(2))?;
+        // the compact form has no room for a note, so noted flashcards are
+        // written with their keys
+        if let Some(note) = &self.note {
+            let mut map = serializer.serialize_map(None)?;
+            map.serialize_entry("word", &self.word)?;
+            map.serialize_entry("definition", &self.definition)?;
+            if !self.tags.is_empty() {
+                map.serialize_entry("tags", &self.tags)?;
+            }
+            map.serialize_entry("note", note)?;
+            return map.end();
+        }
+        // the tags are left out when empty, keeping the [word, definition]
+        // pairs of untagged decks
+        let len = if self.tags.is_empty() { 2 } else { 3 };
+        let mut seq = serializer.serialize_seq(Some(len))?;
         seq.serialize_element(&self.word)?;
         seq.serialize_element(&self.definition)?;
+        if !self.tags.is_empty() {
+            seq.serialize_element(&self.tags)?;
+        }
         seq.end()
     }
 }
 
+/// Represents an element of a serialized flashcard
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Field {
+    Text(String),
+    List(Vec<String>),
+}
+
+/// Represents the forms a flashcard is deserialized from, either the
+/// compact `[word, definition, tags]` or a mapping of the same keys
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Repr {
+    Compact(Vec<Field>),
+    Keyed {
+        word: String,
+        #[serde(default)]
+        definition: String,
+        tags: Option<Field>,
+        note: Option<String>,
+    },
+}
+
+/// Return the tags of a tags field, splitting a single string at commas
+fn field_tags(field: Option<&Field>) -> Vec<String> {
+    match field {
+        Some(Field::Text(tags)) => parse_tags(tags),
+        Some(Field::List(tags)) => tags.to_owned(),
+        None => vec![],
+    }
+}
+
 impl<'de> Deserialize<'de> for Flashcard {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let seq: Vec<String> = Vec::deserialize(deserializer)?;
-        if seq.len() != 2 {
+        let seq = match Repr::deserialize(deserializer)? {
+            Repr::Compact(seq) => seq,
+            Repr::Keyed {
+                word,
+                definition,
+                tags,
+                note,
+            } => {
+                return Ok(Flashcard {
+                    word,
+                    definition,
+                    tags: field_tags(tags.as_ref()),
+                    note: note.filter(|x| !x.trim().is_empty()),
+                })
+            }
+        };
+        if seq.is_empty() || seq.len() > 3 {
             return Err(D::Error::invalid_length(
                 seq.len(),
-                &"expected a sequence with two elements",
+                &"expected a sequence with one to three elements",
             ));
         }
-        let word = seq[0].clone();
-        let definition = seq[1].clone();
-        Ok(Flashcard { word, definition })
+        let text = |field: Option<&Field>| match field {
+            Some(Field::Text(text)) => Ok(text.to_owned()),
+            Some(Field::List(_)) => Err(D::Error::custom(
+                "expected the word and definition to be strings",
+            )),
+            None => Ok(String::new()),
+        };
+        // a word without a definition is left for the enrich pipeline
+        let word = text(seq.first())?;
+        let definition = text(seq.get(1))?;
+        let tags = field_tags(seq.get(2));
+        Ok(Flashcard {
+            word,
+            definition,
+            tags,
+            ..Default::default()
+        })
     }
 }
 
@@ -46,3 +156,79 @@ impl std::fmt::Display for Flashcard {
         write!(f, "{}: {}", self.word, self.definition)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tags() {
+        let cards: Vec<Flashcard> = serde_yaml::from_str(
+            "- [la casa, the house]\n\
+             - [el perro, the dog, [Unit 1, Unit 2]]\n\
+             - [el gato, the cat, \"Unit 1, Unit 3\"]\n",
+        )
+        .unwrap();
+        assert_eq!(cards[0], Flashcard::new("la casa", "the house"));
+        assert_eq!(cards[1].tags, vec!["Unit 1", "Unit 2"]);
+        assert_eq!(cards[2].tags, vec!["Unit 1", "Unit 3"]);
+        assert!(cards[1].has_tag("unit 2"));
+        assert!(!cards[0].has_tag("Unit 1"));
+
+        assert_eq!(
+            serde_yaml::to_string(&cards[..2]).unwrap(),
+            "- - la casa\n  - the house\n\
+             - - el perro\n  - the dog\n  - - Unit 1\n    - Unit 2\n"
+        );
+        assert!(serde_json::from_str::<Flashcard>(r#"[["a"], "b"]"#).is_err());
+        assert!(serde_json::from_str::<Flashcard>(r#"["a", "b", [], "c"]"#)
+            .is_err());
+    }
+
+    #[test]
+    fn test_keyed() {
+        let cards: Vec<Flashcard> = serde_yaml::from_str(
+            "- word: la casa\n  definition: the house\n\
+             - word: el perro\n  tags: [Unit 1]\n\
+             - [el gato, the cat]\n",
+        )
+        .unwrap();
+        assert_eq!(cards[0].word, "la casa");
+        assert_eq!(cards[0].definition, "the house");
+        assert_eq!(cards[1].definition, "");
+        assert_eq!(cards[1].tags, vec!["Unit 1"]);
+        assert_eq!(cards[2].definition, "the cat");
+
+        // the compact form is still written
+        assert_eq!(
+            serde_yaml::to_string(&cards[0]).unwrap(),
+            "- la casa\n- the house\n"
+        );
+        assert!(serde_yaml::from_str::<Flashcard>("definition: the house\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_note() {
+        let cards: Vec<Flashcard> = serde_yaml::from_str(
+            "- word: nadar\n  definition: to swim\n  note: Nado cada día.\n\
+             - word: el gato\n  note: \" \"\n",
+        )
+        .unwrap();
+        assert_eq!(cards[0].note.as_deref(), Some("Nado cada día."));
+        assert_eq!(cards[1].note, None);
+
+        // noted flashcards are written with their keys, and read back
+        let yaml = serde_yaml::to_string(&cards).unwrap();
+        assert_eq!(
+            yaml,
+            "- word: nadar\n  definition: to swim\n  note: Nado cada día.\n\
+             - - el gato\n  - ''\n"
+        );
+        let json = serde_json::to_string(&cards).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<Flashcard>>(&json).unwrap(),
+            cards
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// The style shared by the HTML documents
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #0a0a0a; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border: 1px solid #a3a3a3; padding: 0.5em; text-align: left; }
+th { background: #f5f5f5; }
+.grid { display: grid; gap: 1em; \
+grid-template-columns: repeat(auto-fill, minmax(16em, 1fr)); }
+.card { border: 1px solid #a3a3a3; padding: 1em; }
+.card h2 { margin-top: 0; }
+.card img { max-width: 100%; height: auto; }
+.translation { color: #737373; font-style: italic; }";
+
+/// Escape the text so that it is taken literally in HTML, breaking lines
+/// with `<br>`
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '\n' => escaped.push_str("<br>"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Return a data URI embedding the PNG image `png`
+pub fn png_data_uri(png: &[u8]) -> String {
+    format!("data:image/png;base64,{}", STANDARD.encode(png))
+}
+
+/// Return a self-contained HTML document titled `title` with `body`
+pub fn document(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"es\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        body
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("la casa"), "la casa");
+        assert_eq!(
+            escape("<b>\"A&B\"</b>"),
+            "&lt;b&gt;&quot;A&amp;B&quot;&lt;/b&gt;"
+        );
+        assert_eq!(escape("1. bank\r\n2. bench"), "1. bank<br>2. bench");
+    }
+
+    #[test]
+    fn test_png_data_uri() {
+        assert_eq!(png_data_uri(b"png"), "data:image/png;base64,cG5n");
+    }
+}
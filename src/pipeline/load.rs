@@ -1,13 +1,23 @@
-use std::{fs::File, io::Read, path::PathBuf};
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use async_trait::async_trait;
 use clap::{arg, Parser, ValueEnum};
 use docx_rs::{read_docx, TableChild, TableRowChild};
-use log::{info, warn};
+use log::{debug, info, warn};
+use reqwest::header::CONTENT_TYPE;
 use serde_json::from_reader;
-use serde_yaml::from_str;
+use serde_yaml::from_slice;
+use url::Url;
 
-use super::{Flashcard, Pipeline, PipelineIO};
+use super::{
+    flashcard::parse_tags, Flashcard, Pipeline, PipelineIO, Stats,
+    StatsRecorder,
+};
+use crate::{error::CliError, spider::CLIENT};
 
 /// Represents the different file types that can be loaded
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -15,25 +25,281 @@ pub enum VocabFileType {
     Yaml,
     Json,
     Docx,
+    /// Comma separated word, definition and tags, e.g. a spreadsheet export
+    Csv,
+    /// Tab separated word, definition and tags
+    Tsv,
+}
+
+/// Represents where a vocab file is loaded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VocabSource {
+    /// A local file
+    Path(PathBuf),
+    /// A file fetched over http(s), e.g. a Gist or a Google Docs export
+    Url(Url),
+}
+
+impl std::fmt::Display for VocabSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VocabSource::Path(path) => write!(f, "{}", path.display()),
+            VocabSource::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Parse the source of a vocab file, an http(s) URL or an existing path
+fn parse_source(arg: &str) -> Result<VocabSource, String> {
+    if arg.starts_with("http://") || arg.starts_with("https://") {
+        return Url::parse(arg)
+            .map(VocabSource::Url)
+            .map_err(|e| format!("invalid URL {}: {}", arg, e));
+    }
+    let path = PathBuf::from(arg);
+    if path.exists() {
+        Ok(VocabSource::Path(path))
+    } else {
+        Err("File does not exist".to_string())
+    }
 }
 
 /// Represents the input of a pipeline stage.
 #[derive(Parser)]
 pub struct LoadPipeline {
-    /// The path to the file to load
-    #[arg(value_parser = |x: &str| {
-        let path = PathBuf::from(x);
-        if path.exists() {
-            Ok(Box::new(path))
-        } else {
-            Err("File does not exist")
-        }
-    })]
-    path: Box<PathBuf>,
+    /// The path or http(s) URL of the file to load. When omitted, the
+    /// document output of the previous pipeline or the --words are loaded
+    /// instead.
+    #[arg(value_parser = parse_source)]
+    path: Option<VocabSource>,
 
     /// The type of file to load
     #[arg(short = 't', long = "type")]
     filetype: Option<VocabFileType>,
+
+    /// The number of word/definition pairs in each docx table row. With a
+    /// single pair, a third column holds the comma separated tags of the
+    /// flashcard.
+    #[arg(
+        short,
+        long,
+        default_value = "1",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pairs_per_row: usize,
+
+    /// Keep the bold and italic text of docx files as **bold** and *italic*
+    /// markup
+    #[arg(long)]
+    markup: bool,
+
+    /// Drop the flashcards whose word is a number or a URL, such as page
+    /// numbers and links in docx tables
+    #[arg(long)]
+    clean: bool,
+
+    /// Read the definition from the first column of docx tables and the word
+    /// from the second
+    #[arg(long)]
+    swap_columns: bool,
+
+    /// Load the flashcards from a list of "word:definition" pairs separated
+    /// by semicolons, or by commas if there is no semicolon, instead of a
+    /// file, e.g. "la casa:the house, el perro". The definitions may be
+    /// left out to be enriched later.
+    #[arg(long, conflicts_with = "path")]
+    words: Option<String>,
+
+    #[arg(skip)]
+    stats: StatsRecorder,
+}
+
+impl LoadPipeline {
+    /// Create a pipeline loading the file at `path`, or the document output
+    /// of the previous pipeline if `path` is `None`. The file type is
+    /// determined from the extension unless `filetype` is given.
+    pub fn new(path: Option<PathBuf>, filetype: Option<VocabFileType>) -> Self {
+        Self {
+            path: path.map(VocabSource::Path),
+            filetype,
+            pairs_per_row: 1,
+            markup: false,
+            clean: false,
+            swap_columns: false,
+            words: None,
+            stats: StatsRecorder::default(),
+        }
+    }
+
+    /// Parse the flashcards out of the contents of a vocab file.
+    /// `source` names the file in log and error messages.
+    fn parse(
+        &self,
+        filetype: VocabFileType,
+        buf: &[u8],
+        source: &str,
+    ) -> Result<Vec<Flashcard>, Box<dyn std::error::Error>> {
+        let mut flashcard = match filetype {
+            VocabFileType::Yaml => {
+                info!(target: "load_pipeline", "Loading YAML file: {}", source);
+                from_slice::<Vec<Flashcard>>(buf)?
+            }
+            VocabFileType::Json => {
+                info!(target: "load_pipeline", "Loading JSON file: {}", source);
+                from_reader(buf)?
+            }
+            VocabFileType::Docx => {
+                info!(target: "load_pipeline", "Loading DOCX file: {}", source);
+                self.parse_docx(buf, source)?
+            }
+            VocabFileType::Csv => {
+                info!(target: "load_pipeline", "Loading CSV file: {}", source);
+                parse_table(std::str::from_utf8(buf)?, ',', source)?
+            }
+            VocabFileType::Tsv => {
+                info!(target: "load_pipeline", "Loading TSV file: {}", source);
+                parse_table(std::str::from_utf8(buf)?, '\t', source)?
+            }
+        };
+        for card in flashcard.iter_mut() {
+            card.word = clean_text(&card.word);
+            card.definition = clean_text(&card.definition);
+        }
+        Ok(flashcard)
+    }
+
+    /// Parse the flashcards out of the vocab tables of a docx file
+    fn parse_docx(
+        &self,
+        buf: &[u8],
+        source: &str,
+    ) -> Result<Vec<Flashcard>, Box<dyn std::error::Error>> {
+        let docx = read_docx(buf)?;
+
+        let mut flashcard = Vec::new();
+        for table in docx.document.children.iter().filter_map(|x| {
+            if let docx_rs::DocumentChild::Table(x) = x {
+                Some(x)
+            } else {
+                None
+            }
+        }) {
+            let rows = &table.rows;
+            if rows.is_empty() {
+                warn!(target: "load_pipeline", "Skipping empty table");
+                continue;
+            }
+
+            for (i, row) in rows.iter().enumerate() {
+                let TableChild::TableRow(row) = row;
+                let mut cells = row.cells.as_slice();
+                let tags = match cells {
+                    [_, _, tags] if self.pairs_per_row == 1 => {
+                        cells = &cells[..2];
+                        parse_tags(&textify_cell(tags, false))
+                    }
+                    _ => vec![],
+                };
+                if cells.len() % 2 != 0 || cells.len() > 2 * self.pairs_per_row
+                {
+                    warn!(target: "load_pipeline", "Skipping row {:?} with {} columns", textify_row(row), cells.len());
+                    continue;
+                }
+
+                for pair in cells.chunks(2) {
+                    let (word, definition) = if self.swap_columns {
+                        (&pair[1], &pair[0])
+                    } else {
+                        (&pair[0], &pair[1])
+                    };
+                    let word = textify_cell(word, self.markup);
+                    let definition = textify_cell(definition, self.markup);
+
+                    if i == 0 && is_header_row(&word, &definition) {
+                        info!(target: "load_pipeline", "Skipping header {:?} | {:?}", word, definition);
+                        continue;
+                    }
+
+                    if !word.is_empty()
+                        && !definition.is_empty()
+                        && word.to_lowercase() != definition.to_lowercase()
+                    {
+                        flashcard.push(Flashcard {
+                            word,
+                            definition,
+                            tags: tags.to_owned(),
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+        }
+        if flashcard.is_empty() {
+            Err(format!("no 2-column vocab tables found in {}", source))?
+        }
+        Ok(flashcard)
+    }
+}
+
+/// Determine the file type of a vocab file from its extension
+fn filetype_from_path(path: &Path) -> Option<VocabFileType> {
+    match path.extension()?.to_str()? {
+        "yml" | "yaml" => Some(VocabFileType::Yaml),
+        "json" => Some(VocabFileType::Json),
+        "docx" => Some(VocabFileType::Docx),
+        "csv" => Some(VocabFileType::Csv),
+        "tsv" => Some(VocabFileType::Tsv),
+        _ => None,
+    }
+}
+
+/// The content type of docx files
+const DOCX_MIME: &str =
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+/// Determine the file type of a vocab file from the content type it was
+/// served with, ignoring the generic ones such as text/plain
+fn filetype_from_content_type(content_type: &str) -> Option<VocabFileType> {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    match mime.as_str() {
+        "application/yaml" | "application/x-yaml" | "text/yaml"
+        | "text/x-yaml" => Some(VocabFileType::Yaml),
+        "application/json" => Some(VocabFileType::Json),
+        "text/csv" => Some(VocabFileType::Csv),
+        "text/tab-separated-values" => Some(VocabFileType::Tsv),
+        mime if mime == DOCX_MIME => Some(VocabFileType::Docx),
+        _ => None,
+    }
+}
+
+/// Fetch the vocab file at `url`, returning its contents and the file type
+/// given by its content type, or else by the extension of the URL
+async fn fetch(
+    url: &Url,
+) -> Result<(Vec<u8>, Option<VocabFileType>), CliError> {
+    let error = |e: reqwest::Error| {
+        CliError::with_source(&format!("failed to fetch {}: {}", url, e), e)
+    };
+    info!(target: "load_pipeline", "Fetching {}", url);
+    let response = CLIENT
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|x| x.error_for_status())
+        .map_err(error)?;
+    let filetype = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(filetype_from_content_type)
+        .or_else(|| filetype_from_path(Path::new(url.path())));
+    let buf = response.bytes().await.map_err(error)?;
+    Ok((buf.to_vec(), filetype))
 }
 
 #[async_trait]
@@ -44,104 +310,237 @@ impl Pipeline for LoadPipeline {
     ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
         info!(target: "load_pipeline", "Pipeline starting");
 
-        if input.is_some() {
-            Err("LoadPipeline does not accept input")?
+        if let Some(words) = &self.words {
+            if input.is_some() {
+                Err("LoadPipeline does not accept input when given words")?
+            }
+            let flashcard = parse_words(words)?;
+            info!(target: "load_pipeline", "Loaded {} flashcards from the command line", flashcard.len());
+            self.stats.record(|x| x.words_loaded += flashcard.len());
+            return Ok(PipelineIO::Flashcard(flashcard));
         }
 
-        let mut file = File::open(&self.path as &PathBuf)?;
-
-        let extension = self
-            .path
-            .extension()
-            .ok_or("Failed to get file extension")?
-            .to_str()
-            .ok_or("Failed to convert file extension to string")?;
-        let mut filetype = self.filetype;
-        if filetype.is_none() {
-            filetype = match extension {
-                "yml" | "yaml" => Some(VocabFileType::Yaml),
-                "json" => Some(VocabFileType::Json),
-                "docx" => Some(VocabFileType::Docx),
-                _ => Err("Failed to determine file type")?,
-            };
+        let (source, buf, filetype) = match (input, &self.path) {
+            (None, Some(VocabSource::Path(path))) => {
+                let mut buf = Vec::new();
+                File::open(path)?.read_to_end(&mut buf)?;
+                (path.display().to_string(), buf, filetype_from_path(path))
+            }
+            (None, Some(VocabSource::Url(url))) => {
+                let (buf, filetype) = fetch(url).await?;
+                (url.to_string(), buf, filetype)
+            }
+            (
+                Some(
+                    PipelineIO::Document { name, content }
+                    | PipelineIO::Documents { name, content, .. },
+                ),
+                None,
+            ) => {
+                let filetype = filetype_from_path(Path::new(&name));
+                (name, content, filetype)
+            }
+            (Some(_), Some(_)) => {
+                Err("LoadPipeline does not accept input when given a path")?
+            }
+            (Some(_), None) => Err("LoadPipeline only accepts document input")?,
+            (None, None) => {
+                Err("LoadPipeline requires a path, words or a document input")?
+            }
+        };
+
+        let filetype = self.filetype.or(filetype).ok_or_else(|| {
+            format!("Failed to determine file type of {}", source)
+        })?;
+        let mut flashcard = self.parse(filetype, &buf, &source)?;
+        if self.clean {
+            flashcard.retain(|x| {
+                let noise = is_noise(&x.word);
+                if noise {
+                    debug!(target: "load_pipeline", "Dropping {:?} | {:?}", x.word, x.definition);
+                }
+                !noise
+            });
         }
-        match filetype {
-            None => Err("Failed to determine file type")?,
-            Some(filetype) => {
-                let flashcard = match filetype {
-                    VocabFileType::Yaml => {
-                        info!(target: "load_pipeline", "Loading YAML file: {}", self.path.display());
-                        let mut contents = String::new();
-                        file.read_to_string(&mut contents)?;
-                        from_str::<Vec<Flashcard>>(&contents)?
-                    }
-                    VocabFileType::Json => {
-                        info!(target: "load_pipeline", "Loading JSON file: {}", self.path.display());
-                        from_reader(&mut file)?
-                    }
-                    VocabFileType::Docx => {
-                        info!(target: "load_pipeline", "Loading DOCX file: {}", self.path.display());
-                        let mut buf = Vec::new();
-                        File::open(&self.path as &PathBuf)?
-                            .read_to_end(&mut buf)?;
-                        let docx = read_docx(&buf)?;
-
-                        let mut flashcard = Vec::new();
-                        for table in
-                            docx.document.children.iter().filter_map(|x| {
-                                if let docx_rs::DocumentChild::Table(x) = x {
-                                    Some(x)
-                                } else {
-                                    None
-                                }
-                            })
-                        {
-                            let rows = &table.rows;
-                            if rows.is_empty() {
-                                warn!(target: "load_pipeline", "Skipping empty table");
-                                continue;
-                            }
-
-                            for row in rows.iter() {
-                                let TableChild::TableRow(row) = row;
-                                if row.cells.len() != 2 {
-                                    warn!(target: "load_pipeline", "Skipping row {:?} with {} columns", textify_row(row), row.cells.len());
-                                    continue;
-                                }
-
-                                let cells = &row.cells;
-                                let word = textify_cell(&cells[0]);
-                                let definition = textify_cell(&cells[1]);
-
-                                if !word.is_empty()
-                                    && !definition.is_empty()
-                                    && word.to_lowercase()
-                                        != definition.to_lowercase()
-                                {
-                                    let word = word
-                                        .replace("->", "→")
-                                        .replace(['“', '”'], "\"")
-                                        .replace('¨', "");
-                                    let definition = definition
-                                        .replace("->", "→")
-                                        .replace(['“', '”'], "\"")
-                                        .replace('¨', "");
-                                    flashcard
-                                        .push(Flashcard { word, definition });
-                                }
-                            }
-                        }
-                        flashcard
-                    }
-                };
-                Ok(PipelineIO::Flashcard(flashcard))
+        self.stats.record(|x| x.words_loaded += flashcard.len());
+        Ok(PipelineIO::Flashcard(flashcard))
+    }
+
+    async fn dry_run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        match (&input, &self.path) {
+            (None, Some(VocabSource::Url(url))) => {
+                info!(target: "load_pipeline", "Would fetch and load {}", url);
+                Ok(PipelineIO::Flashcard(vec![]))
             }
+            _ => self.run(input).await,
         }
     }
 
     fn name(&self) -> &'static str {
         "load"
     }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(self.stats.get())
+    }
+}
+
+/// Words that mark a table row as a header rather than a vocab entry
+const HEADER_WORDS: [&str; 6] = [
+    "palabra",
+    "vocabulario",
+    "español",
+    "spanish",
+    "word",
+    "term",
+];
+const HEADER_DEFINITIONS: [&str; 6] = [
+    "definición",
+    "definicion",
+    "significado",
+    "inglés",
+    "english",
+    "definition",
+];
+
+/// The replacements made by `clean_text`, in order
+const CLEAN_TEXT_REPLACEMENTS: [(&str, &str); 8] = [
+    // smart double quotes become straight quotes
+    ("“", "\""),
+    ("”", "\""),
+    ("„", "\""),
+    // smart single quotes become apostrophes
+    ("‘", "'"),
+    ("’", "'"),
+    // en dashes become hyphens, em dashes are kept
+    ("–", "-"),
+    // ASCII arrows become arrows
+    ("->", "→"),
+    // stray diaereses left by some keyboards are dropped
+    ("¨", ""),
+];
+
+/// Normalize the quotes, dashes and arrows of the text of a flashcard, so
+/// that a deck reads the same whatever file type it is loaded from
+fn clean_text(text: &str) -> String {
+    CLEAN_TEXT_REPLACEMENTS
+        .iter()
+        .fold(text.to_string(), |text, (from, to)| text.replace(from, to))
+}
+
+/// Parse a list of "word:definition" pairs separated by semicolons, or by
+/// commas if there is no semicolon, into flashcards. Definitions left out
+/// are empty.
+fn parse_words(words: &str) -> Result<Vec<Flashcard>, String> {
+    let separator = if words.contains(';') { ';' } else { ',' };
+    let flashcard = words
+        .split(separator)
+        .filter(|x| !x.trim().is_empty())
+        .map(|pair| {
+            let (word, definition) = pair.split_once(':').unwrap_or((pair, ""));
+            if word.trim().is_empty() {
+                return Err(format!("{:?} has no word", pair.trim()));
+            }
+            Ok(Flashcard::new(
+                &clean_text(word.trim()),
+                &clean_text(definition.trim()),
+            ))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if flashcard.is_empty() {
+        return Err("no words given".to_string());
+    }
+    Ok(flashcard)
+}
+
+/// Split delimited text into records of fields, where a field in double
+/// quotes may hold the delimiter, line breaks and doubled quotes. Blank
+/// lines are skipped.
+fn parse_delimited(text: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            c if quoted => field.push(c),
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records.retain(|x| x.iter().any(|x| !x.trim().is_empty()));
+    records
+}
+
+/// Parse the flashcards out of delimited text of word, definition and
+/// optional comma separated tags, skipping a header row
+fn parse_table(
+    text: &str,
+    delimiter: char,
+    source: &str,
+) -> Result<Vec<Flashcard>, String> {
+    let mut flashcard = vec![];
+    for (i, record) in parse_delimited(text, delimiter).iter().enumerate() {
+        let field = |i: usize| record.get(i).map_or("", |x| x.trim());
+        let (word, definition) = (field(0), field(1));
+        if i == 0 && is_header_row(word, definition) {
+            info!(target: "load_pipeline", "Skipping header {:?} | {:?}", word, definition);
+            continue;
+        }
+        if word.is_empty() {
+            warn!(target: "load_pipeline", "Skipping row {} without a word", i + 1);
+            continue;
+        }
+        flashcard.push(Flashcard {
+            word: word.to_string(),
+            definition: definition.to_string(),
+            tags: parse_tags(field(2)),
+            ..Default::default()
+        });
+    }
+    if flashcard.is_empty() {
+        return Err(format!("no flashcards found in {}", source));
+    }
+    Ok(flashcard)
+}
+
+/// Check whether a word is not vocabulary but a number (e.g. a page number)
+/// or a URL
+fn is_noise(word: &str) -> bool {
+    let word = word.trim().to_lowercase();
+    let numeric = word.chars().any(|c| c.is_ascii_digit())
+        && word.chars().all(|c| {
+            c.is_ascii_digit() || c.is_whitespace() || c.is_ascii_punctuation()
+        });
+    let url = word.contains("://") || word.starts_with("www.");
+    numeric || url
+}
+
+/// Check whether a row looks like a "Palabra | Definición" header
+fn is_header_row(word: &str, definition: &str) -> bool {
+    let normalize =
+        |x: &str| x.trim().trim_end_matches(':').trim().to_lowercase();
+    HEADER_WORDS.contains(&normalize(word).as_str())
+        && HEADER_DEFINITIONS.contains(&normalize(definition).as_str())
 }
 
 fn textify_row(row: &docx_rs::TableRow) -> String {
@@ -149,20 +548,20 @@ fn textify_row(row: &docx_rs::TableRow) -> String {
         + row
             .cells
             .iter()
-            .map(textify_cell)
+            .map(|x| textify_cell(x, false))
             .collect::<Vec<_>>()
             .join(" | ")
             .trim()
         + " |"
 }
 
-fn textify_cell(cell: &docx_rs::TableRowChild) -> String {
+fn textify_cell(cell: &docx_rs::TableRowChild, markup: bool) -> String {
     let TableRowChild::TableCell(cell) = cell;
     cell.children
         .iter()
         .map(|x| {
             if let docx_rs::TableCellContent::Paragraph(paragraph) = x {
-                textify_paragraph(paragraph)
+                textify_paragraph(paragraph, markup)
             } else {
                 String::new()
             }
@@ -172,29 +571,412 @@ fn textify_cell(cell: &docx_rs::TableRowChild) -> String {
         .to_string()
 }
 
-fn textify_paragraph(paragraph: &docx_rs::Paragraph) -> String {
-    paragraph
-        .children
+/// Textify a paragraph. With `markup`, the bold and italic text is wrapped
+/// in `**` and `*`, adjacent runs of the same style sharing the markers.
+fn textify_paragraph(paragraph: &docx_rs::Paragraph, markup: bool) -> String {
+    let runs = paragraph_runs(&paragraph.children);
+    if !markup {
+        return runs.into_iter().map(textify_run).collect();
+    }
+
+    let mut spans: Vec<(String, &str)> = vec![];
+    for run in runs {
+        let style = run_markup(run);
+        match spans.last_mut() {
+            Some((text, last)) if *last == style => {
+                text.push_str(&textify_run(run))
+            }
+            _ => spans.push((textify_run(run), style)),
+        }
+    }
+    spans
+        .into_iter()
+        .map(|(text, style)| {
+            let trimmed = text.trim();
+            if style.is_empty() || trimmed.is_empty() {
+                return text;
+            }
+            // the markers hug the text, leaving its surrounding spaces out
+            let start = text.len() - text.trim_start().len();
+            let end = start + trimmed.len();
+            format!(
+                "{}{style}{}{style}{}",
+                &text[..start],
+                trimmed,
+                &text[end..]
+            )
+        })
+        .collect()
+}
+
+/// Collect the runs of a paragraph, including the runs nested in hyperlinks
+/// and tracked insertions, as exported by Google Docs
+fn paragraph_runs(children: &[docx_rs::ParagraphChild]) -> Vec<&docx_rs::Run> {
+    children
         .iter()
-        .map(|x| {
-            if let docx_rs::ParagraphChild::Run(run) = x {
-                textify_run(run)
-            } else {
-                String::new()
+        .flat_map(|x| match x {
+            docx_rs::ParagraphChild::Run(run) => vec![run.as_ref()],
+            docx_rs::ParagraphChild::Hyperlink(hyperlink) => {
+                paragraph_runs(&hyperlink.children)
             }
+            docx_rs::ParagraphChild::Insert(insert) => insert
+                .children
+                .iter()
+                .filter_map(|x| match x {
+                    docx_rs::InsertChild::Run(run) => Some(run.as_ref()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
         })
-        .collect::<String>()
+        .collect()
+}
+
+/// Return the markup markers of the style of a run, empty for plain text
+fn run_markup(run: &docx_rs::Run) -> &'static str {
+    let property = &run.run_property;
+    match (is_on(&property.bold), is_on(&property.italic)) {
+        (true, true) => "***",
+        (true, false) => "**",
+        (false, true) => "*",
+        (false, false) => "",
+    }
+}
+
+/// Check whether a toggle property such as bold is set and on. docx-rs
+/// keeps the value private, so it is read back from its serialization.
+fn is_on<T: serde::Serialize>(property: &Option<T>) -> bool {
+    property.as_ref().is_some_and(|x| {
+        serde_json::to_value(x)
+            .is_ok_and(|x| x == serde_json::Value::Bool(true))
+    })
 }
 
 fn textify_run(run: &docx_rs::Run) -> String {
     run.children
         .iter()
-        .map(|x| {
-            if let docx_rs::RunChild::Text(text) = x {
-                text.text.clone()
-            } else {
-                String::new()
+        .map(|x| match x {
+            docx_rs::RunChild::Text(text) => text.text.clone(),
+            // tabs and line breaks separate words
+            docx_rs::RunChild::Tab(_) | docx_rs::RunChild::Break(_) => {
+                " ".to_string()
             }
+            _ => String::new(),
         })
         .collect::<String>()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_header_row() {
+        assert!(is_header_row("Palabra", "Definición"));
+        assert!(is_header_row("Vocabulario:", "English"));
+        assert!(!is_header_row("la casa", "the house"));
+        assert!(!is_header_row("palabra", "word"));
+    }
+
+    #[test]
+    fn test_is_noise() {
+        assert!(is_noise("12"));
+        assert!(is_noise(" - 3 - "));
+        assert!(is_noise("1.5"));
+        assert!(is_noise("https://www.spanishdict.com/translate/casa"));
+        assert!(is_noise("www.example.com"));
+        assert!(!is_noise("la casa"));
+        assert!(!is_noise("¿?"));
+        assert!(!is_noise("los 3 cerditos"));
+    }
+
+    #[tokio::test]
+    async fn test_words() {
+        let pairs = |words: &str| {
+            parse_words(words).map(|x| {
+                x.into_iter()
+                    .map(|x| (x.word, x.definition))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let pair = |word: &str, definition: &str| {
+            (word.to_string(), definition.to_string())
+        };
+        assert_eq!(
+            pairs("la casa:the house, el perro ,"),
+            Ok(vec![pair("la casa", "the house"), pair("el perro", "")])
+        );
+        assert_eq!(
+            pairs("correr: to run, to jog; luz:light"),
+            Ok(vec![pair("correr", "to run, to jog"), pair("luz", "light")])
+        );
+        assert!(pairs(":the house").is_err());
+        assert!(pairs(" ; ").is_err());
+
+        let pipeline =
+            LoadPipeline::parse_from(["load", "--words", "casa:house,perro"]);
+        let PipelineIO::Flashcard(flashcards) =
+            pipeline.run(None).await.unwrap()
+        else {
+            panic!("should have loaded flashcards");
+        };
+        assert_eq!(flashcards.len(), 2);
+        assert_eq!(pipeline.stats().unwrap().words_loaded, 2);
+    }
+
+    #[tokio::test]
+    async fn test_clean() {
+        let input = || {
+            PipelineIO::Document {
+            name: "flashcard.yml".to_string(),
+            content: b"- [casa, house]\n- ['42', page]\n- [https://example.com, link]\n"
+                .to_vec(),
+        }
+        };
+        let count = |output| match output {
+            PipelineIO::Flashcard(flashcards) => flashcards.len(),
+            _ => panic!("should have loaded flashcards"),
+        };
+        let pipeline = LoadPipeline::parse_from(["load"]);
+        assert_eq!(count(pipeline.run(Some(input())).await.unwrap()), 3);
+        let pipeline = LoadPipeline::parse_from(["load", "--clean"]);
+        assert_eq!(count(pipeline.run(Some(input())).await.unwrap()), 1);
+    }
+
+    #[test]
+    fn test_textify_markup() {
+        use docx_rs::{Hyperlink, HyperlinkType, Paragraph, Run};
+
+        let paragraph = Paragraph::new()
+            .add_run(Run::new().add_text("la ").bold())
+            .add_hyperlink(
+                Hyperlink::new("https://example.com", HyperlinkType::External)
+                    .add_run(Run::new().add_text("casa").bold()),
+            )
+            .add_run(Run::new().add_text(" (f)").italic())
+            .add_run(Run::new().add_text(", "))
+            .add_run(Run::new().add_text("hogar").bold().italic());
+        assert_eq!(
+            textify_paragraph(&paragraph, true),
+            "**la casa** *(f)*, ***hogar***"
+        );
+        assert_eq!(textify_paragraph(&paragraph, false), "la casa (f), hogar");
+    }
+
+    #[test]
+    fn test_clean_text() {
+        assert_eq!(clean_text("“hola” ‘chao’"), "\"hola\" 'chao'");
+        assert_eq!(clean_text("ir -> fui – 1"), "ir → fui - 1");
+        assert_eq!(clean_text("pingu¨ino — penguin"), "pinguino — penguin");
+    }
+
+    /// Build a docx file holding a table of `rows`
+    fn docx_table(rows: &[&[&str]]) -> Vec<u8> {
+        use docx_rs::{Docx, Paragraph, Run, Table, TableCell, TableRow};
+
+        let cell = |text: &&str| {
+            TableCell::new().add_paragraph(
+                Paragraph::new().add_run(Run::new().add_text(*text)),
+            )
+        };
+        let rows = rows
+            .iter()
+            .map(|row| TableRow::new(row.iter().map(cell).collect()))
+            .collect();
+        let mut docx = std::io::Cursor::new(Vec::new());
+        Docx::new()
+            .add_table(Table::new(rows))
+            .build()
+            .pack(&mut docx)
+            .unwrap();
+        docx.into_inner()
+    }
+
+    #[test]
+    fn test_clean_text_formats() {
+        let (word, definition) = ("dijo “hola”", "he said ‘hi’ -> greeted");
+        let yaml = format!("- [\"{}\", \"{}\"]\n", word, definition);
+        let json = serde_json::to_vec(&[[word, definition]]).unwrap();
+        let docx = docx_table(&[&[word, definition]]);
+
+        let pipeline = LoadPipeline::new(None, None);
+        let expected =
+            vec![Flashcard::new("dijo \"hola\"", "he said 'hi' → greeted")];
+        for (filetype, buf) in [
+            (VocabFileType::Yaml, yaml.into_bytes()),
+            (VocabFileType::Json, json),
+            (VocabFileType::Docx, docx),
+        ] {
+            assert_eq!(
+                pipeline.parse(filetype, &buf, "test").unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_swap_columns() {
+        let docx = docx_table(&[
+            &["Definition", "Palabra"],
+            &["the “house”", "la casa"],
+            &["dog", "el perro"],
+        ]);
+        let pipeline = LoadPipeline::parse_from(["load", "--swap-columns"]);
+        let flashcards = pipeline
+            .parse(VocabFileType::Docx, &docx, "swapped.docx")
+            .unwrap();
+        let pairs = flashcards
+            .iter()
+            .map(|x| (x.word.as_str(), x.definition.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, [("la casa", "the \"house\""), ("el perro", "dog")]);
+    }
+
+    #[test]
+    fn test_parse_google_docs() {
+        let pipeline = LoadPipeline::new(None, None);
+        let flashcards = pipeline
+            .parse(
+                VocabFileType::Docx,
+                include_bytes!("fixtures/google_docs_vocab.docx"),
+                "google_docs_vocab.docx",
+            )
+            .unwrap();
+        let pairs = flashcards
+            .iter()
+            .map(|x| (x.word.as_str(), x.definition.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pairs,
+            [
+                ("la casa", "the house"),
+                ("el perro", "dog (animal)"),
+                ("correr", "to run")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_document_input() {
+        let pipeline = LoadPipeline::parse_from(["load"]);
+        let input = PipelineIO::Document {
+            name: "flashcard.yml".to_string(),
+            content: b"- [casa, house]\n- [perro, dog]\n".to_vec(),
+        };
+        let output = pipeline.run(Some(input)).await.unwrap();
+        let PipelineIO::Flashcard(flashcards) = output else {
+            panic!("should have loaded flashcards");
+        };
+        assert_eq!(flashcards.len(), 2);
+        assert_eq!(flashcards[1].word, "perro");
+        assert_eq!(pipeline.stats().unwrap().words_loaded, 2);
+
+        let input = PipelineIO::Document {
+            name: "flashcard.pdf".to_string(),
+            content: vec![],
+        };
+        assert!(pipeline.run(Some(input)).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_table() {
+        let csv = "Palabra,Definición\r\n\
+                   la casa,the house\r\n\
+                   \"el banco\",\"1. bank, 2. bench\",\"unidad 2, ciudad\"\n\
+                   \n\
+                   decir,\"to say \"\"hi\"\"\nor bye\"\n\
+                   la biblioteca\n";
+        let flashcards = parse_table(csv, ',', "vocab.csv").unwrap();
+        let pairs = flashcards
+            .iter()
+            .map(|x| (x.word.as_str(), x.definition.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pairs,
+            [
+                ("la casa", "the house"),
+                ("el banco", "1. bank, 2. bench"),
+                ("decir", "to say \"hi\"\nor bye"),
+                ("la biblioteca", ""),
+            ]
+        );
+        assert_eq!(flashcards[1].tags, ["unidad 2", "ciudad"]);
+
+        let flashcards =
+            parse_table("perro\tdog, hound\n", '\t', "vocab.tsv").unwrap();
+        assert_eq!(flashcards[0].definition, "dog, hound");
+        assert!(parse_table("Word,Definition\n", ',', "empty.csv").is_err());
+    }
+
+    #[test]
+    fn test_filetype_from_content_type() {
+        let filetype = filetype_from_content_type;
+        assert!(filetype("application/json") == Some(VocabFileType::Json));
+        assert!(
+            filetype("text/yaml; charset=utf-8") == Some(VocabFileType::Yaml)
+        );
+        assert!(filetype(DOCX_MIME) == Some(VocabFileType::Docx));
+        assert!(filetype("text/csv") == Some(VocabFileType::Csv));
+        assert!(filetype("text/plain; charset=utf-8").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_load_url() {
+        let mut server = mockito::Server::new_async().await;
+        // served as plain text, the type is taken from the extension
+        server
+            .mock("GET", "/raw/vocab.yml")
+            .with_header("content-type", "text/plain; charset=utf-8")
+            .with_body("- [casa, house]\n- [perro, dog]\n")
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/export")
+            .with_header("content-type", "application/json")
+            .with_body(r#"[["gato", "cat"]]"#)
+            .create_async()
+            .await;
+        // a spreadsheet export, whose URL has no extension
+        server
+            .mock("GET", "/spreadsheets/export")
+            .with_header("content-type", "text/csv")
+            .with_body("Word,Definition\nratón,mouse\n")
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/missing.yml")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let load = |path: &str| {
+            let url = format!("{}{}", server.url(), path);
+            LoadPipeline::try_parse_from(["load", &url]).unwrap()
+        };
+        let words = |output: PipelineIO| match output {
+            PipelineIO::Flashcard(flashcards) => {
+                flashcards.into_iter().map(|x| x.word).collect::<Vec<_>>()
+            }
+            _ => panic!("should have loaded flashcards"),
+        };
+        let output = load("/raw/vocab.yml").run(None).await.unwrap();
+        assert_eq!(words(output), ["casa", "perro"]);
+        let output = load("/export").run(None).await.unwrap();
+        assert_eq!(words(output), ["gato"]);
+        let output = load("/spreadsheets/export").run(None).await.unwrap();
+        assert_eq!(words(output), ["ratón"]);
+
+        let err = load("/missing.yml").run(None).await.unwrap_err();
+        assert!(err.to_string().contains("/missing.yml"));
+        assert!(err.is::<CliError>());
+        // the failed request is kept as the cause
+        assert!(err.source().unwrap().is::<reqwest::Error>());
+
+        // a dry run does not fetch anything
+        let output = load("/missing.yml").dry_run(None).await.unwrap();
+        assert!(words(output).is_empty());
+
+        assert!(LoadPipeline::try_parse_from(["load", "https://"]).is_err());
+        assert!(LoadPipeline::try_parse_from(["load", "missing.yml"]).is_err());
+    }
+}
@@ -1,74 +1,433 @@
+pub mod anki;
+pub mod define;
+pub mod dictionary;
 mod docx;
+pub mod enrich;
 pub mod flashcard;
+mod html;
 pub mod load;
+pub mod notion;
 pub mod transform;
 pub mod visual_vocab;
 
+use std::{
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
 use async_trait::async_trait;
+use clap::Args;
 use clipboard::{ClipboardContext, ClipboardProvider};
 pub use flashcard::Flashcard;
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::spider::spanish_dict::{DictionaryEntry, SearchStrategy};
 
 /// Represents the output of a pipeline stage.
 #[derive(Debug)]
 pub enum PipelineIO {
-    Document { name: String, content: Vec<u8> },
+    Document {
+        name: String,
+        content: Vec<u8>,
+    },
+    /// A document written along with auxiliary files named relative to it,
+    /// e.g. a summary or the sources of its images
+    Documents {
+        name: String,
+        content: Vec<u8>,
+        files: Vec<(String, Vec<u8>)>,
+    },
     Clipboard(String),
+    Stdout(String),
     Flashcard(Vec<Flashcard>),
+    Dictionary(Vec<DictionaryEntry>),
 }
 
 impl PipelineIO {
-    /// Dump the output to the specified path.
-    pub fn dump(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let out_dir = format!("./out/{}", name);
-        std::fs::create_dir_all(&out_dir)?;
+    /// Return the paths of the files that dumping the output to the group
+    /// `name` under `out_dir` writes. Relative directories are resolved
+    /// against the current directory.
+    pub fn dump_paths(
+        &self,
+        out_dir: &Path,
+        name: &str,
+    ) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let out_dir = if out_dir.is_absolute() {
+            out_dir.to_path_buf()
+        } else {
+            std::env::current_dir()?.join(out_dir)
+        }
+        .join(name);
+
+        Ok(match self {
+            PipelineIO::Document { name, .. } => vec![out_dir.join(name)],
+            PipelineIO::Documents { name, files, .. } => std::iter::once(name)
+                .chain(files.iter().map(|(name, _)| name))
+                .map(|name| out_dir.join(name))
+                .collect(),
+            PipelineIO::Clipboard(_) | PipelineIO::Stdout(_) => vec![],
+            PipelineIO::Flashcard(_) => vec![out_dir.join("flashcard.yml")],
+            PipelineIO::Dictionary(_) => vec![
+                out_dir.join("dictionary.json"),
+                out_dir.join("dictionary.yml"),
+            ],
+        })
+    }
+
+    /// Dump the output to the group `name` under `out_dir`. Relative
+    /// directories are resolved against the current directory.
+    ///
+    /// Return the paths of the files written, which is empty for outputs
+    /// that do not touch the disk (e.g. the clipboard or stdout).
+    pub fn dump(
+        &self,
+        out_dir: &Path,
+        name: &str,
+    ) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let paths = self.dump_paths(out_dir, name)?;
+        for dir in paths.iter().filter_map(|path| path.parent()) {
+            std::fs::create_dir_all(dir)?;
+        }
 
         match self {
-            PipelineIO::Document { name, content } => {
-                let path = format!("{}/{}", out_dir, name);
-                std::fs::write(path, content)?;
+            PipelineIO::Document { content, .. } => {
+                std::fs::write(&paths[0], content)?;
+            }
+            PipelineIO::Documents { content, files, .. } => {
+                let contents = std::iter::once(content)
+                    .chain(files.iter().map(|(_, content)| content));
+                for (path, content) in paths.iter().zip(contents) {
+                    std::fs::write(path, content)?;
+                }
             }
             PipelineIO::Clipboard(info) => {
-                let mut clipboard: ClipboardContext =
-                    clipboard::ClipboardProvider::new().unwrap();
-                clipboard.set_contents(info.to_owned()).unwrap();
-                let clipboard_info = if info.len() > 20 {
-                    format!("{}...", &info[..20])
-                } else {
-                    info.to_owned()
+                let clipboard_error = |err: Box<dyn std::error::Error>| {
+                    PipelineError::new(&format!(
+                        "failed to copy to the clipboard ({}), which may be \
+                         missing over SSH or in CI; use --stdout or drop \
+                         --clipboard to print the output instead",
+                        err
+                    ))
                 };
-                println!("Clipboard copied: {}", clipboard_info);
+                let mut clipboard: ClipboardContext =
+                    ClipboardProvider::new().map_err(clipboard_error)?;
+                clipboard
+                    .set_contents(info.to_owned())
+                    .map_err(clipboard_error)?;
+                println!("Clipboard copied: {}", preview(info, 20));
+            }
+            PipelineIO::Stdout(info) => {
+                println!("{}", info);
             }
             PipelineIO::Flashcard(flashcards) => {
-                let path = format!("{}/flashcard.yml", out_dir);
                 let serialized = serde_yaml::to_string(flashcards)?;
-                std::fs::write(path, serialized)?;
+                std::fs::write(&paths[0], serialized)?;
+            }
+            PipelineIO::Dictionary(entries) => {
+                std::fs::write(
+                    &paths[0],
+                    serde_json::to_string_pretty(entries)?,
+                )?;
+                std::fs::write(&paths[1], serde_yaml::to_string(entries)?)?;
             }
         }
+        Ok(paths)
+    }
+
+    /// Write the output to `writer` instead of dumping it, e.g. to pipe it
+    /// into another program. Documents are written as is without their
+    /// auxiliary files, and flashcards and dictionary entries as YAML.
+    pub fn write_to(
+        &self,
+        writer: &mut impl Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            PipelineIO::Document { content, .. }
+            | PipelineIO::Documents { content, .. } => {
+                writer.write_all(content)?
+            }
+            PipelineIO::Clipboard(info) | PipelineIO::Stdout(info) => {
+                writeln!(writer, "{}", info)?
+            }
+            PipelineIO::Flashcard(flashcards) => {
+                serde_yaml::to_writer(&mut *writer, flashcards)?
+            }
+            PipelineIO::Dictionary(entries) => {
+                serde_yaml::to_writer(&mut *writer, entries)?
+            }
+        }
+        writer.flush()?;
         Ok(())
     }
 }
 
+/// Return the first `chars` characters of the text, followed by "..." if
+/// it is longer
+fn preview(text: &str, chars: usize) -> String {
+    match text.char_indices().nth(chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+/// Represents the position of the running pipeline in a chain of pipelines,
+/// e.g. `2/3 visual_vocab`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunContext {
+    /// The 1-based index of the pipeline
+    pub stage: usize,
+    /// The number of pipelines in the chain
+    pub total: usize,
+    pub name: &'static str,
+}
+
+tokio::task_local! {
+    static RUN_CONTEXT: RunContext;
+}
+
+impl RunContext {
+    /// Run `future` as the pipeline of this context. Chains running at once
+    /// each keep their own context.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        RUN_CONTEXT.scope(self, future).await
+    }
+
+    /// Run `f` in `context`, e.g. on a thread spawned by the pipeline
+    pub fn sync_scope<R>(context: Option<Self>, f: impl FnOnce() -> R) -> R {
+        match context {
+            Some(context) => RUN_CONTEXT.sync_scope(context, f),
+            None => f(),
+        }
+    }
+
+    /// Return the context of the running pipeline, if any
+    pub fn current() -> Option<Self> {
+        RUN_CONTEXT.try_with(|x| *x).ok()
+    }
+
+    /// Spawn a task keeping the context of the running pipeline
+    pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let context = Self::current();
+        tokio::spawn(async move {
+            match context {
+                Some(context) => context.scope(future).await,
+                None => future.await,
+            }
+        })
+    }
+
+    /// Spawn a blocking task keeping the context of the running pipeline
+    pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let context = Self::current();
+        tokio::task::spawn_blocking(move || Self::sync_scope(context, f))
+    }
+}
+
+impl std::fmt::Display for RunContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{} {}", self.stage, self.total, self.name)
+    }
+}
+
+/// Asks the pipelines of a run to stop early, e.g. on Ctrl-C. The pipelines
+/// that can return the output they have so far, and the others finish.
+/// Clones share the same state, and each run has its own.
+#[derive(Debug, Default, Clone)]
+pub struct Interrupt(Arc<(AtomicBool, Notify)>);
+
+impl Interrupt {
+    /// Ask the pipelines holding this interrupt to stop
+    pub fn interrupt(&self) {
+        self.0 .0.store(true, Ordering::SeqCst);
+        self.0 .1.notify_waiters();
+    }
+
+    /// Check whether the run was interrupted
+    pub fn is_interrupted(&self) -> bool {
+        self.0 .0.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the run is interrupted
+    pub async fn interrupted(&self) {
+        // created before checking, so that an interrupt in between still
+        // wakes
+        let notified = self.0 .1.notified();
+        if self.is_interrupted() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// The number of searches of a word before giving up, shared by the
+/// pipelines looking up words
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Args)]
+pub struct SearchAttempts {
+    /// The number of searches of a word itself
+    #[clap(long, default_value_t = SearchStrategy::default().direct_attempts)]
+    pub direct_attempts: u32,
+
+    /// The number of keywords of an unknown word searched, best first
+    #[clap(long, default_value_t = SearchStrategy::default().keyword_attempts)]
+    pub keyword_attempts: u32,
+}
+
+impl Default for SearchAttempts {
+    fn default() -> Self {
+        let strategy = SearchStrategy::default();
+        Self {
+            direct_attempts: strategy.direct_attempts,
+            keyword_attempts: strategy.keyword_attempts,
+        }
+    }
+}
+
+impl SearchAttempts {
+    /// Create a strategy making these attempts, retrying with the keywords
+    /// of a word if `keyword_fallback` is set and with its dictionary forms
+    /// if `lemmatize` is set
+    pub fn strategy(
+        &self,
+        keyword_fallback: bool,
+        lemmatize: bool,
+    ) -> SearchStrategy {
+        SearchStrategy {
+            direct_attempts: self.direct_attempts,
+            keyword_attempts: self.keyword_attempts,
+            ..SearchStrategy::new(keyword_fallback, lemmatize)
+        }
+    }
+}
+
 /// Represents a pipeline for processing the input.
 #[async_trait]
-pub trait Pipeline {
+pub trait Pipeline: Send + Sync {
     /// Processes the input and returns the output.
     async fn run(
         &self,
         input: Option<PipelineIO>,
     ) -> Result<PipelineIO, Box<dyn std::error::Error>>;
 
+    /// Logs what processing the input would do, without any network I/O or
+    /// writing files, and returns a stand-in for the output. Pipelines that
+    /// do neither are simply run.
+    async fn dry_run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        self.run(input).await
+    }
+
     /// Return the name of the pipeline.
     fn name(&self) -> &'static str;
+
+    /// Tell the pipeline the directory the outputs are dumped to, for the
+    /// files it keeps between runs.
+    fn set_out_dir(&mut self, _dir: &Path) {}
+
+    /// Give the pipeline the interrupt of the run, for the pipelines that
+    /// can stop early with the output they have so far.
+    fn set_interrupt(&mut self, _interrupt: Interrupt) {}
+
+    /// Check whether the pipeline looks up unknown words with the keyword
+    /// model, so that the model is loaded before the run.
+    fn needs_models(&self) -> bool {
+        false
+    }
+
+    /// Return the counters of the work done by the last run, if the pipeline
+    /// keeps any.
+    fn stats(&self) -> Option<Stats> {
+        None
+    }
+}
+
+/// Represents the counters of the work done by a pipeline of a run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of words loaded from vocab files
+    pub words_loaded: usize,
+    /// The number of flashcards or visual flashcards made
+    pub cards_built: usize,
+    /// The number of cards for which no image could be downloaded
+    pub image_failures: usize,
+}
+
+impl std::fmt::Display for Stats {
+    /// List the counters of the work done, leaving out those at zero
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let counters = [
+            (self.words_loaded, "words loaded"),
+            (self.cards_built, "cards built"),
+            (self.image_failures, "image failures"),
+        ];
+        let counters = counters
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, name)| format!("{} {}", count, name))
+            .collect::<Vec<_>>();
+        match counters.as_slice() {
+            [] => write!(f, "nothing done"),
+            counters => write!(f, "{}", counters.join(", ")),
+        }
+    }
+}
+
+/// Collects the stats of a pipeline from the tasks it spawns. Clones share
+/// the same counters.
+#[derive(Debug, Default, Clone)]
+pub struct StatsRecorder(Arc<Mutex<Stats>>);
+
+impl StatsRecorder {
+    /// Update the counters with `f`
+    pub fn record(&self, f: impl FnOnce(&mut Stats)) {
+        f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    /// Return the counters recorded so far
+    pub fn get(&self) -> Stats {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The kind of a pipeline error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineErrorKind {
+    /// Typst failed to compile the flashcards
+    Typst,
+    /// No image could be downloaded for a card
+    Image,
+    /// Any other failure
+    Other,
 }
 
 /// Represents a Pipeline Error
 
 #[derive(Debug)]
 pub struct PipelineError {
+    kind: PipelineErrorKind,
     message: String,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
 }
 
-impl std::error::Error for PipelineError {}
+impl std::error::Error for PipelineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_deref().map(|x| x as _)
+    }
+}
 
 impl std::fmt::Display for PipelineError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -78,8 +437,171 @@ impl std::fmt::Display for PipelineError {
 
 impl PipelineError {
     pub fn new(message: &str) -> Self {
+        Self::with_kind(PipelineErrorKind::Other, message)
+    }
+
+    /// Create a new pipeline error of the given kind
+    pub fn with_kind(kind: PipelineErrorKind, message: &str) -> Self {
         Self {
+            kind,
             message: message.to_owned(),
+            source: None,
         }
     }
+
+    /// Create a new pipeline error caused by `source`
+    pub fn with_source(
+        message: &str,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            source: Some(Box::new(source)),
+            ..Self::new(message)
+        }
+    }
+
+    /// Return the error as one of the given kind, keeping its message and
+    /// source
+    pub fn of_kind(self, kind: PipelineErrorKind) -> Self {
+        Self { kind, ..self }
+    }
+
+    /// Return the kind of the error
+    pub fn kind(&self) -> PipelineErrorKind {
+        self.kind
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_context() {
+        let context = |stage| RunContext {
+            stage,
+            total: 2,
+            name: "load",
+        };
+        assert_eq!(RunContext::current(), None);
+        // chains running at once, and the tasks they spawn, keep their own
+        let chain = |stage| {
+            context(stage).scope(async move {
+                tokio::task::yield_now().await;
+                let spawned =
+                    RunContext::spawn(async { RunContext::current() });
+                let blocking = RunContext::spawn_blocking(RunContext::current);
+                (
+                    RunContext::current(),
+                    spawned.await.unwrap(),
+                    blocking.await.unwrap(),
+                )
+            })
+        };
+        let (first, second) = tokio::join!(chain(1), chain(2));
+        assert_eq!(
+            first,
+            (Some(context(1)), Some(context(1)), Some(context(1)))
+        );
+        assert_eq!(
+            second,
+            (Some(context(2)), Some(context(2)), Some(context(2)))
+        );
+        assert_eq!(RunContext::current(), None);
+    }
+
+    #[tokio::test]
+    async fn test_interrupt() {
+        let interrupt = Interrupt::default();
+        let waiting = tokio::spawn({
+            let interrupt = interrupt.clone();
+            async move { interrupt.interrupted().await }
+        });
+        tokio::task::yield_now().await;
+        interrupt.interrupt();
+        waiting.await.unwrap();
+        assert!(interrupt.is_interrupted());
+        interrupt.interrupted().await;
+        // the next run starts afresh
+        assert!(!Interrupt::default().is_interrupted());
+    }
+
+    #[test]
+    fn test_stats() {
+        let recorder = StatsRecorder::default();
+        recorder.clone().record(|x| x.words_loaded += 3);
+        recorder.record(|x| x.image_failures += 1);
+        recorder.record(|x| x.cards_built += 2);
+        assert_eq!(
+            recorder.get().to_string(),
+            "3 words loaded, 2 cards built, 1 image failures"
+        );
+        let stats = Stats {
+            cards_built: 2,
+            ..Stats::default()
+        };
+        assert_eq!(stats.to_string(), "2 cards built");
+        assert_eq!(Stats::default().to_string(), "nothing done");
+    }
+
+    #[test]
+    fn test_write_to() {
+        let write = |output: PipelineIO| {
+            let mut buf = Vec::new();
+            output.write_to(&mut buf).unwrap();
+            buf
+        };
+        let content = vec![0x50, 0x4b, 0xff];
+        assert_eq!(
+            write(PipelineIO::Document {
+                name: "flashcard.pdf".to_string(),
+                content: content.clone(),
+            }),
+            content
+        );
+        assert_eq!(
+            write(PipelineIO::Flashcard(vec![Flashcard::new(
+                "la casa",
+                "the house"
+            )])),
+            b"- - la casa\n  - the house\n"
+        );
+        assert_eq!(write(PipelineIO::Stdout("luz".to_string())), b"luz\n");
+    }
+
+    #[test]
+    fn test_preview() {
+        // The 20th byte falls inside the "ó"
+        let text = "la casa de la canción";
+        assert!(!text.is_char_boundary(20));
+        assert_eq!(preview(text, 20), "la casa de la canció...");
+        assert_eq!(preview("la canción", 20), "la canción");
+        assert_eq!(preview("", 20), "");
+    }
+
+    #[test]
+    fn test_dump_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = PipelineIO::Documents {
+            name: "sheet.docx".to_string(),
+            content: b"docx".to_vec(),
+            files: vec![
+                ("sheet.sources.txt".to_string(), b"sources".to_vec()),
+                ("summary/sheet.txt".to_string(), b"summary".to_vec()),
+            ],
+        };
+        let group = dir.path().join("visual");
+        assert_eq!(
+            output.dump(dir.path(), "visual").unwrap(),
+            vec![
+                group.join("sheet.docx"),
+                group.join("sheet.sources.txt"),
+                group.join("summary/sheet.txt"),
+            ]
+        );
+        let read = |name: &str| std::fs::read(group.join(name)).unwrap();
+        assert_eq!(read("sheet.docx"), b"docx");
+        assert_eq!(read("sheet.sources.txt"), b"sources");
+        assert_eq!(read("summary/sheet.txt"), b"summary");
+    }
 }
@@ -0,0 +1,140 @@
+use async_trait::async_trait;
+use clap::Parser;
+use log::info;
+use serde_json::{json, Value};
+
+use super::{Flashcard, Pipeline, PipelineError, PipelineIO};
+use crate::spider::{send_with_retry, CLIENT};
+
+/// The endpoint for creating Notion pages
+const NOTION_PAGES_URL: &str = "https://api.notion.com/v1/pages";
+
+/// The version of the Notion API spoken by this pipeline
+const NOTION_VERSION: &str = "2022-06-28";
+
+/// The number of attempts made for each page before giving up
+const NOTION_RETRY_ATTEMPTS: u32 = 5;
+
+/// A pipeline for exporting flashcards into a Notion database
+#[derive(Debug, Parser)]
+pub struct NotionPipeline {
+    /// The token of the Notion integration
+    #[clap(short, long, env = "NOTION_TOKEN", hide_env_values = true)]
+    token: String,
+
+    /// The id of the database to add the pages to
+    #[clap(short, long, env = "NOTION_DATABASE_ID")]
+    database: String,
+
+    /// The title property that holds the word
+    #[clap(long, default_value = "Word")]
+    word_property: String,
+
+    /// The text property that holds the definition
+    #[clap(long, default_value = "Definition")]
+    definition_property: String,
+}
+
+impl NotionPipeline {
+    /// Create a pipeline adding pages to the database `database` with the
+    /// integration `token`
+    pub fn new(token: &str, database: &str) -> Self {
+        Self {
+            token: token.to_string(),
+            database: database.to_string(),
+            word_property: "Word".to_string(),
+            definition_property: "Definition".to_string(),
+        }
+    }
+
+    /// Create a page for the flashcard and return its id
+    async fn create_page(
+        &self,
+        card: &Flashcard,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let request = CLIENT
+            .post(NOTION_PAGES_URL)
+            .bearer_auth(&self.token)
+            .header("Notion-Version", NOTION_VERSION)
+            .json(&json!({
+                "parent": { "database_id": self.database },
+                "properties": {
+                    self.word_property.as_str(): {
+                        "title": [{ "text": { "content": card.word } }],
+                    },
+                    self.definition_property.as_str(): {
+                        "rich_text": [
+                            { "text": { "content": card.definition } },
+                        ],
+                    },
+                },
+            }));
+        let response = send_with_retry(request, NOTION_RETRY_ATTEMPTS).await?;
+        let status = response.status();
+        let body: Value = response.json().await?;
+        if !status.is_success() {
+            return Err(PipelineError::new(&format!(
+                "failed to create Notion page for {}: {} {}",
+                card.word,
+                status,
+                body["message"].as_str().unwrap_or_default()
+            ))
+            .into());
+        }
+        Ok(body["id"]
+            .as_str()
+            .ok_or("Notion returned a page without an id")?
+            .to_string())
+    }
+}
+
+#[async_trait]
+impl Pipeline for NotionPipeline {
+    async fn run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let flashcards = match input {
+            Some(PipelineIO::Flashcard(flashcard)) => flashcard,
+            _ => {
+                return Err(Box::new(PipelineError::new(
+                    "input is not a flashcard",
+                )))
+            }
+        };
+
+        let mut pages = Vec::new();
+        for card in &flashcards {
+            let page = self.create_page(card).await?;
+            info!(target: "notion", "Created page {} for {}", page, card.word);
+            pages.push(page);
+        }
+        info!(target: "notion", "Created {} pages: {}", pages.len(), pages.join(", "));
+
+        Ok(PipelineIO::Flashcard(flashcards))
+    }
+
+    async fn dry_run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let flashcards = match input {
+            Some(PipelineIO::Flashcard(flashcard)) => flashcard,
+            _ => {
+                return Err(Box::new(PipelineError::new(
+                    "input is not a flashcard",
+                )))
+            }
+        };
+
+        for card in &flashcards {
+            info!(target: "notion", "Would create page for {} in database {}", card.word, self.database);
+        }
+
+        Ok(PipelineIO::Flashcard(flashcards))
+    }
+
+    fn name(&self) -> &'static str {
+        "notion"
+    }
+}
@@ -1,9 +1,17 @@
-use std::io::{Read, Write};
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
 
 use async_trait::async_trait;
 use clap::{Parser, ValueEnum};
+use log::{info, warn};
+use rust_xlsxwriter::{Format, Workbook};
 
-use super::{Flashcard, Pipeline, PipelineError, PipelineIO};
+use super::{
+    html, Flashcard, Pipeline, PipelineError, PipelineErrorKind, PipelineIO,
+    RunContext, Stats, StatsRecorder,
+};
 
 /// Represents the different file types that can be loaded
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -11,6 +19,12 @@ pub enum TransformOutputType {
     Yaml,
     Pdf,
     Json,
+    Xlsx,
+    Html,
+    /// Comma separated text, e.g. for spreadsheets
+    Csv,
+    /// Tab separated text, e.g. for importing into Quizlet or Anki
+    Tsv,
 }
 
 #[derive(Parser)]
@@ -34,83 +48,423 @@ pub struct TransformPipeline {
     /// The fontsize of the flashcard, specified in Typst length
     #[clap(short, long, default_value = "14pt")]
     fontsize: String,
+
+    /// Shrink the font of the flashcards whose content is long, so that it
+    /// fits in the card.
+    #[clap(long)]
+    auto_fit: bool,
+
+    /// Mirror the backs of each row, so that they line up with the fronts
+    /// when printed double-sided
+    #[clap(long)]
+    reverse: bool,
+
+    /// Only transform the flashcards with this tag, ignoring case
+    #[clap(long)]
+    tag: Option<String>,
+
+    /// Separate the PDF into sections titled by the first tag of the
+    /// flashcards, the flashcards without tags going under "General"
+    #[clap(long)]
+    group_by_tag: bool,
+
+    /// Show the note of each flashcard, e.g. an example sentence or the part
+    /// of speech, beneath its definition on the back
+    #[clap(long)]
+    back_notes: bool,
+
+    /// The separator between the word and the definition of a flashcard in
+    /// text outputs, where \t is a tab and \n a new line. Defaults to a comma
+    /// for csv and a tab for tsv.
+    #[clap(long, value_parser = parse_separator)]
+    front_back_sep: Option<String>,
+
+    /// The separator between the flashcards in text outputs
+    #[clap(long, default_value = "\\n", value_parser = parse_separator)]
+    card_sep: String,
+
+    /// Copy the generated Typst source of the PDF to this path before
+    /// compiling it, to inspect it when the PDF renders oddly
+    #[clap(long)]
+    keep_typst: Option<PathBuf>,
+
+    /// Split the PDF into files of this many pages of fronts, each followed
+    /// by the page of their backs, numbered e.g. flashcard-1.pdf. The files
+    /// are compiled concurrently.
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pages_per_file: Option<usize>,
+
+    #[clap(skip)]
+    stats: StatsRecorder,
 }
 
 const TYPST_FLASHCARD_TEMPLATE: &str =
     include_str!("../templates/flashcard.typ");
 
+/// The number of characters that fit in a card of a 6 by 3 page at the full
+/// font size when auto fitting
+const AUTO_FIT_LENGTH: f32 = 60.0;
+
+/// The smallest scale of the font size when auto fitting
+const AUTO_FIT_MIN_SCALE: f32 = 0.4;
+
 impl TransformPipeline {
+    /// Create a pipeline transforming flashcards into `output_type`. For
+    /// PDFs, `row` by `column` flashcards are laid out on each page with the
+    /// Typst length `fontsize`.
+    pub fn new(
+        name: Option<String>,
+        output_type: TransformOutputType,
+        row: usize,
+        column: usize,
+        fontsize: &str,
+    ) -> Self {
+        Self {
+            name,
+            output_type,
+            row,
+            column,
+            fontsize: fontsize.to_string(),
+            auto_fit: false,
+            reverse: false,
+            tag: None,
+            group_by_tag: false,
+            back_notes: false,
+            front_back_sep: None,
+            card_sep: "\n".to_string(),
+            keep_typst: None,
+            pages_per_file: None,
+            stats: StatsRecorder::default(),
+        }
+    }
+
+    /// Keep the flashcards with the tag to filter by, if any. Fail if no
+    /// flashcard is left, rather than writing an empty file.
+    fn filter_tag(
+        &self,
+        flashcard: &mut Vec<Flashcard>,
+    ) -> Result<(), PipelineError> {
+        if let Some(tag) = &self.tag {
+            flashcard.retain(|x| x.has_tag(tag));
+            info!(target: "transform", "Kept {} flashcards tagged {}", flashcard.len(), tag);
+            if flashcard.is_empty() {
+                return Err(PipelineError::new(&format!(
+                    "no flashcards tagged {} to transform",
+                    tag
+                )));
+            }
+        }
+        if flashcard.is_empty() {
+            return Err(PipelineError::new("no flashcards to transform"));
+        }
+        Ok(())
+    }
+
+    /// Check whether the output shows the notes of the flashcards
+    fn keeps_notes(&self) -> bool {
+        match self.output_type {
+            TransformOutputType::Yaml | TransformOutputType::Json => true,
+            TransformOutputType::Pdf => self.back_notes,
+            TransformOutputType::Xlsx
+            | TransformOutputType::Html
+            | TransformOutputType::Csv
+            | TransformOutputType::Tsv => false,
+        }
+    }
+
+    /// Split the flashcards into the decks of each PDF file
+    fn split_files(&self, flashcard: Vec<Flashcard>) -> Vec<Vec<Flashcard>> {
+        match self.pages_per_file {
+            Some(pages) => split_pages(
+                &flashcard,
+                self.row * self.column,
+                pages,
+                self.group_by_tag,
+            ),
+            None => vec![flashcard],
+        }
+    }
+
     fn run_pdf(
         &self,
-        flashcard: Vec<Flashcard>,
+        mut flashcard: Vec<Flashcard>,
     ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
-        let mut content = TYPST_FLASHCARD_TEMPLATE
-            .replace("<ROW>", self.row.to_string().as_str())
-            .replace("<COLUMN>", self.column.to_string().as_str())
-            .replace("<FONT_SIZE>", self.fontsize.as_str());
-
-        content.push_str(
-            flashcard
-                .chunks(self.row * self.column)
-                .map(|cards| {
-                    [
-                        "#card_layout(".to_string(),
-                        cards
-                            .iter()
-                            .map(|card| format!("front[{}]", card.word))
-                            .collect::<Vec<_>>()
-                            .join(",\n"),
-                        ")".to_string(),
-                        "#pagebreak()".to_string(),
-                        "#card_layout(".to_string(),
-                        cards
-                            .iter()
-                            .map(|card| format!("back[{}]", card.definition))
-                            .collect::<Vec<_>>()
-                            .join(",\n"),
-                        ")".to_string(),
-                    ]
-                    .join("\n")
-                })
-                .collect::<Vec<_>>()
-                .join("\n")
-                .as_str(),
+        if !self.back_notes {
+            flashcard.iter_mut().for_each(|x| x.note = None);
+        }
+        let name = self.name.clone().unwrap_or("flashcard.pdf".to_string());
+        let decks = self.split_files(flashcard);
+        if let [deck] = decks.as_slice() {
+            let content = self
+                .compile_pdf(deck, self.keep_typst.as_deref())
+                .map_err(|e| e as Box<dyn std::error::Error>)?;
+            return Ok(PipelineIO::Document { name, content });
+        }
+
+        // the files are compiled in batches of the number of cores, rather
+        // than starting a typst process for every file at once
+        let batch = std::thread::available_parallelism().map_or(1, |x| x.get());
+        let mut pdfs = Vec::with_capacity(decks.len());
+        for (i, decks) in decks.chunks(batch).enumerate() {
+            std::thread::scope(|scope| {
+                let handles = decks
+                    .iter()
+                    .enumerate()
+                    .map(|(j, deck)| {
+                        let number = i * batch + j + 1;
+                        let keep_typst = self
+                            .keep_typst
+                            .as_ref()
+                            .map(|x| numbered(x, number));
+                        let context = RunContext::current();
+                        scope.spawn(move || {
+                            RunContext::sync_scope(context, || {
+                                self.compile_pdf(deck, keep_typst.as_deref())
+                            })
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    let name = numbered(Path::new(&name), pdfs.len() + 1);
+                    let pdf = handle.join().expect("compiling a PDF panicked");
+                    pdfs.push(pdf.map(|x| (name.display().to_string(), x)));
+                }
+            });
+        }
+        let mut files = pdfs
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e as Box<dyn std::error::Error>)?;
+        info!(target: "transform", "Compiled {} PDF files", files.len());
+        let (name, content) = files.remove(0);
+        Ok(PipelineIO::Documents {
+            name,
+            content,
+            files,
+        })
+    }
+
+    /// Compile the flashcards into a PDF with typst, keeping the Typst
+    /// source in `keep_typst` if given
+    fn compile_pdf(
+        &self,
+        flashcard: &[Flashcard],
+        keep_typst: Option<&Path>,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let content = render_cards_typst(
+            flashcard,
+            self.row,
+            self.column,
+            &self.fontsize,
+            self.reverse,
+            self.auto_fit,
+            self.group_by_tag,
         );
 
-        let temp_dir = tempfile::tempdir()?;
-        let flashcard_file_path = temp_dir.path().join("flashcard.typ");
-        let mut flashcard_file = std::fs::File::create(&flashcard_file_path)?;
-        flashcard_file.write_all(content.as_bytes())?;
+        // the artifacts are uniquely named in their own directory, and typst
+        // is pointed at them by absolute paths, so that concurrent runs
+        // share nothing, not even the working directory
+        let temp_dir =
+            tempfile::Builder::new().prefix("transform-").tempdir()?;
+        let mut source = tempfile::Builder::new()
+            .prefix("flashcard-")
+            .suffix(".typ")
+            .tempfile_in(temp_dir.path())?;
+        source.write_all(content.as_bytes())?;
+        source.flush()?;
+        if let Some(path) = keep_typst {
+            std::fs::copy(source.path(), path)?;
+            info!(target: "transform", "Kept the Typst source in {}", path.display());
+        }
 
+        let pdf_path = source.path().with_extension("pdf");
         let output = std::process::Command::new("typst")
             .arg("compile")
-            .arg(flashcard_file_path)
-            .output()?;
+            .arg("--root")
+            .arg(temp_dir.path())
+            .arg(source.path())
+            .arg(&pdf_path)
+            .current_dir(temp_dir.path())
+            .output()
+            .map_err(|e| {
+                PipelineError::with_kind(
+                    PipelineErrorKind::Typst,
+                    &format!("failed to run typst: {}", e),
+                )
+            })?;
 
         if !output.status.success() {
-            return Err(Box::new(PipelineError::new(
+            return Err(Box::new(PipelineError::with_kind(
+                PipelineErrorKind::Typst,
                 "typst failed to compile",
             )));
         }
 
         let mut buf = Vec::new();
-        let mut pdf_file =
-            std::fs::File::open(temp_dir.path().join("flashcard.pdf"))?;
-        pdf_file.read_to_end(&mut buf)?;
+        std::fs::File::open(pdf_path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
 
-        let name = self.name.clone().unwrap_or("flashcard.pdf".to_string());
+    /// Write the flashcards into a two-column spreadsheet below a frozen
+    /// header row
+    fn run_xlsx(
+        &self,
+        flashcard: Vec<Flashcard>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let mut workbook = Workbook::new();
+        let worksheet = workbook.add_worksheet().set_name("Flashcards")?;
+        let header = Format::new().set_bold();
+        worksheet.write_string_with_format(0, 0, "Word", &header)?;
+        worksheet.write_string_with_format(0, 1, "Definition", &header)?;
+        for (i, card) in flashcard.iter().enumerate() {
+            let row = i as u32 + 1;
+            worksheet.write_string(row, 0, &card.word)?;
+            worksheet.write_string(row, 1, &card.definition)?;
+        }
+        worksheet.set_freeze_panes(1, 0)?;
+        worksheet.autofit();
+
+        let name = self.name.clone().unwrap_or("flashcard.xlsx".to_string());
 
-        Ok(PipelineIO::Document { name, content: buf })
+        Ok(PipelineIO::Document {
+            name,
+            content: workbook.save_to_buffer()?,
+        })
+    }
+
+    /// Join the flashcards into text with the separators. Fields containing
+    /// a separator or a double quote are quoted, doubling their quotes.
+    fn run_text(
+        &self,
+        flashcard: Vec<Flashcard>,
+        default_sep: &str,
+        default_name: &str,
+    ) -> Result<PipelineIO, PipelineError> {
+        let front_back_sep =
+            self.front_back_sep.as_deref().unwrap_or(default_sep);
+        let card_sep = self.card_sep.as_str();
+        if front_back_sep.contains(card_sep)
+            || card_sep.contains(front_back_sep)
+        {
+            return Err(PipelineError::new(&format!(
+                "the separators {:?} and {:?} cannot tell the flashcards apart",
+                front_back_sep, card_sep
+            )));
+        }
+        let quote = |field: &str| {
+            if field.contains(front_back_sep)
+                || field.contains(card_sep)
+                || field.contains('"')
+            {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        };
+        let mut content = flashcard
+            .iter()
+            .map(|card| {
+                format!(
+                    "{}{}{}",
+                    quote(&card.word),
+                    front_back_sep,
+                    quote(&card.definition)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(card_sep);
+        if !flashcard.is_empty() && card_sep.ends_with('\n') {
+            content.push_str(card_sep);
+        }
+        let name = self.name.clone().unwrap_or(default_name.to_string());
+
+        Ok(PipelineIO::Document {
+            name,
+            content: content.into_bytes(),
+        })
+    }
+
+    fn run_html(&self, flashcard: Vec<Flashcard>) -> PipelineIO {
+        let rows = flashcard
+            .iter()
+            .map(|card| {
+                format!(
+                    "<tr><td>{}</td><td>{}</td></tr>",
+                    html::escape(&card.word),
+                    html::escape(&card.definition)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let body = format!(
+            "<table>\n<thead><tr><th>Word</th><th>Definition</th></tr></thead>\n\
+             <tbody>\n{}\n</tbody>\n</table>",
+            rows
+        );
+        let name = self.name.clone().unwrap_or("flashcard.html".to_string());
+
+        PipelineIO::Document {
+            content: html::document("Flashcards", &body).into_bytes(),
+            name,
+        }
     }
 }
 
+/// Parse a separator of text outputs, where \t is a tab, \n a new line and
+/// \\ a backslash
+fn parse_separator(arg: &str) -> Result<String, String> {
+    let mut separator = String::with_capacity(arg.len());
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            separator.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => separator.push('\t'),
+            Some('n') => separator.push('\n'),
+            Some('\\') => separator.push('\\'),
+            other => {
+                return Err(format!(
+                    "unknown escape \\{} in separator",
+                    other.map(String::from).unwrap_or_default()
+                ))
+            }
+        }
+    }
+    if separator.is_empty() {
+        return Err("separator cannot be empty".to_string());
+    }
+    Ok(separator)
+}
+
+/// Escape the text so that it is taken literally in Typst markup
+fn escape_typst(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '#' | '[' | ']' | '*' | '_' | '`' | '$' | '<' | '>'
+            | '@' | '=' | '-' | '+' | '/' | '~' | '"' | '\'' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' | '\r' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 #[async_trait]
 impl Pipeline for TransformPipeline {
     async fn run(
         &self,
         input: Option<PipelineIO>,
     ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
-        let flashcards = match input {
+        let mut flashcards = match input {
             Some(PipelineIO::Flashcard(flashcard)) => flashcard,
             _ => {
                 return Err(Box::new(PipelineError::new(
@@ -118,6 +472,12 @@ impl Pipeline for TransformPipeline {
                 )))
             }
         };
+        self.filter_tag(&mut flashcards)?;
+        self.stats.record(|x| x.cards_built += flashcards.len());
+        let notes = flashcards.iter().filter(|x| x.note.is_some()).count();
+        if notes > 0 && !self.keeps_notes() {
+            warn!(target: "transform", "Left out the notes of {} flashcards, which only yaml, json and pdf with --back-notes keep", notes);
+        }
         match self.output_type {
             TransformOutputType::Yaml => {
                 let name =
@@ -136,10 +496,700 @@ impl Pipeline for TransformPipeline {
                 })
             }
             TransformOutputType::Pdf => self.run_pdf(flashcards),
+            TransformOutputType::Xlsx => self.run_xlsx(flashcards),
+            TransformOutputType::Html => Ok(self.run_html(flashcards)),
+            TransformOutputType::Csv => {
+                Ok(self.run_text(flashcards, ",", "flashcard.csv")?)
+            }
+            TransformOutputType::Tsv => {
+                Ok(self.run_text(flashcards, "\t", "flashcard.tsv")?)
+            }
         }
     }
 
+    async fn dry_run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let mut flashcards = match (self.output_type, input) {
+            (TransformOutputType::Pdf, Some(PipelineIO::Flashcard(x))) => x,
+            (_, input) => return self.run(input).await,
+        };
+        self.filter_tag(&mut flashcards)?;
+        let name = self.name.clone().unwrap_or("flashcard.pdf".to_string());
+        let pages = sections(&flashcards, self.group_by_tag)
+            .iter()
+            .map(|(_, cards)| cards.len().div_ceil(self.row * self.column))
+            .sum::<usize>();
+        info!(
+            target: "transform",
+            "Would compile {} flashcards into {} pages of {}x{} cards in {}",
+            flashcards.len(),
+            2 * pages,
+            self.row,
+            self.column,
+            name
+        );
+        if let Some(per_file) = self.pages_per_file {
+            info!(target: "transform", "Would split them into {} files of up to {} pages of fronts", pages.div_ceil(per_file), per_file);
+        }
+        if let Some(path) = &self.keep_typst {
+            info!(target: "transform", "Would keep the Typst source in {}", path.display());
+        }
+        Ok(PipelineIO::Document {
+            name,
+            content: vec![],
+        })
+    }
+
     fn name(&self) -> &'static str {
         "transform"
     }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(self.stats.get())
+    }
+}
+
+/// The title of the section of the flashcards without tags
+const DEFAULT_SECTION: &str = "General";
+
+/// Split the flashcards into sections in the order they first appear,
+/// titled by the first tag of the flashcards if `grouped`, ignoring case.
+/// Otherwise, the flashcards make a single untitled section.
+fn sections(
+    cards: &[Flashcard],
+    grouped: bool,
+) -> Vec<(Option<&str>, Vec<&Flashcard>)> {
+    if !grouped {
+        return vec![(None, cards.iter().collect())];
+    }
+    let mut sections: Vec<(Option<&str>, Vec<&Flashcard>)> = vec![];
+    for card in cards {
+        let title = card.tags.first().map_or(DEFAULT_SECTION, |x| x.trim());
+        let section = sections.iter_mut().find(|(x, _)| {
+            x.is_some_and(|x| x.to_lowercase() == title.to_lowercase())
+        });
+        match section {
+            Some((_, cards)) => cards.push(card),
+            None => sections.push((Some(title), vec![card])),
+        }
+    }
+    sections
+}
+
+/// Split the flashcards into the decks of files of up to `pages` pages of
+/// `per_page` flashcards each, breaking the pages as `render_cards_typst`
+/// does, so that each file lays them out the same
+fn split_pages(
+    cards: &[Flashcard],
+    per_page: usize,
+    pages: usize,
+    grouped: bool,
+) -> Vec<Vec<Flashcard>> {
+    let mut files: Vec<Vec<Flashcard>> = vec![];
+    let mut count = 0;
+    for (_, section) in sections(cards, grouped) {
+        for page in section.chunks(per_page.max(1)) {
+            let page = page.iter().map(|&x| x.clone());
+            match files.last_mut() {
+                Some(file) if count < pages => file.extend(page),
+                _ => {
+                    files.push(page.collect());
+                    count = 0;
+                }
+            }
+            count += 1;
+        }
+    }
+    files
+}
+
+/// Number the file at `path`, e.g. flashcard-2.pdf for flashcard.pdf
+fn numbered(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => {
+            format!("{}-{}.{}", stem, number, extension.to_string_lossy())
+        }
+        None => format!("{}-{}", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+/// Return the Typst source laying out `cards` on pages of `row` by `column`
+/// flashcards, each page of fronts followed by a page of their backs. With
+/// `reverse`, the backs of each row are mirrored for double-sided printing.
+/// With `grouped`, the cards are laid out in sections by their first tag,
+/// each page headed by the title of its section. The note of a card is
+/// shown beneath its definition.
+pub fn render_cards_typst(
+    cards: &[Flashcard],
+    row: usize,
+    column: usize,
+    fontsize: &str,
+    reverse: bool,
+    auto_fit: bool,
+    grouped: bool,
+) -> String {
+    let room = if auto_fit {
+        Some(AUTO_FIT_LENGTH * 18.0 / (row * column).max(1) as f32)
+    } else {
+        None
+    };
+    let mut content = TYPST_FLASHCARD_TEMPLATE
+        .replace("<ROW>", row.to_string().as_str())
+        .replace("<COLUMN>", column.to_string().as_str())
+        .replace("<FONT_SIZE>", fontsize);
+
+    let column = column.max(1);
+    let pages = sections(cards, grouped)
+        .into_iter()
+        .flat_map(|(title, cards)| {
+            let heading = title
+                .map(|x| format!("#section[{}]\n", escape_typst(x)))
+                .unwrap_or_default();
+            cards
+                .chunks((row * column).max(1))
+                .map(|cards| (heading.to_owned(), cards.to_vec()))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    content.push_str(
+        pages
+            .iter()
+            .map(|(heading, cards)| {
+                let fronts = cards
+                    .iter()
+                    .map(|card| typst_card("front", &card.word, None, room))
+                    .collect::<Vec<_>>();
+                let mut backs = cards
+                    .iter()
+                    .map(|card| {
+                        typst_card(
+                            "back",
+                            &card.definition,
+                            card.note.as_deref(),
+                            room,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                if reverse {
+                    // pad the last row so that its backs stay opposite of
+                    // their fronts
+                    backs.resize(
+                        cards.len().div_ceil(column) * column,
+                        "[]".to_string(),
+                    );
+                    backs.chunks_mut(column).for_each(|row| row.reverse());
+                }
+                // the backs are headed too, keeping them opposite of the
+                // fronts
+                [
+                    format!("{}#card_layout(", heading),
+                    fronts.join(",\n"),
+                    ")".to_string(),
+                    "#pagebreak()".to_string(),
+                    format!("{}#card_layout(", heading),
+                    backs.join(",\n"),
+                    ")".to_string(),
+                ]
+                .join("\n")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+            .as_str(),
+    );
+    content
+}
+
+/// Return the Typst call of the card `side` showing `text` and the `note`
+/// beneath it, shrinking its font to fit `room` characters when auto
+/// fitting
+fn typst_card(
+    side: &str,
+    text: &str,
+    note: Option<&str>,
+    room: Option<f32>,
+) -> String {
+    let length = match note {
+        Some(note) => format!("{} {}", text, note),
+        None => text.to_string(),
+    };
+    let scale = room.map_or(1.0, |room| font_scale(&length, room));
+    let mut args = vec![];
+    if let Some(note) = note {
+        args.push(format!("note: [{}]", escape_typst(note)));
+    }
+    if scale < 1.0 {
+        args.push(format!("scale: {:.2}", scale));
+    }
+    if args.is_empty() {
+        format!("{}[{}]", side, escape_typst(text))
+    } else {
+        format!("{}({})[{}]", side, args.join(", "), escape_typst(text))
+    }
+}
+
+/// Return the scale of the font size for `text` in a card with `room` for
+/// characters at the full size. The area of the text grows with the square
+/// of the font size.
+fn font_scale(text: &str, room: f32) -> f32 {
+    let length = text.chars().count() as f32;
+    (room / length).sqrt().clamp(AUTO_FIT_MIN_SCALE, 1.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escape_typst() {
+        assert_eq!(escape_typst("la casa"), "la casa");
+        assert_eq!(escape_typst("C#"), "C\\#");
+        assert_eq!(escape_typst("array[0]"), "array\\[0\\]");
+        assert_eq!(escape_typst("a\\b\nc"), "a\\\\b c");
+    }
+
+    fn cards(n: usize) -> Vec<Flashcard> {
+        (0..n)
+            .map(|i| Flashcard::new(&format!("w{}", i), &format!("d{}", i)))
+            .collect()
+    }
+
+    /// Return the cells of each `#card_layout` in the Typst source
+    fn layouts(source: &str) -> Vec<Vec<&str>> {
+        source
+            .split("#card_layout(\n")
+            .skip(1)
+            .map(|x| x.split("\n)").next().unwrap().split(",\n").collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_auto_fit() {
+        let cards = [Flashcard::new("la definición", &"a".repeat(200))];
+        let source =
+            render_cards_typst(&cards, 6, 3, "14pt", false, false, false);
+        assert!(source.contains(&format!("back[{}]", "a".repeat(200))));
+
+        let source =
+            render_cards_typst(&cards, 6, 3, "14pt", false, true, false);
+        assert!(source.contains("front[la definición]"));
+        assert!(
+            source.contains(&format!("back(scale: 0.55)[{}]", "a".repeat(200)))
+        );
+        assert_eq!(
+            font_scale(&"a".repeat(10_000), AUTO_FIT_LENGTH),
+            AUTO_FIT_MIN_SCALE
+        );
+
+        // the room for the text shrinks with smaller cards
+        let source =
+            render_cards_typst(&cards, 12, 3, "14pt", false, true, false);
+        assert!(source.contains("back(scale: 0.40)"));
+    }
+
+    #[test]
+    fn test_render_partial_chunk() {
+        let source =
+            render_cards_typst(&cards(5), 2, 2, "12pt", false, false, false);
+        assert!(source.starts_with("#let row = 2\n#let col = 2\n"));
+        assert!(source.contains("#let fontsize = 12pt"));
+        assert_eq!(
+            layouts(&source),
+            vec![
+                vec!["front[w0]", "front[w1]", "front[w2]", "front[w3]"],
+                vec!["back[d0]", "back[d1]", "back[d2]", "back[d3]"],
+                vec!["front[w4]"],
+                vec!["back[d4]"],
+            ]
+        );
+        assert_eq!(source.lines().filter(|x| *x == "#pagebreak()").count(), 2);
+
+        let source = render_cards_typst(&[], 2, 2, "12pt", false, false, false);
+        assert!(layouts(&source).is_empty());
+    }
+
+    #[test]
+    fn test_render_reverse() {
+        let source =
+            render_cards_typst(&cards(5), 2, 3, "12pt", true, false, false);
+        assert_eq!(
+            layouts(&source),
+            vec![
+                vec![
+                    "front[w0]",
+                    "front[w1]",
+                    "front[w2]",
+                    "front[w3]",
+                    "front[w4]"
+                ],
+                vec![
+                    "back[d2]", "back[d1]", "back[d0]", "[]", "back[d4]",
+                    "back[d3]"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_sections() {
+        let mut cards = cards(5);
+        cards[0].tags = vec!["Los verbos".to_string()];
+        cards[2].tags = vec!["los verbos ".to_string(), "unit 1".to_string()];
+        cards[3].tags = vec!["Los nombres".to_string()];
+        let source =
+            render_cards_typst(&cards, 2, 2, "12pt", false, false, true);
+        assert_eq!(
+            layouts(&source),
+            vec![
+                vec!["front[w0]", "front[w2]"],
+                vec!["back[d0]", "back[d2]"],
+                vec!["front[w1]", "front[w4]"],
+                vec!["back[d1]", "back[d4]"],
+                vec!["front[w3]"],
+                vec!["back[d3]"],
+            ]
+        );
+        let headings = source
+            .lines()
+            .filter(|x| x.starts_with("#section["))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headings,
+            [
+                "#section[Los verbos]",
+                "#section[Los verbos]",
+                "#section[General]",
+                "#section[General]",
+                "#section[Los nombres]",
+                "#section[Los nombres]",
+            ]
+        );
+
+        let source =
+            render_cards_typst(&cards, 2, 2, "12pt", false, false, false);
+        assert!(!source.contains("#section["));
+        assert_eq!(layouts(&source).len(), 4);
+    }
+
+    #[test]
+    fn test_keep_typst() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kept.typ");
+        let mut pipeline = TransformPipeline::new(
+            None,
+            TransformOutputType::Pdf,
+            2,
+            2,
+            "12pt",
+        );
+        pipeline.keep_typst = Some(path.clone());
+        // The source is kept before compiling, whether typst is installed
+        let _ = pipeline.run_pdf(cards(3));
+        let source = std::fs::read_to_string(path).unwrap();
+        assert!(source.contains("front[w2]"));
+    }
+
+    #[test]
+    fn test_concurrent_pdf() {
+        let dir = tempfile::tempdir().unwrap();
+        let pipeline = |i: usize| {
+            let mut pipeline = TransformPipeline::new(
+                None,
+                TransformOutputType::Pdf,
+                2,
+                2,
+                "12pt",
+            );
+            pipeline.keep_typst = Some(dir.path().join(format!("{}.typ", i)));
+            pipeline
+        };
+        let deck = |i: usize| {
+            let mut cards = cards(3);
+            cards.iter_mut().for_each(|x| x.word = format!("deck{}", i));
+            cards
+        };
+        let pipelines = [pipeline(0), pipeline(1)];
+        let pdfs = std::thread::scope(|scope| {
+            let handles = pipelines
+                .iter()
+                .enumerate()
+                .map(|(i, pipeline)| {
+                    scope.spawn(move || pipeline.run_pdf(deck(i)).ok())
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|x| x.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        // whether typst is installed, neither run sees the other's source
+        for i in 0..2 {
+            let source =
+                std::fs::read_to_string(dir.path().join(format!("{}.typ", i)))
+                    .unwrap();
+            assert!(source.contains(&format!("front[deck{}]", i)));
+            assert!(!source.contains(&format!("front[deck{}]", 1 - i)));
+        }
+        let typst = std::process::Command::new("typst")
+            .arg("--version")
+            .output();
+        if !typst.is_ok_and(|x| x.status.success()) {
+            eprintln!("typst is not installed, skipping the PDF comparison");
+            return;
+        }
+        // both compile, each to the PDF of its own deck
+        let pdfs = pdfs
+            .into_iter()
+            .map(|x| match x {
+                Some(PipelineIO::Document { content, .. }) => content,
+                _ => panic!("the deck did not compile to a PDF"),
+            })
+            .collect::<Vec<_>>();
+        assert!(pdfs.iter().all(|x| x.starts_with(b"%PDF")));
+        assert_ne!(pdfs[0], pdfs[1]);
+    }
+
+    #[tokio::test]
+    async fn test_xlsx() {
+        let pipeline = TransformPipeline::new(
+            None,
+            TransformOutputType::Xlsx,
+            6,
+            2,
+            "14pt",
+        );
+        let input =
+            PipelineIO::Flashcard(vec![Flashcard::new("la casa", "the house")]);
+        let PipelineIO::Document { name, content } =
+            pipeline.run(Some(input)).await.unwrap()
+        else {
+            panic!("should have made a document");
+        };
+        assert_eq!(name, "flashcard.xlsx");
+        // xlsx files are zip archives
+        assert!(content.starts_with(b"PK"));
+    }
+
+    #[tokio::test]
+    async fn test_filter_tag() {
+        let mut pipeline = TransformPipeline::new(
+            None,
+            TransformOutputType::Json,
+            6,
+            3,
+            "14pt",
+        );
+        pipeline.tag = Some("unit 1".to_string());
+        let card = |word: &str, tags: &[&str]| Flashcard {
+            word: word.to_string(),
+            definition: String::new(),
+            tags: tags.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        };
+        let input = PipelineIO::Flashcard(vec![
+            card("la casa", &["Unit 1"]),
+            card("el perro", &[]),
+            card("el gato", &["Unit 2", "Unit 1"]),
+            card("el pez", &["Unit 2"]),
+        ]);
+        let PipelineIO::Document { content, .. } =
+            pipeline.run(Some(input)).await.unwrap()
+        else {
+            panic!("should have made a document");
+        };
+        let cards: Vec<Flashcard> = serde_json::from_slice(&content).unwrap();
+        let words = cards.iter().map(|x| x.word.as_str()).collect::<Vec<_>>();
+        assert_eq!(words, ["la casa", "el gato"]);
+
+        pipeline.tag = Some("unit 3".to_string());
+        let input = PipelineIO::Flashcard(vec![card("la casa", &["Unit 1"])]);
+        let err = pipeline.run(Some(input)).await.unwrap_err();
+        assert_eq!(err.to_string(), "no flashcards tagged unit 3 to transform");
+    }
+
+    #[test]
+    fn test_split_pages() {
+        let words = |files: Vec<Vec<Flashcard>>| {
+            files
+                .into_iter()
+                .map(|x| x.into_iter().map(|x| x.word).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        let files = split_pages(&cards(7), 2, 2, false);
+        assert_eq!(
+            words(files),
+            [vec!["w0", "w1", "w2", "w3"], vec!["w4", "w5", "w6"]]
+        );
+        assert_eq!(split_pages(&cards(4), 2, 2, false).len(), 1);
+
+        // a section starts on a new page, which counts towards the file
+        let mut cards = cards(3);
+        cards[1].tags = vec!["unit 2".to_string()];
+        let files = split_pages(&cards, 2, 2, true);
+        assert_eq!(words(files), [vec!["w0", "w2", "w1"]]);
+        let files = split_pages(&cards, 2, 1, true);
+        assert_eq!(words(files), [vec!["w0", "w2"], vec!["w1"]]);
+    }
+
+    #[test]
+    fn test_numbered() {
+        assert_eq!(
+            numbered(Path::new("flashcard.pdf"), 2),
+            Path::new("flashcard-2.pdf")
+        );
+        assert_eq!(
+            numbered(Path::new("out/kept.typ"), 1),
+            Path::new("out/kept-1.typ")
+        );
+        assert_eq!(numbered(Path::new("deck"), 3), Path::new("deck-3"));
+    }
+
+    #[tokio::test]
+    async fn test_empty() {
+        let mut pipeline = TransformPipeline::new(
+            None,
+            TransformOutputType::Pdf,
+            6,
+            3,
+            "14pt",
+        );
+        let input = || Some(PipelineIO::Flashcard(vec![]));
+        let err = pipeline.run(input()).await.unwrap_err();
+        assert_eq!(err.to_string(), "no flashcards to transform");
+        assert!(pipeline.dry_run(input()).await.is_err());
+        pipeline.output_type = TransformOutputType::Csv;
+        assert!(pipeline.run(input()).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_separator() {
+        assert_eq!(parse_separator(" - ").unwrap(), " - ");
+        assert_eq!(parse_separator("\\t").unwrap(), "\t");
+        assert_eq!(parse_separator("\\n\\n").unwrap(), "\n\n");
+        assert_eq!(parse_separator("a\\\\b").unwrap(), "a\\b");
+        assert!(parse_separator("").is_err());
+        assert!(parse_separator("\\x").is_err());
+        assert!(parse_separator("\\").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_text() {
+        let text = |args: &[&str]| {
+            let pipeline =
+                TransformPipeline::parse_from(["transform"].iter().chain(args));
+            let input = PipelineIO::Flashcard(vec![
+                Flashcard::new("la casa", "the house, the home"),
+                Flashcard::new("\"hola\"", "hi"),
+            ]);
+            async move {
+                match pipeline.run(Some(input)).await {
+                    Ok(PipelineIO::Document { name, content }) => {
+                        Ok((name, String::from_utf8(content).unwrap()))
+                    }
+                    Ok(_) => panic!("should have made a document"),
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+        };
+
+        let (name, content) = text(&["-o", "csv"]).await.unwrap();
+        assert_eq!(name, "flashcard.csv");
+        assert_eq!(
+            content,
+            "la casa,\"the house, the home\"\n\"\"\"hola\"\"\",hi\n"
+        );
+        let (name, content) = text(&["-o", "tsv"]).await.unwrap();
+        assert_eq!(name, "flashcard.tsv");
+        assert_eq!(
+            content,
+            "la casa\tthe house, the home\n\"\"\"hola\"\"\"\thi\n"
+        );
+        let (_, content) =
+            text(&["-o", "tsv", "--front-back-sep", " - ", "--card-sep", ";"])
+                .await
+                .unwrap();
+        assert_eq!(
+            content,
+            "la casa - the house, the home;\"\"\"hola\"\"\" - hi"
+        );
+        assert!(text(&["-o", "csv", "--card-sep", ","])
+            .await
+            .unwrap_err()
+            .contains("separators"));
+    }
+
+    #[tokio::test]
+    async fn test_html() {
+        let pipeline = TransformPipeline::new(
+            None,
+            TransformOutputType::Html,
+            6,
+            3,
+            "14pt",
+        );
+        let input = PipelineIO::Flashcard(vec![Flashcard::new(
+            "<b>",
+            "1. bank\n2. bench",
+        )]);
+        let PipelineIO::Document { name, content } =
+            pipeline.run(Some(input)).await.unwrap()
+        else {
+            panic!("should have made a document");
+        };
+        assert_eq!(name, "flashcard.html");
+        let content = String::from_utf8(content).unwrap();
+        assert!(content.starts_with("<!DOCTYPE html>"));
+        assert!(content.contains(
+            "<tr><td>&lt;b&gt;</td><td>1. bank<br>2. bench</td></tr>"
+        ));
+    }
+
+    #[test]
+    fn test_keeps_notes() {
+        let keeps_notes = |args: &[&str]| {
+            let args = ["transform"].iter().chain(args);
+            TransformPipeline::parse_from(args).keeps_notes()
+        };
+        assert!(keeps_notes(&["-o", "yaml"]));
+        assert!(keeps_notes(&["--back-notes"]));
+        assert!(!keeps_notes(&[]));
+        assert!(!keeps_notes(&["-o", "csv", "--back-notes"]));
+    }
+
+    #[test]
+    fn test_back_notes() {
+        let mut cards = cards(2);
+        cards[1].note = Some("Nado [mucho].".to_string());
+        let source =
+            render_cards_typst(&cards, 1, 2, "12pt", false, false, false);
+        assert!(source.contains("back[d0]"));
+        assert!(source.contains("back(note: [Nado \\[mucho\\].])[d1]"));
+        assert_eq!(
+            typst_card("back", "d1", Some(&"a".repeat(200)), Some(60.0)),
+            format!("back(note: [{}], scale: 0.54)[d1]", "a".repeat(200))
+        );
+    }
+
+    #[test]
+    fn test_typst_source() {
+        let source = render_cards_typst(
+            &[
+                Flashcard::new("C#", "a language]#pagebreak()"),
+                Flashcard::new("array[0]", "the first element"),
+            ],
+            1,
+            2,
+            "14pt",
+            false,
+            false,
+            false,
+        );
+        assert!(source.contains("front[C\\#]"));
+        assert!(source.contains("front[array\\[0\\]]"));
+        assert!(source.contains("back[a language\\]\\#pagebreak()]"));
+        assert_eq!(source.lines().filter(|x| *x == "#pagebreak()").count(), 1);
+    }
 }
@@ -1,31 +1,146 @@
-use std::io::Cursor;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Cursor, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
-use clap::Parser;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{Parser, ValueEnum};
 use docx_rs::*;
-use image::{DynamicImage, GenericImageView};
-use log::{debug, error, info};
-use rand::random;
+use futures::{stream::FuturesOrdered, StreamExt};
+use image::{
+    imageops::FilterType, DynamicImage, GenericImageView, GrayImage, Luma,
+};
+use log::{debug, error, info, warn};
+use qrcode::{Color, QrCode};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use rust_bert::pipelines::sentence_embeddings::{
     builder::SentenceEmbeddingsBuilder, SentenceEmbeddingsModel,
     SentenceEmbeddingsModelType,
 };
-use tokio::{
-    sync::{Mutex, OnceCell},
-    task,
-};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::{Mutex, OnceCell};
 
-use super::{Flashcard, Pipeline, PipelineError, PipelineIO};
+use super::{
+    html, Flashcard, Interrupt, Pipeline, PipelineError, PipelineErrorKind,
+    PipelineIO, RunContext, SearchAttempts, Stats, StatsRecorder,
+};
 use crate::{
     error::CliError,
     spider::{
-        google_image::image_search_max,
-        spanish_dict::{search_vocab, DictionaryDefinition, DictionaryExample},
+        google_image::{image_search_max, GoogleImage, Image},
+        spanish_dict::{
+            search_vocab, spanish_dict_url, DictionaryDefinition,
+            DictionaryExample, SearchStrategy,
+        },
+        SpiderError, SpiderErrorKind,
     },
 };
 
+/// Represents the supported paper sizes
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum PaperSize {
+    A4,
+    Letter,
+    Legal,
+}
+
+impl PaperSize {
+    /// Return the (width, height) of the paper in portrait, in emu
+    fn size(self) -> (u32, u32) {
+        match self {
+            PaperSize::A4 => (super::docx::cm(21.0), super::docx::cm(29.7)),
+            PaperSize::Letter => {
+                (super::docx::inch(8.5), super::docx::inch(11.0))
+            }
+            PaperSize::Legal => {
+                (super::docx::inch(8.5), super::docx::inch(14.0))
+            }
+        }
+    }
+}
+
+/// Represents the orientation of the paper
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+/// Represents how images are sized to the cells of the sheet
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Crop {
+    /// Shrink the whole image into the cell
+    Fit,
+    /// Crop the center of the image to the shape of the cell
+    Fill,
+}
+
+/// Represents the filter resizing the images to the cells, from the fastest
+/// to the sharpest
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ResizeFilter {
+    /// Nearest neighbor
+    Nearest,
+    /// Linear
+    Triangle,
+    /// Cubic
+    Catmull,
+    /// Lanczos with a window of 3
+    Lanczos,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::Catmull => FilterType::CatmullRom,
+            ResizeFilter::Lanczos => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Represents how the image of a card is picked from the pool of images
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ImageStrategy {
+    /// Pick a random image
+    Random,
+    /// Pick the largest image within the maximum image size
+    BestByResolution,
+}
+
+/// Represents which size of each image found is downloaded
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ImageQuality {
+    /// The full size image
+    Full,
+    /// The thumbnail shown in the search results
+    Thumb,
+    /// The full size image, or the thumbnail if it fails
+    Auto,
+}
+
+/// Represents the file types that the sheet can be saved as
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum VisualOutputType {
+    /// A Word document to print
+    Docx,
+    /// A self-contained web page with the images embedded
+    Html,
+}
+
+/// The instructions at the top of the sheet
+const DEFAULT_INSTRUCTIONS: &str = "\
+Escoge <COUNT> palabras del vocabulario de esta unidad.
+Escribe la palabra de vocabulario y una frase completa con la palabra. \
+Dibuja una foto que representa la palabra.";
+
 /// A pipeline for making visual vocab
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 pub struct VisualVocabPipeline {
     /// The number of rows
     #[clap(short, long, default_value = "3")]
@@ -33,13 +148,466 @@ pub struct VisualVocabPipeline {
     /// The number of columns
     #[clap(short, long, default_value = "6")]
     col: u32,
+    /// The number of words on the sheet, laid out in rows of `col` words.
+    /// Defaults to filling the `row` by `col` grid.
+    #[clap(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+    total: Option<u32>,
+    /// The instructions at the top of the sheet, where <COUNT> is replaced
+    /// by the number of words and each line is broken in the document
+    #[clap(long)]
+    instructions: Option<String>,
+    /// List where each image came from at the end of the sheet
+    #[clap(long)]
+    attributions: bool,
     /// The name of the output file
     #[clap(short, long, default_value = "visual_vocab.docx")]
     filename: String,
+    /// The type of the output file, whose extension replaces the one of the
+    /// file name
+    #[clap(long, default_value = "docx")]
+    output_type: VisualOutputType,
     /// The name of the student
     name: String,
     /// The period of the student
     period: String,
+    /// Skip the machine learning models, picking the first example found
+    #[clap(long)]
+    no_ml: bool,
+    /// Do not retry unknown words with their keywords, which loads the
+    /// keyword model, while still ranking the examples
+    #[clap(long)]
+    no_keywords: bool,
+    /// Retry unknown words with their dictionary forms, e.g. "correr" for
+    /// "corriendo"
+    #[clap(long)]
+    lemmatize: bool,
+    /// Only look up the words in the small dictionary bundled for working
+    /// without network, e.g. for demos. The images still need the network
+    /// unless they are overridden with local files.
+    #[clap(long)]
+    offline: bool,
+    #[clap(flatten)]
+    search_attempts: SearchAttempts,
+    /// The number of example sentences on each card, best ranked first.
+    /// Defaults to 1, or to one for every part of speech with
+    /// --example-per-group.
+    #[clap(
+        short,
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    examples: Option<usize>,
+    /// The similarity, between -1 and 1, that an example must exceed to be
+    /// put on a card. A card without such an example is left for the
+    /// student to fill in.
+    #[clap(long, default_value = "0.0", value_parser = parse_threshold)]
+    example_threshold: f32,
+    /// Pick the best ranked example of each part of speech of the word,
+    /// labelled with it, so that e.g. a verb and a noun sense both get a
+    /// line. The number of examples can be capped by --examples.
+    #[clap(long)]
+    example_per_group: bool,
+    /// Log the ranking of the examples of each word with their similarity
+    /// at debug level, e.g. with --log visual_vocab=debug, to see why an
+    /// example was picked
+    #[clap(long)]
+    explain: bool,
+    /// Show the English translation of each example beneath it
+    #[clap(long)]
+    translations: bool,
+    /// Only pick from the flashcards with this tag, ignoring case
+    #[clap(long)]
+    tag: Option<String>,
+    /// The seed of the random pick of words, for a reproducible sheet
+    #[clap(long)]
+    seed: Option<u64>,
+    /// Take the first words in the order of the flashcards instead of
+    /// picking them at random, e.g. for a curated list
+    #[clap(long, conflicts_with = "seed")]
+    no_shuffle: bool,
+    /// The number of images to pick a random image from for each word
+    #[clap(
+        long,
+        default_value = "10",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    image_pool: u32,
+    /// Terms appended to each word when searching for its images, e.g.
+    /// "clip art" or "illustration" for abstract words
+    #[clap(long, default_value = "")]
+    image_query_suffix: String,
+    /// How the image of each word is picked from the pool
+    #[clap(long, default_value = "random")]
+    image_strategy: ImageStrategy,
+    /// The largest width or height of an image picked by resolution, larger
+    /// images are only picked if no other image can be downloaded
+    #[clap(
+        long,
+        default_value = "4000",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    max_image_size: u32,
+    /// The largest download of an image in bytes, larger images are skipped
+    #[clap(
+        long,
+        default_value = "5242880",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    max_image_bytes: u64,
+    /// Which size of each image is downloaded
+    #[clap(long, default_value = "auto")]
+    image_quality: ImageQuality,
+    /// A YAML file mapping words to the URLs or the local paths of their
+    /// images, which are used instead of searching for the images of those
+    /// words
+    #[clap(long, value_parser = parse_image_overrides)]
+    image_overrides: Option<HashMap<String, String>>,
+    /// A directory to write the image chosen for each word to, as
+    /// `<word>.png`, as the cards are built. A word given twice is written
+    /// to `<word> (2).png`.
+    #[clap(long)]
+    export_images: Option<PathBuf>,
+    /// The number of attempts at building each card, searching its images
+    /// and definition again after a failure
+    #[clap(
+        long,
+        default_value = "2",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    attempts: u32,
+    /// The size of the paper
+    #[clap(long, default_value = "a4")]
+    paper: PaperSize,
+    /// The orientation of the paper
+    #[clap(long, default_value = "portrait")]
+    orientation: Orientation,
+    /// How the images are sized to the cells
+    #[clap(long, default_value = "fit")]
+    crop: Crop,
+    /// The filter resizing the images, trading their quality for speed on
+    /// big sheets
+    #[clap(long, default_value = "lanczos")]
+    resize_filter: ResizeFilter,
+    /// Convert the images to grayscale for printing
+    #[clap(long)]
+    grayscale: bool,
+    /// The space between the content and the edges of each cell, in cm
+    #[clap(long, value_parser = parse_cell_margin)]
+    cell_margin: Option<f32>,
+    /// Remove the borders of the tables
+    #[clap(long)]
+    borderless: bool,
+    /// The font of the text of the sheet, e.g. "Comic Sans MS". Defaults to
+    /// the font of the document.
+    #[clap(long)]
+    font_family: Option<String>,
+    /// The size of the text of the sheet in points, between 6 and 72.
+    /// Defaults to the size of the document.
+    #[clap(long, value_parser = parse_font_size)]
+    font_size: Option<f32>,
+    /// Put a QR code linking to the SpanishDict page of each word beneath
+    /// it, for students to hear it and see more examples
+    #[clap(long)]
+    qr: bool,
+    /// Save the progress of the sheet until it is created, and continue the
+    /// sheet of a run that failed, keeping its words and the cards it
+    /// gathered. The progress is saved in the output directory as the file
+    /// name with the extension .checkpoint.jsonl, unless --checkpoint is
+    /// given.
+    #[clap(long)]
+    resume: bool,
+    /// Save the progress of the sheet to this file until it is created,
+    /// continuing from it with --resume
+    #[clap(long)]
+    checkpoint: Option<PathBuf>,
+    /// The directory the outputs are dumped to
+    #[clap(skip)]
+    out_dir: Option<PathBuf>,
+    #[clap(skip)]
+    stats: StatsRecorder,
+    #[clap(skip)]
+    interrupt: Interrupt,
+}
+
+impl VisualVocabPipeline {
+    /// Create a pipeline making a `row` by `col` visual vocab sheet for the
+    /// student `name` in `period`, saved as `filename`
+    pub fn new(
+        row: u32,
+        col: u32,
+        name: &str,
+        period: &str,
+        filename: &str,
+    ) -> Self {
+        Self {
+            row,
+            col,
+            total: None,
+            instructions: None,
+            attributions: false,
+            filename: filename.to_string(),
+            output_type: VisualOutputType::Docx,
+            name: name.to_string(),
+            period: period.to_string(),
+            no_ml: false,
+            no_keywords: false,
+            lemmatize: false,
+            offline: false,
+            search_attempts: SearchAttempts::default(),
+            examples: None,
+            example_threshold: 0.0,
+            example_per_group: false,
+            explain: false,
+            translations: false,
+            tag: None,
+            seed: None,
+            no_shuffle: false,
+            image_pool: 10,
+            image_query_suffix: String::new(),
+            image_strategy: ImageStrategy::Random,
+            max_image_size: 4000,
+            max_image_bytes: 5 * 1024 * 1024,
+            image_quality: ImageQuality::Auto,
+            image_overrides: None,
+            export_images: None,
+            attempts: 2,
+            paper: PaperSize::A4,
+            orientation: Orientation::Portrait,
+            crop: Crop::Fit,
+            resize_filter: ResizeFilter::Lanczos,
+            grayscale: false,
+            cell_margin: None,
+            borderless: false,
+            font_family: None,
+            font_size: None,
+            qr: false,
+            resume: false,
+            checkpoint: None,
+            out_dir: None,
+            stats: StatsRecorder::default(),
+            interrupt: Interrupt::default(),
+        }
+    }
+
+    /// Return the (width, height) of the page in emu
+    fn page_size(&self) -> (u32, u32) {
+        let (width, height) = self.paper.size();
+        match self.orientation {
+            Orientation::Portrait => (width, height),
+            Orientation::Landscape => (height, width),
+        }
+    }
+
+    /// Return the number of words on the sheet
+    fn total(&self) -> Result<u32, CliError> {
+        match self.total {
+            Some(total) if total > self.row * self.col => {
+                Err(CliError::new(&format!(
+                    "a total of {} words does not fit in {} rows of {}",
+                    total, self.row, self.col
+                )))
+            }
+            Some(total) => Ok(total),
+            None => Ok(self.row * self.col),
+        }
+    }
+
+    /// Return the instructions for a sheet of `count` words
+    fn instructions(&self, count: usize) -> String {
+        self.instructions
+            .as_deref()
+            .unwrap_or(DEFAULT_INSTRUCTIONS)
+            .replace("<COUNT>", &count.to_string())
+    }
+
+    /// Return the name of the output file, with the extension of the
+    /// output type
+    fn output_filename(&self) -> String {
+        match self.output_type {
+            VisualOutputType::Docx => self.filename.to_owned(),
+            VisualOutputType::Html => Path::new(&self.filename)
+                .with_extension("html")
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    /// Return the maximum number of examples on each card
+    fn example_limit(&self) -> usize {
+        match self.examples {
+            Some(examples) => examples,
+            None if self.example_per_group => usize::MAX,
+            None => 1,
+        }
+    }
+
+    /// Return the path of the file saving the progress of the sheet, if the
+    /// progress is saved
+    fn checkpoint_path(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.checkpoint {
+            return Some(path.to_owned());
+        }
+        let name = Path::new(&self.output_filename())
+            .with_extension("checkpoint.jsonl");
+        self.resume.then(|| match &self.out_dir {
+            Some(dir) => dir.join(name),
+            None => name,
+        })
+    }
+
+    /// Return the sheet as a web page of cards laid out in a grid that
+    /// follows the width of the screen
+    fn to_html(
+        &self,
+        vocabs: &[VisualFlashCard],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let mut body = format!(
+            "<header>\n<p>Nombre: {}</p>\n<p>Hora: {}</p>\n<p>{}</p>\n</header>\n\
+             <div class=\"grid\">\n",
+            html::escape(&self.name),
+            html::escape(&self.period),
+            html::escape(&self.instructions(vocabs.len())),
+        );
+        for vocab in vocabs {
+            body.push_str("<div class=\"card\">\n");
+            body.push_str(&format!(
+                "<h2>Vocabulario: {}</h2>\n",
+                html::escape(&vocab.word)
+            ));
+            for (line, italic) in vocab.example_lines(self.translations) {
+                let class = if italic { " class=\"translation\"" } else { "" };
+                body.push_str(&format!(
+                    "<p{}>{}</p>\n",
+                    class,
+                    html::escape(&line)
+                ));
+            }
+            // failed cards are left without an image
+            if vocab.source.is_some() {
+                let image =
+                    vocab.image.thumbnail(HTML_IMAGE_SIZE, HTML_IMAGE_SIZE);
+                let image = if self.grayscale {
+                    image.grayscale()
+                } else {
+                    image
+                };
+                body.push_str(&format!(
+                    "<img src=\"{}\" alt=\"{}\">\n",
+                    html::png_data_uri(&encode_png(&image)?),
+                    html::escape(&vocab.word)
+                ));
+            }
+            body.push_str("</div>\n");
+        }
+        body.push_str("</div>");
+
+        if self.attributions {
+            body.push_str("\n<h2>Fuentes de las imágenes:</h2>\n<ul>\n");
+            for vocab in vocabs {
+                if let Some(source) = &vocab.source {
+                    body.push_str(&format!(
+                        "<li>{} → {}</li>\n",
+                        html::escape(&vocab.word),
+                        html::escape(&source.to_string())
+                    ));
+                }
+            }
+            body.push_str("</ul>");
+        }
+        Ok(html::document("Visual vocab", &body))
+    }
+
+    /// Return the run of the instructions for a sheet of `count` words
+    fn instructions_run(&self, count: usize) -> Run {
+        let instructions = self.instructions(count);
+        let mut run = self.style(Run::new());
+        for (i, line) in instructions.lines().enumerate() {
+            if i > 0 {
+                run = run.add_break(BreakType::TextWrapping);
+            }
+            run = run.add_text(line);
+        }
+        run
+    }
+
+    /// Return the image search query of a word
+    fn image_query(&self, word: &str) -> String {
+        match self.image_query_suffix.trim() {
+            "" => word.to_string(),
+            suffix => format!("{} {}", word, suffix),
+        }
+    }
+
+    /// Set the font and the font size of the sheet on a run of text
+    fn style(&self, run: Run) -> Run {
+        let run = match &self.font_family {
+            Some(font) => run.fonts(
+                RunFonts::new()
+                    .ascii(font)
+                    .hi_ansi(font)
+                    .east_asia(font)
+                    .cs(font),
+            ),
+            None => run,
+        };
+        match self.font_size {
+            Some(size) => run.size(super::docx::half_points(size)),
+            None => run,
+        }
+    }
+
+    /// Pick distinct words of the sheet at random from the flashcards, or
+    /// the first ones without shuffling, or all of them if there are not
+    /// enough
+    fn pick_words(
+        &self,
+        flashcard: &[Flashcard],
+    ) -> Result<Vec<Flashcard>, CliError> {
+        let total = self.total()? as usize;
+        let mut words = flashcard
+            .iter()
+            .filter(|x| self.tag.as_ref().is_none_or(|tag| x.has_tag(tag)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if words.len() < total {
+            warn!(target: "visual_vocab", "Only {} words for a sheet of {}, using all of them", words.len(), total);
+        }
+        if self.no_shuffle {
+            words.truncate(total);
+            return Ok(words);
+        }
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let (picked, _) = words.partial_shuffle(&mut rng, total);
+        Ok(picked.to_vec())
+    }
+}
+
+/// Represents where the image of a visual flashcard came from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageSource {
+    pub title: String,
+    pub url: String,
+}
+
+impl std::fmt::Display for ImageSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.url, self.title)
+    }
+}
+
+/// Represents an example sentence on a visual flashcard
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VisualExample {
+    pub example: String,
+    /// The English translation, if the dictionary has one
+    pub translation: Option<String>,
+    /// The part of speech the example is of, shown when the examples are
+    /// picked per part of speech
+    #[serde(default)]
+    pub group: Option<String>,
 }
 
 /// A representation of the results created by VisualVocabPipeline
@@ -48,7 +616,8 @@ pub struct VisualFlashCard {
     pub word: String,
     pub definition: String,
     pub image: DynamicImage,
-    pub example: String,
+    pub examples: Vec<VisualExample>,
+    pub source: Option<ImageSource>,
 }
 
 impl std::fmt::Display for VisualFlashCard {
@@ -58,7 +627,11 @@ impl std::fmt::Display for VisualFlashCard {
             "{} - {} ({}, {} bytes)",
             self.word,
             self.definition,
-            self.example,
+            self.examples
+                .iter()
+                .map(|x| x.example.as_str())
+                .collect::<Vec<_>>()
+                .join("; "),
             self.image.dimensions().0 * self.image.dimensions().1 * 3
         )
     }
@@ -76,35 +649,59 @@ impl VisualFlashCard {
     /// |-------------------------|-------------------------|-------------------------|
     /// ```
     ///
-    /// Size should be specified as (width, height) in emu
+    /// Size should be specified as (width, height) in emu. The images are
+    /// shrunk to fit within the cell margins of `config`.
     async fn to_table(
         vocabs: Vec<VisualFlashCard>,
         size: (u32, u32),
+        config: &VisualVocabPipeline,
     ) -> Result<Table, Box<dyn std::error::Error>> {
+        let VisualVocabPipeline {
+            crop,
+            resize_filter,
+            grayscale,
+            translations,
+            cell_margin,
+            borderless,
+            qr,
+            ..
+        } = *config;
         info!(target: "visual_vocab", "Creating table for {} vocabs with size {:?}", vocabs.len(), size);
         let mut images = Vec::new();
+        let margin_emu = cell_margin.map_or(0, super::docx::cm);
 
         for vocab in &vocabs {
-            let (t_w_emu, t_h_emu) =
-                (size.0 / vocabs.len() as u32, size.1 - super::docx::cm(0.5));
-            let (w_emu, h_emu) = Pic::new(&vocab.get_image_buf()?).size;
+            let (t_w_emu, t_h_emu) = (
+                (size.0 / vocabs.len() as u32).saturating_sub(2 * margin_emu),
+                (size.1 - super::docx::cm(0.5)).saturating_sub(2 * margin_emu),
+            );
+            let image = match crop {
+                Crop::Fit => vocab.image.clone(),
+                Crop::Fill => crop_to_aspect(
+                    &vocab.image,
+                    t_w_emu as f32 / t_h_emu as f32,
+                ),
+            };
+            let (w_emu, h_emu) = Pic::new(&encode_png(&image)?).size;
             let ratio = f32::min(
                 t_w_emu as f32 / w_emu as f32,
                 t_h_emu as f32 / h_emu as f32,
             );
             let (f_w_emu, f_h_emu) =
                 ((w_emu as f32 * ratio) as u32, (h_emu as f32 * ratio) as u32);
-            let (w_px, h_px) = vocab.image.dimensions();
+            let (w_px, h_px) = image.dimensions();
             let (f_w_px, f_h_px) =
-                ((h_px as f32 * ratio) as u32, (w_px as f32 * ratio) as u32);
+                ((w_px as f32 * ratio) as u32, (h_px as f32 * ratio) as u32);
 
             info!(target: "visual_vocab", "Resizing image from {}x{} to {}x{}", w_px, h_px, f_w_px, f_h_px);
             let mut buffer = Cursor::new(Vec::new());
-            let resized = vocab.image.resize_exact(
-                f_w_px,
-                f_h_px,
-                image::imageops::FilterType::Lanczos3,
-            );
+            let resized =
+                image.resize_exact(f_w_px, f_h_px, resize_filter.into());
+            let resized = if grayscale {
+                resized.grayscale()
+            } else {
+                resized
+            };
             resized.write_to(&mut buffer, image::ImageOutputFormat::Png)?;
 
             info!(target: "visual_vocab", "Adding image ({}, {})", f_w_emu, f_h_emu);
@@ -115,10 +712,13 @@ impl VisualFlashCard {
             ))
         }
 
-        let cellify = |x: String| {
-            let mut cell = TableCell::new().add_paragraph(
-                Paragraph::new().add_run(Run::new().add_text(x)),
-            );
+        let cellify = |lines: Vec<(String, bool)>| {
+            let mut cell = TableCell::new();
+            for (line, italic) in lines {
+                let run = config.style(Run::new().add_text(line));
+                let run = if italic { run.italic() } else { run };
+                cell = cell.add_paragraph(Paragraph::new().add_run(run));
+            }
             cell.property = cell.property.width(
                 size.0 as usize / vocabs.len() / 12_700 * 12,
                 WidthType::Dxa,
@@ -126,21 +726,73 @@ impl VisualFlashCard {
             cell
         };
 
-        Ok(Table::new(vec![
-            TableRow::new(
-                vocabs
-                    .iter()
-                    .map(|x| cellify(format!("Vocabulario: {}", x.word)))
-                    .collect(),
-            ),
+        let mut words = Vec::new();
+        for vocab in &vocabs {
+            let mut cell =
+                cellify(vec![(format!("Vocabulario: {}", vocab.word), false)]);
+            // failed cards have no word to link to
+            if qr && !vocab.word.is_empty() {
+                let size = super::docx::cm(QR_SIZE_CM);
+                cell = cell.add_paragraph(
+                    Paragraph::new().add_run(
+                        Run::new().add_image(
+                            Pic::new(&qr_png(&spanish_dict_url(&vocab.word))?)
+                                .size(size, size),
+                        ),
+                    ),
+                );
+            }
+            words.push(cell);
+        }
+
+        let mut table = Table::new(vec![
+            TableRow::new(words),
             TableRow::new(
                 vocabs
                     .iter()
-                    .map(|x| cellify(format!("Frase Completa: {}", x.example)))
+                    .map(|x| cellify(x.example_lines(translations)))
                     .collect(),
             ),
             TableRow::new(images),
-        ]))
+        ]);
+        if let Some(cell_margin) = cell_margin {
+            let margin =
+                super::docx::twip(super::docx::cm(cell_margin)) as usize;
+            table = table.margins(
+                TableCellMargins::new().margin(margin, margin, margin, margin),
+            );
+        }
+        if borderless {
+            table = table.set_borders(TableBorders::with_empty());
+        }
+        Ok(table)
+    }
+
+    /// Return the lines of the examples cell as (text, italic), listing the
+    /// examples when there are several, each followed by its translation if
+    /// `translations` is set
+    fn example_lines(&self, translations: bool) -> Vec<(String, bool)> {
+        let (mut lines, prefix) = match self.examples.as_slice() {
+            [] => return vec![("Frase Completa: ".to_string(), false)],
+            [_] => (vec![], "Frase Completa: "),
+            _ => (vec![("Frases Completas:".to_string(), false)], "• "),
+        };
+        for example in &self.examples {
+            let text = match &example.group {
+                Some(group) => {
+                    format!("{}({}) {}", prefix, group, example.example)
+                }
+                None => format!("{}{}", prefix, example.example),
+            };
+            lines.push((text, false));
+            match &example.translation {
+                Some(translation) if translations => {
+                    lines.push((translation.to_owned(), true));
+                }
+                _ => {}
+            }
+        }
+        lines
     }
 
     fn default() -> Self {
@@ -148,19 +800,84 @@ impl VisualFlashCard {
             word: String::new(),
             definition: String::new(),
             image: DynamicImage::new_rgb8(1, 1),
-            example: String::new(),
+            examples: vec![],
+            source: None,
         }
     }
 
-    fn get_image_buf(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let mut buf = Cursor::new(Vec::new());
-        self.image
-            .write_to(&mut buf, image::ImageOutputFormat::Png)?;
-        Ok(buf.into_inner())
+    /// Return the paragraphs listing the source of the image of each
+    /// visual flashcard in the font of `config`
+    fn attributions(
+        vocabs: &[VisualFlashCard],
+        config: &VisualVocabPipeline,
+    ) -> Vec<Paragraph> {
+        let mut paragraphs = vec![Paragraph::new().add_run(
+            config
+                .style(Run::new())
+                .add_text("Fuentes de las imágenes:")
+                .bold(),
+        )];
+        for vocab in vocabs {
+            if let Some(source) = &vocab.source {
+                paragraphs.push(
+                    Paragraph::new().add_run(
+                        config
+                            .style(Run::new())
+                            .add_text(format!("{} → {}", vocab.word, source)),
+                    ),
+                );
+            }
+        }
+        paragraphs
     }
 }
 
-const IMAGE_RANDOM_POOL_SIZE: u32 = 10;
+/// The width and height of the QR codes, in cm
+const QR_SIZE_CM: f32 = 1.5;
+
+/// The number of pixels of each module of the QR codes
+const QR_MODULE_PX: u32 = 4;
+
+/// The width of the blank border around the QR codes, in modules
+const QR_QUIET_ZONE: u32 = 4;
+
+/// Encode `data` as the PNG of a QR code
+fn qr_png(data: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let code = QrCode::new(data.as_bytes())?;
+    let width = code.width() as u32;
+    let colors = code.to_colors();
+    let size = (width + 2 * QR_QUIET_ZONE) * QR_MODULE_PX;
+    let image = GrayImage::from_fn(size, size, |x, y| {
+        let (x, y) = (x / QR_MODULE_PX, y / QR_MODULE_PX);
+        let dark = (QR_QUIET_ZONE..QR_QUIET_ZONE + width).contains(&x)
+            && (QR_QUIET_ZONE..QR_QUIET_ZONE + width).contains(&y)
+            && colors
+                [((y - QR_QUIET_ZONE) * width + x - QR_QUIET_ZONE) as usize]
+                == Color::Dark;
+        Luma([if dark { 0 } else { 255 }])
+    });
+    encode_png(&DynamicImage::ImageLuma8(image))
+}
+
+/// Encode the image as PNG
+fn encode_png(
+    image: &DynamicImage,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut buf = Cursor::new(Vec::new());
+    image.write_to(&mut buf, image::ImageOutputFormat::Png)?;
+    Ok(buf.into_inner())
+}
+
+/// The largest width or height of the images embedded in a web page, in
+/// pixels
+const HTML_IMAGE_SIZE: u32 = 800;
+
+/// The image pool size above which the image searches get slow
+const IMAGE_POOL_WARN_SIZE: u32 = 50;
+
+/// The number of images of a word downloaded at once while looking for one
+/// that decodes
+const IMAGE_DOWNLOADS: usize = 3;
 
 #[async_trait]
 impl Pipeline for VisualVocabPipeline {
@@ -169,34 +886,77 @@ impl Pipeline for VisualVocabPipeline {
         input: Option<PipelineIO>,
     ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
         let VisualVocabPipeline {
-            row,
             col,
             name,
             period,
             filename,
+            ..
         } = self;
 
         let col = *col;
-        let row = *row;
+        if self.image_pool > IMAGE_POOL_WARN_SIZE {
+            warn!(target: "visual_vocab", "Image pool of {} is large, searching for images will be slow", self.image_pool);
+        }
         let flashcard = match input {
             Some(PipelineIO::Flashcard(vocab)) => vocab,
             _ => return Err(CliError::new("No flashcard input").into()),
         };
 
-        // pick random words
-        let mut words = flashcard.clone();
-        let mut result: Vec<Flashcard> = vec![];
-        for _ in 0..row * col {
-            let word = words.remove(random::<usize>() % words.len());
-            result.push(word);
+        // pick random words, or the words of the run resumed
+        let path = self.checkpoint_path();
+        let (checkpoint, result, gathered) = match &path {
+            Some(path) if self.resume && path.exists() => {
+                let (words, gathered) = Checkpoint::read(path)?;
+                info!(target: "visual_vocab", "Resuming {}, {} of {} cards already gathered", path.display(), gathered.len(), words.len());
+                (Some(Checkpoint::open(path)?), words, gathered)
+            }
+            _ => {
+                if let (Some(path), true) = (&path, self.resume) {
+                    info!(target: "visual_vocab", "No progress saved in {}, starting over", path.display());
+                }
+                let words = self.pick_words(&flashcard)?;
+                info!(target: "visual_vocab", "Picked {} words", words.len());
+                let checkpoint = path
+                    .map(|path| Checkpoint::create(&path, &words))
+                    .transpose()?;
+                (checkpoint, words, vec![])
+            }
+        };
+
+        if let Some(dir) = &self.export_images {
+            std::fs::create_dir_all(dir)?;
         }
-        info!(target: "visual_vocab", "Picked {} words", result.len());
 
         // create visual flashcards
         info!(target: "visual_vocab", "Creating visual flashcards");
-        let vocabs = create_visual_vocabs(result.as_slice())
-            .await
-            .expect("should have created visual flashcards");
+        let (vocabs, method) = create_visual_vocabs(
+            self,
+            result.as_slice(),
+            gathered,
+            checkpoint.as_ref(),
+        )
+        .await
+        .expect("should have created visual flashcards");
+        // an interrupted sheet has the cards gathered so far, its progress
+        // being kept to --resume it
+        let checkpoint = if self.interrupt.is_interrupted() {
+            warn!(target: "visual_vocab", "Interrupted, creating the sheet of the {} of {} cards gathered", vocabs.len(), result.len());
+            None
+        } else {
+            checkpoint
+        };
+
+        if self.output_type == VisualOutputType::Html {
+            info!(target: "visual_vocab", "Created {} visual flashcards, examples picked by {}", vocabs.len(), method);
+            let content = self.to_html(&vocabs)?.into_bytes();
+            if let Some(checkpoint) = checkpoint {
+                checkpoint.remove();
+            }
+            return Ok(PipelineIO::Document {
+                name: self.output_filename(),
+                content,
+            });
+        }
 
         // create document
         info!(target: "visual_vocab", "Creating document");
@@ -205,32 +965,39 @@ impl Pipeline for VisualVocabPipeline {
             .header(
                 Header::new().add_paragraph(
                     Paragraph::new().add_run(
-                        Run::new()
+                        self.style(Run::new())
                             .add_text(&format!("Nombre: {}", name))
                             .add_tab()
                             .add_text(&format!("Hora: {}", period)),
                     ),
                 ),
-            ).add_paragraph(
-                Paragraph::new().add_run(Run::new()
-                    .add_text("Escoge 18 palabras del vocabulario de esta unidad.")
-                    .add_break(BreakType::TextWrapping)
-                    .add_text("Escribe la palabra de vocabulario y una frase completa con la palabra. Dibuja una foto que representa la palabra."))
+            )
+            .add_paragraph(
+                Paragraph::new().add_run(self.instructions_run(vocabs.len())),
             );
 
-        // a4paper
-        let paper_width = super::docx::cm(21.0);
-        let paper_height = super::docx::cm(29.7);
+        let (paper_width, paper_height) = self.page_size();
+        docx = docx
+            .page_size(
+                super::docx::twip(paper_width),
+                super::docx::twip(paper_height),
+            )
+            .page_orient(match self.orientation {
+                Orientation::Portrait => PageOrientationType::Portrait,
+                Orientation::Landscape => PageOrientationType::Landscape,
+            });
 
         // create tables
         let handles =
             vocabs.chunks(col as usize).enumerate().map(|(i, vocabs)| {
                 info!(target: "visual_vocab", "Creating row {}", i);
                 let vocabs = vocabs.to_owned();
-                tokio::spawn(async move {
+                let config = self.clone();
+                RunContext::spawn(async move {
                     VisualFlashCard::to_table(
                         vocabs,
                         (paper_width, paper_height / 3),
+                        &config,
                     )
                     .await
                     .map_err(|err| {
@@ -247,84 +1014,758 @@ impl Pipeline for VisualVocabPipeline {
             );
         }
 
+        for vocab in &vocabs {
+            if let Some(source) = &vocab.source {
+                info!(target: "visual_vocab", "Image of {} from {}", vocab.word, source);
+            }
+        }
+        if self.attributions {
+            for paragraph in VisualFlashCard::attributions(&vocabs, self) {
+                docx = docx.add_paragraph(paragraph);
+            }
+        }
+
         // save document
         let mut buffer = Cursor::new(Vec::new());
         docx.build()
             .pack(&mut buffer)
             .expect("should have built document");
+        if let Some(checkpoint) = checkpoint {
+            checkpoint.remove();
+        }
+        info!(target: "visual_vocab", "Created {} visual flashcards, examples picked by {}", vocabs.len(), method);
         Ok(PipelineIO::Document {
             name: filename.to_string(),
             content: buffer.into_inner(),
         })
     }
 
+    async fn dry_run(
+        &self,
+        input: Option<PipelineIO>,
+    ) -> Result<PipelineIO, Box<dyn std::error::Error>> {
+        let flashcard = match input {
+            Some(PipelineIO::Flashcard(vocab)) => vocab,
+            _ => return Err(CliError::new("No flashcard input").into()),
+        };
+
+        let words = self.pick_words(&flashcard)?;
+        info!(target: "visual_vocab", "Would lay out {} words in rows of {}:", words.len(), self.col);
+        for row in words.chunks(self.col as usize) {
+            let row = row.iter().map(|x| x.word.as_str()).collect::<Vec<_>>();
+            info!(target: "visual_vocab", "  {}", row.join(" | "));
+        }
+        info!(target: "visual_vocab", "Would search the dictionary for {} words and {} images for each", words.len(), self.image_pool);
+        if let Some(overrides) = &self.image_overrides {
+            for word in words
+                .iter()
+                .filter(|x| overrides.contains_key(&x.word.to_lowercase()))
+            {
+                info!(target: "visual_vocab", "Would download the image of {} from the overrides instead", word.word);
+            }
+        }
+        if let Some(dir) = &self.export_images {
+            info!(target: "visual_vocab", "Would export the images to {}", dir.display());
+        }
+        match self.checkpoint_path() {
+            Some(path) if self.resume && path.exists() => {
+                info!(target: "visual_vocab", "Would resume the words and cards saved in {} instead", path.display());
+            }
+            Some(path) => {
+                info!(target: "visual_vocab", "Would save the progress in {}", path.display());
+            }
+            None => {}
+        }
+        info!(target: "visual_vocab", "Would create {} for {} of period {}", self.output_filename(), self.name, self.period);
+        Ok(PipelineIO::Document {
+            name: self.output_filename(),
+            content: vec![],
+        })
+    }
+
     fn name(&self) -> &'static str {
         "visual_vocab"
     }
+
+    fn set_out_dir(&mut self, dir: &Path) {
+        self.out_dir = Some(dir.to_owned());
+    }
+
+    fn set_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = interrupt;
+    }
+
+    fn needs_models(&self) -> bool {
+        !self.no_ml && !self.no_keywords
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(self.stats.get())
+    }
+}
+
+/// Crop the center of the image to the aspect ratio (width / height)
+fn crop_to_aspect(image: &DynamicImage, aspect: f32) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let (crop_width, crop_height) = if width as f32 / height as f32 > aspect {
+        (((height as f32 * aspect).round() as u32).max(1), height)
+    } else {
+        (width, ((width as f32 / aspect).round() as u32).max(1))
+    };
+    image.crop_imm(
+        (width - crop_width) / 2,
+        (height - crop_height) / 2,
+        crop_width,
+        crop_height,
+    )
+}
+
+/// A visual flashcard whose example is yet to be picked
+#[derive(Clone, Serialize, Deserialize)]
+struct VisualVocabCandidate {
+    vocab: Flashcard,
+    #[serde(with = "png_base64")]
+    image: DynamicImage,
+    source: ImageSource,
+    /// The query to rank the examples against
+    query: String,
+    /// The definition each example illustrates, ranked against the query
+    definitions: Vec<String>,
+    examples: Vec<VisualExample>,
+}
+
+impl VisualVocabCandidate {
+    /// Finish the visual flashcard with the examples in the order ranked,
+    /// without examples if none was ranked. The examples are labelled with
+    /// their parts of speech if `labelled` is set.
+    fn into_visual_flash_card(
+        self,
+        rank: &[(usize, f32)],
+        labelled: bool,
+    ) -> Result<VisualFlashCard, PipelineError> {
+        if rank.is_empty() {
+            warn!(target: "visual_vocab", "No relevant example found for {}, leaving it blank", self.vocab.word);
+        }
+        Ok(VisualFlashCard {
+            word: self.vocab.word,
+            definition: self.vocab.definition,
+            image: self.image,
+            examples: rank
+                .iter()
+                .map(|(index, _)| VisualExample {
+                    group: self.examples[*index]
+                        .group
+                        .clone()
+                        .filter(|_| labelled),
+                    ..self.examples[*index].to_owned()
+                })
+                .collect(),
+            source: Some(self.source),
+        })
+    }
+}
+
+/// Serializes images as base64 encoded PNGs
+mod png_base64 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        image: &DynamicImage,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let png = encode_png(image).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&STANDARD.encode(png))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DynamicImage, D::Error> {
+        let png = STANDARD
+            .decode(String::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)?;
+        image::load_from_memory(&png).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Represents a line of the checkpoint file of a sheet
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CheckpointEntry {
+    /// The words picked for the sheet, on the first line
+    Words(Vec<Flashcard>),
+    /// A card whose image and examples were gathered
+    Card(Box<VisualVocabCandidate>),
+}
+
+/// Saves the progress of a sheet to a file, a line for each card gathered,
+/// so that a failed run can be resumed
+#[derive(Clone)]
+struct Checkpoint {
+    path: PathBuf,
+    file: Arc<std::sync::Mutex<File>>,
+}
+
+impl Checkpoint {
+    /// Start the checkpoint at `path` over for a sheet of `words`
+    fn create(
+        path: &Path,
+        words: &[Flashcard],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let checkpoint = Self {
+            path: path.to_owned(),
+            file: Arc::new(std::sync::Mutex::new(File::create(path)?)),
+        };
+        checkpoint.write(&CheckpointEntry::Words(words.to_vec()))?;
+        Ok(checkpoint)
+    }
+
+    /// Continue the checkpoint at `path`, dropping a last line cut short
+    /// by a crash so that the next entry starts on a line of its own
+    fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = std::fs::read(path)?;
+        let len = content
+            .iter()
+            .rposition(|&x| x == b'\n')
+            .map_or(0, |i| i + 1);
+        let file = OpenOptions::new().append(true).open(path)?;
+        file.set_len(len as u64)?;
+        Ok(Self {
+            path: path.to_owned(),
+            file: Arc::new(std::sync::Mutex::new(file)),
+        })
+    }
+
+    /// Read the words of the sheet and the cards already gathered from the
+    /// checkpoint at `path`. A line cut short by a crash is skipped.
+    fn read(
+        path: &Path,
+    ) -> Result<
+        (Vec<Flashcard>, Vec<VisualVocabCandidate>),
+        Box<dyn std::error::Error>,
+    > {
+        let mut words = None;
+        let mut cards = vec![];
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            match serde_json::from_str(&line?) {
+                Ok(CheckpointEntry::Words(x)) => words = Some(x),
+                Ok(CheckpointEntry::Card(x)) => cards.push(*x),
+                Err(err) => {
+                    warn!(target: "visual_vocab", "Skipping line {} of {}: {}", i + 1, path.display(), err)
+                }
+            }
+        }
+        let words = words.ok_or_else(|| {
+            CliError::new(&format!("no words in {}", path.display()))
+        })?;
+        Ok((words, cards))
+    }
+
+    /// Append an entry to the checkpoint
+    fn write(
+        &self,
+        entry: &CheckpointEntry,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(&line)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Append an entry to the checkpoint on a blocking thread, keeping the
+    /// encoding and writing of its images off the async tasks
+    async fn save(&self, entry: CheckpointEntry) -> Result<(), String> {
+        let checkpoint = self.clone();
+        RunContext::spawn_blocking(move || {
+            checkpoint.write(&entry).map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+
+    /// Remove the checkpoint once the sheet is created
+    fn remove(self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!(target: "visual_vocab", "Failed to remove {}: {}", self.path.display(), err);
+        }
+    }
 }
 
-/// Create visual flashcards
+/// Create visual flashcards, returning them with how their examples were
+/// ranked. The cards in `gathered` are reused, and the others are saved to
+/// the checkpoint, if any, as they are gathered.
 async fn create_visual_vocabs(
+    config: &VisualVocabPipeline,
     vocabs: &[Flashcard],
-) -> Result<Vec<VisualFlashCard>, PipelineError> {
+    mut gathered: Vec<VisualVocabCandidate>,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<(Vec<VisualFlashCard>, RankingMethod), PipelineError> {
     info!(target: "visual_vocab", "Creating visual {} flashcards", vocabs.len());
 
+    // the names are given in the order of the words, so that the same word
+    // twice is exported the same way in every run
+    let mut exported = HashSet::new();
     let tasks = vocabs.iter().map(|vocab| {
+        let done = gathered
+            .iter()
+            .position(|x| x.vocab == *vocab)
+            .map(|i| gathered.swap_remove(i));
+        let export_path = config.export_images.as_ref().and_then(|dir| {
+            let name = export_name(&vocab.word, &mut exported);
+            if name.is_none() {
+                warn!(target: "visual_vocab", "Not exporting the image of a blank word");
+            }
+            name.map(|x| dir.join(x))
+        });
         let vocab = vocab.clone();
-        tokio::spawn(async move {
-            match create_visual_vocab(&vocab).await {
-                Ok(vocab) => vocab,
-                Err(err) => {
-                    error!(target: "visual_vocab", "Error creating visual flashcard: {}", err);
-                    VisualFlashCard::default()
+        let config = config.clone();
+        let checkpoint = checkpoint.cloned();
+        RunContext::spawn(async move {
+            let candidate = match done {
+                Some(candidate) => {
+                    info!(target: "visual_vocab", "Reusing the saved card of {}", vocab.word);
+                    Ok(candidate)
+                }
+                None => {
+                    // the cards not gathered when interrupted are left out
+                    let candidate = tokio::select! {
+                        biased;
+                        _ = config.interrupt.interrupted() => return None,
+                        candidate = create_visual_vocab(&config, &vocab) => candidate,
+                    };
+                    if let (Some(checkpoint), Ok(candidate)) =
+                        (&checkpoint, &candidate)
+                    {
+                        let entry =
+                            CheckpointEntry::Card(Box::new(candidate.clone()));
+                        if let Err(err) = checkpoint.save(entry).await {
+                            warn!(target: "visual_vocab", "Failed to save the progress of {}: {}", vocab.word, err);
+                        }
+                    }
+                    candidate
+                }
+            };
+            if let (Some(path), Ok(candidate)) = (&export_path, &candidate) {
+                match export_image(path, &candidate.image) {
+                    Ok(()) => {
+                        debug!(target: "visual_vocab", "Exported the image of {} to {}", vocab.word, path.display())
+                    }
+                    Err(err) => {
+                        warn!(target: "visual_vocab", "Failed to export the image of {}: {}", vocab.word, err)
+                    }
                 }
             }
+            Some(candidate)
         })
     });
-    let result = futures::future::join_all(tasks)
+    let candidates = futures::future::join_all(tasks)
         .await
         .into_iter()
-        .filter_map(|res| res.ok())
-        .collect();
-    Ok(result)
-}
-
-/// Create a visual flashcard
-async fn create_visual_vocab(
-    vocab: &Flashcard,
-) -> Result<VisualFlashCard, PipelineError> {
-    info!(target: "visual_vocab", "Creating visual flashcard for {}", vocab);
-
-    let mut images = image_search_max(&vocab.word, IMAGE_RANDOM_POOL_SIZE)
-        .await
-        .map_err(|e| {
-            PipelineError::new(&format!("Error getting images: {}", e))
-        })?;
+        .filter_map(|res| res.ok().flatten())
+        .collect::<Vec<_>>();
 
-    let definition = search_vocab(&vocab.word).await.map_err(|e| {
-        PipelineError::new(&format!("Error searching for definition: {}", e))
-    })?;
+    // rank the examples of every word in a single batch, rather than taking
+    // turns on the model for each word
+    let searches = candidates
+        .iter()
+        .flatten()
+        .map(|x| (x.query.as_str(), x.definitions.as_slice()))
+        .collect::<Vec<_>>();
+    // every example is ranked when picking per part of speech or
+    // explaining the ranking, the best being kept afterwards
+    let limit = if config.example_per_group || config.explain {
+        0
+    } else {
+        config.example_limit()
+    };
+    let (method, ranks) = if config.no_ml {
+        let ranks = searches
+            .iter()
+            .map(|(_, contents)| {
+                let count = match limit {
+                    0 => contents.len(),
+                    limit => contents.len().min(limit),
+                };
+                (0..count).map(|i| (i, 1.0)).collect()
+            })
+            .collect();
+        (RankingMethod::First, ranks)
+    } else {
+        deep_search_batch(&searches, limit, config.example_threshold).await
+    };
+    let mut ranks = ranks.into_iter();
+
+    let result = candidates
+        .into_iter()
+        .map(|candidate| {
+            let vocab = candidate.and_then(|x| {
+                let ranked = ranks.next().unwrap_or_default();
+                let rank = if config.example_per_group {
+                    best_per_group(&ranked, &x.examples, config.example_limit())
+                } else {
+                    ranked.iter().take(config.example_limit()).copied().collect()
+                };
+                if config.explain {
+                    let word = &x.vocab.word;
+                    for line in explain_rank(word, method, &x.examples, &ranked, &rank) {
+                        debug!(target: "visual_vocab", "{}", line);
+                    }
+                }
+                x.into_visual_flash_card(&rank, config.example_per_group)
+            });
+            match vocab {
+                Ok(vocab) => {
+                    info!(target: "visual_vocab", "Created visual flashcard {}", vocab);
+                    config.stats.record(|x| x.cards_built += 1);
+                    vocab
+                }
+                Err(err) => {
+                    error!(target: "visual_vocab", "Error creating visual flashcard: {}", err);
+                    VisualFlashCard::default()
+                }
+            }
+        })
+        .collect();
+    Ok((result, method))
+}
 
-    let image = loop {
-        let img = images.remove(random::<usize>() % images.len());
-        match img.full.get_image().await {
-            Ok(img) => {
-                break Some(img);
+/// Return the lines explaining the ranking of the examples of `word`, each
+/// example ranked with its similarity, the picked ones marked with a `*`
+fn explain_rank(
+    word: &str,
+    method: RankingMethod,
+    examples: &[VisualExample],
+    ranked: &[(usize, f32)],
+    picked: &[(usize, f32)],
+) -> Vec<String> {
+    let mut lines = vec![format!(
+        "Ranked {} of the {} examples of {} by {}",
+        ranked.len(),
+        examples.len(),
+        word,
+        method
+    )];
+    lines.extend(ranked.iter().map(|&(i, similarity)| {
+        let mark = if picked.iter().any(|x| x.0 == i) {
+            '*'
+        } else {
+            ' '
+        };
+        let group = examples[i]
+            .group
+            .as_deref()
+            .map(|x| format!(" ({})", x))
+            .unwrap_or_default();
+        format!(
+            "{} {:.3} {}{}",
+            mark, similarity, examples[i].example, group
+        )
+    }));
+    if ranked.len() < examples.len() {
+        lines.push(format!(
+            "{} examples were not above the --example-threshold",
+            examples.len() - ranked.len()
+        ));
+    }
+    lines
+}
+
+/// Keep the best ranked example of each part of speech, ignoring case, up to
+/// `limit` examples in the order ranked
+fn best_per_group(
+    rank: &[(usize, f32)],
+    examples: &[VisualExample],
+    limit: usize,
+) -> Vec<(usize, f32)> {
+    let mut seen = HashSet::new();
+    rank.iter()
+        .filter(|(i, _)| {
+            let group = examples[*i].group.as_deref().unwrap_or_default();
+            seen.insert(group.to_lowercase())
+        })
+        .take(limit)
+        .copied()
+        .collect()
+}
+
+/// Name the exported image of a word `<word>.png`, replacing the characters
+/// that cannot be in file names. A name already in `taken`, ignoring case,
+/// gets a numeric suffix, e.g. `casa (2).png`, and a blank word has no name.
+fn export_name(word: &str, taken: &mut HashSet<String>) -> Option<String> {
+    let stem = word
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    if stem.is_empty() {
+        return None;
+    }
+    (1..)
+        .map(|i| match i {
+            1 => format!("{}.png", stem),
+            i => format!("{} ({}).png", stem, i),
+        })
+        .find(|name| taken.insert(name.to_lowercase()))
+}
+
+/// Write the image of a word to `path` as a PNG
+fn export_image(
+    path: &Path,
+    image: &DynamicImage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, encode_png(image)?)?;
+    Ok(())
+}
+
+/// Return the best ranked image of the pool that downloads and decodes, with
+/// a few downloads in flight at once, or the number of images that failed.
+/// The downloads are taken in the order of the pool, so a faster download
+/// of a worse image never wins, and the pending ones are cancelled once an
+/// image decodes. The images in `failed` are skipped, and the ones failing
+/// now are added to it, so that a retry tries other images.
+async fn first_image(
+    images: &[GoogleImage],
+    max_bytes: u64,
+    quality: ImageQuality,
+    failed: &mut HashSet<String>,
+) -> Result<(DynamicImage, ImageSource), usize> {
+    let untried = images
+        .iter()
+        .filter(|x| !failed.contains(&x.full.src))
+        .collect::<Vec<_>>();
+    let mut pending = untried.into_iter();
+    let mut downloads = pending
+        .by_ref()
+        .take(IMAGE_DOWNLOADS)
+        .map(|img| download_image(img, max_bytes, quality))
+        .collect::<FuturesOrdered<_>>();
+    let mut failures = 0;
+    while let Some((img, result)) = downloads.next().await {
+        match result {
+            Ok(decoded) => {
+                let source = ImageSource {
+                    title: img.title.to_owned(),
+                    url: img.url.to_owned(),
+                };
+                return Ok((decoded, source));
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    SpiderErrorKind::ContentType | SpiderErrorKind::TooLarge
+                ) =>
+            {
+                debug!(target: "visual_vocab", "{}", err);
+                failed.insert(img.full.src.to_owned());
+                failures += 1;
             }
             Err(err) => {
                 error!(target: "visual_vocab", "Error getting image bytes: {}", err);
+                failed.insert(img.full.src.to_owned());
+                failures += 1;
             }
         }
-    };
-    let image = match image {
-        Some(img) => img,
-        None => {
-            return Err(PipelineError::new("No image found"));
+        if let Some(img) = pending.next() {
+            downloads.push_back(download_image(img, max_bytes, quality));
         }
+    }
+    Err(failures)
+}
+
+/// Download and decode the image of the size `quality`, returning it along
+/// with the image searched
+async fn download_image(
+    img: &GoogleImage,
+    max_bytes: u64,
+    quality: ImageQuality,
+) -> (&GoogleImage, Result<DynamicImage, SpiderError>) {
+    let result = match quality {
+        ImageQuality::Full => img.full.get_image(max_bytes).await,
+        ImageQuality::Thumb => img.thumb.get_image(max_bytes).await,
+        ImageQuality::Auto => match img.full.get_image(max_bytes).await {
+            Ok(image) => Ok(image),
+            Err(err) => {
+                debug!(target: "visual_vocab", "Falling back to the thumbnail of {}: {}", img, err);
+                img.thumb.get_image(max_bytes).await
+            }
+        },
     };
+    (img, result)
+}
+
+/// Order the pool of images by how they should be tried. By resolution, the
+/// images within `max_size` come first, largest first, followed by the
+/// larger images, smallest first.
+fn order_images<R: rand::Rng>(
+    images: &mut [GoogleImage],
+    strategy: ImageStrategy,
+    max_size: u32,
+    rng: &mut R,
+) {
+    match strategy {
+        ImageStrategy::Random => images.shuffle(rng),
+        ImageStrategy::BestByResolution => images.sort_by_key(|x| {
+            let area = x.full.width as u64 * x.full.height as u64;
+            let oversized = x.full.width.max(x.full.height) > max_size;
+            (oversized, if oversized { area } else { u64::MAX - area })
+        }),
+    }
+}
+
+/// Search for the images of a word, returning the first one that downloads
+/// in the order of the image strategy. The images in `failed` by earlier
+/// attempts are skipped, with the pool grown to make up for them.
+async fn search_image(
+    config: &VisualVocabPipeline,
+    vocab: &Flashcard,
+    failed: &mut HashSet<String>,
+) -> Result<(DynamicImage, ImageSource), PipelineError> {
+    let query = config.image_query(&vocab.word);
+    let pool = config.image_pool + failed.len() as u32;
+    let mut images = image_search_max(&query, pool).await.map_err(|e| {
+        PipelineError::with_source(
+            &format!("Error getting images: {}", e),
+            SpiderError::flatten(e.as_ref()),
+        )
+    })?;
+    order_images(
+        &mut images,
+        config.image_strategy,
+        config.max_image_size,
+        &mut rand::thread_rng(),
+    );
+    let candidates = images
+        .iter()
+        .filter(|x| !failed.contains(&x.full.src))
+        .count();
+    first_image(
+        &images,
+        config.max_image_bytes,
+        config.image_quality,
+        failed,
+    )
+    .await
+    .map_err(|failures| {
+        PipelineError::new(&format!(
+            "no usable image for {} ({} of {} candidates failed)",
+            vocab.word, failures, candidates
+        ))
+    })
+}
+
+/// Download the image at `url` given in the image overrides, or open it if
+/// it is the path of a local file
+async fn override_image(
+    url: &str,
+    max_bytes: u64,
+) -> Result<(DynamicImage, ImageSource), PipelineError> {
+    let path = Path::new(url);
+    if !url.contains("://") && path.is_file() {
+        let image = image::open(path).map_err(|e| {
+            PipelineError::new(&format!("Error opening image override: {}", e))
+        })?;
+        let source = ImageSource {
+            title: "image override".to_string(),
+            url: url.to_string(),
+        };
+        return Ok((image, source));
+    }
+    let image = Image {
+        src: url.to_string(),
+        alt: String::new(),
+        width: 0,
+        height: 0,
+    }
+    .get_image(max_bytes)
+    .await
+    .map_err(|e| {
+        PipelineError::with_source(
+            &format!("Error getting image override: {}", e),
+            e,
+        )
+    })?;
+    let source = ImageSource {
+        title: "image override".to_string(),
+        url: url.to_string(),
+    };
+    Ok((image, source))
+}
+
+/// Gather the image and the candidate examples of a visual flashcard,
+/// starting over up to `config.attempts` times on failure
+async fn create_visual_vocab(
+    config: &VisualVocabPipeline,
+    vocab: &Flashcard,
+) -> Result<VisualVocabCandidate, PipelineError> {
+    let mut attempt = 1;
+    // the images that failed to download are not tried again on a retry
+    let mut failed = HashSet::new();
+    loop {
+        match build_visual_vocab(config, vocab, &mut failed).await {
+            Err(err) if attempt < config.attempts => {
+                warn!(target: "visual_vocab", "Attempt {} at {} failed, retrying: {}", attempt, vocab.word, err);
+                attempt += 1;
+            }
+            result => {
+                // counted once for the card, rather than for every attempt
+                if let Err(err) = &result {
+                    if err.kind() == PipelineErrorKind::Image {
+                        config.stats.record(|x| x.image_failures += 1);
+                    }
+                }
+                return result;
+            }
+        }
+    }
+}
+
+/// Make a single attempt at gathering the image and the candidate examples
+/// of a visual flashcard
+async fn build_visual_vocab(
+    config: &VisualVocabPipeline,
+    vocab: &Flashcard,
+    failed_images: &mut HashSet<String>,
+) -> Result<VisualVocabCandidate, PipelineError> {
+    info!(target: "visual_vocab", "Creating visual flashcard for {}", vocab);
+
+    let overridden = config
+        .image_overrides
+        .as_ref()
+        .and_then(|x| x.get(&vocab.word.to_lowercase()));
+    let (image, source) = match overridden {
+        Some(url) => override_image(url, config.max_image_bytes).await,
+        None => search_image(config, vocab, failed_images).await,
+    }
+    .map_err(|e| e.of_kind(PipelineErrorKind::Image))?;
     info!(target: "visual_vocab", "Got image for {}", vocab);
 
-    let examples: Vec<(_, _)> = definition
+    let keyword_fallback = !config.no_ml && !config.no_keywords;
+    let definition = search_vocab(
+        &vocab.word,
+        SearchStrategy {
+            offline: config.offline,
+            ..config
+                .search_attempts
+                .strategy(keyword_fallback, config.lemmatize)
+        },
+    )
+    .await
+    .map_err(|e| {
+        PipelineError::with_source(
+            &format!("Error searching for definition: {}", e),
+            SpiderError::flatten(e.as_ref()),
+        )
+    })?;
+
+    let (definitions, examples): (Vec<_>, Vec<_>) = definition
         .definitions
         .iter()
         .filter(|x| {
@@ -345,11 +1786,22 @@ async fn create_visual_vocab(
                     .map(|x| {
                         let def = format!("{} ({})", definition, group);
                         let example = match x {
-                            DictionaryExample::Example { example } => example,
+                            DictionaryExample::Example { example } => {
+                                VisualExample {
+                                    example: example.to_owned(),
+                                    translation: None,
+                                    group: Some(group.to_owned()),
+                                }
+                            }
                             DictionaryExample::ExampleAndTranslation {
                                 example,
-                                translation: _,
-                            } => example,
+                                translation,
+                            } => VisualExample {
+                                example: example.to_owned(),
+                                translation: Some(translation.to_owned())
+                                    .filter(|x| !x.is_empty()),
+                                group: Some(group.to_owned()),
+                            },
                         };
                         (def, example)
                     })
@@ -357,72 +1809,232 @@ async fn create_visual_vocab(
             }
             vec![]
         })
-        .collect();
+        .unzip();
 
-    let definition =
-        examples.iter().map(|x| x.0.to_owned()).collect::<Vec<_>>();
-    let rank = deep_search(&vocab.word, &definition, 1, 0.0).await;
-    let example = examples[rank[0].0].1.to_owned();
+    // bias the ranking towards the primary part of speech of the word, so a
+    // noun prefers noun-usage sentences
+    let query = definition
+        .definitions
+        .iter()
+        .find_map(|x| match x {
+            DictionaryDefinition::DefinitionAndGroup { group, .. }
+            | DictionaryDefinition::DefinitionAndGroupWithExample {
+                group,
+                ..
+            } => Some(format!("{} ({})", vocab.word, group)),
+            DictionaryDefinition::Definition { .. } => None,
+        })
+        .unwrap_or_else(|| vocab.word.to_owned());
 
-    let visual_flash_card = VisualFlashCard {
-        word: vocab.word.to_owned(),
-        definition: vocab.definition.to_owned(),
+    Ok(VisualVocabCandidate {
+        vocab: vocab.to_owned(),
         image,
-        example,
-    };
-    info!(target: "visual_vocab", "Created visual flashcard {}", visual_flash_card);
-    Ok(visual_flash_card)
+        source,
+        query,
+        definitions,
+        examples,
+    })
 }
 
-static SENTENCE_EMBEDDER: OnceCell<Mutex<SentenceEmbeddingsModel>> =
-    OnceCell::const_new();
+/// How the examples of the visual flashcards were ranked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RankingMethod {
+    /// Cosine similarity of the sentence embeddings
+    Semantic,
+    /// Token overlap, used when the sentence embedder is unavailable
+    Lexical,
+    /// The first example found, used when the models are disabled
+    First,
+}
 
-/// Search for a query in a list of strings
-/// - `query` is the string to search for
-/// - `contents` is the list of strings to search in
-/// - `limit` is the maximum number of results to return. If 0, return all results
-/// - `threshold` is the minimum similarity score to return a result
-/// Return a list ranked by relevance of the results
-async fn deep_search(
-    query: &str,
-    contents: &[String],
-    limit: usize,
-    threshold: f32,
-) -> Vec<(usize, f32)> {
-    debug!(target: "deep_search", "Searching for {} in {} contents", query, contents.len());
-    if contents.is_empty() {
-        info!(target: "deep_search", "No contents to search for {}", query);
-        return vec![];
+impl std::fmt::Display for RankingMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RankingMethod::Semantic => write!(f, "semantic similarity"),
+            RankingMethod::Lexical => write!(f, "lexical overlap"),
+            RankingMethod::First => write!(f, "first available"),
+        }
     }
+}
+
+static SENTENCE_EMBEDDER: OnceCell<Option<Mutex<SentenceEmbeddingsModel>>> =
+    OnceCell::const_new();
 
-    let model = SENTENCE_EMBEDDER
+/// Return the sentence embedder, loading it on first use. Return `None` if
+/// the model failed to load (e.g. offline or out of disk space)
+async fn sentence_embedder() -> Option<&'static Mutex<SentenceEmbeddingsModel>>
+{
+    SENTENCE_EMBEDDER
         .get_or_init(|| async {
-            task::spawn_blocking(move || {
+            let model = RunContext::spawn_blocking(move || {
                 info!(target: "deep_search", "Loading sentence embedder model");
-                Mutex::new(
-                    SentenceEmbeddingsBuilder::remote(
-                        SentenceEmbeddingsModelType::AllMiniLmL12V2,
-                    )
-                    .create_model()
-                    .expect("should have created a model"),
+                SentenceEmbeddingsBuilder::remote(
+                    SentenceEmbeddingsModelType::AllMiniLmL12V2,
                 )
+                .create_model()
+                .map_err(|e| e.to_string())
             })
             .await
-            .expect("should have awaited task")
+            .map_err(|e| e.to_string())
+            .and_then(|x| x);
+            match model {
+                Ok(model) => Some(Mutex::new(model)),
+                Err(err) => {
+                    warn!(target: "deep_search", "Failed to load sentence embedder, semantic ranking is unavailable: {}", err);
+                    None
+                }
+            }
         })
         .await
-        .lock()
-        .await;
-    let query_embedding =
-        model.encode(&[query]).expect("should have encoded query")[0]
-            .to_owned();
-    let content_embedding = model
-        .encode(contents)
-        .expect("should have encoded contents");
-    let similarities = content_embedding
+        .as_ref()
+}
+
+/// Search for queries in lists of strings, encoding every query and content
+/// with a single call to the model
+/// - `searches` is the list of queries and the strings to search them in
+/// - `limit` is the maximum number of results to return. If 0, return all results
+/// - `threshold` is the minimum similarity score to return a result
+/// Return how the results were ranked, and a list ranked by relevance of the
+/// results for each search. Falls back to lexical ranking when the sentence
+/// embedder is unavailable.
+pub(super) async fn deep_search_batch(
+    searches: &[(&str, &[String])],
+    limit: usize,
+    threshold: f32,
+) -> (RankingMethod, Vec<Vec<(usize, f32)>>) {
+    // empty contents are never encoded nor ranked, keeping the indices of
+    // the others to map the ranks back to the contents
+    let kept = searches
+        .iter()
+        .map(|(_, contents)| {
+            (0..contents.len())
+                .filter(|&i| !contents[i].trim().is_empty())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut sentences = Vec::new();
+    for ((query, contents), kept) in searches.iter().zip(&kept) {
+        debug!(target: "deep_search", "Searching for {} in {} contents", query, kept.len());
+        if kept.is_empty() {
+            info!(target: "deep_search", "No contents to search for {}", query);
+            continue;
+        }
+        sentences.push(*query);
+        sentences.extend(kept.iter().map(|&i| contents[i].as_str()));
+    }
+    if sentences.is_empty() {
+        return (RankingMethod::Semantic, vec![vec![]; searches.len()]);
+    }
+
+    let embeddings = match sentence_embedder().await {
+        Some(model) => match model.lock().await.encode(&sentences) {
+            Ok(embeddings) => Some(embeddings),
+            Err(err) => {
+                warn!(target: "deep_search", "Failed to encode sentences, semantic ranking is unavailable: {}", err);
+                None
+            }
+        },
+        None => None,
+    };
+    let rank = |similarities: &[f32], kept: &[usize]| {
+        rank_similarities(similarities, limit, threshold)
+            .into_iter()
+            .map(|(i, similarity)| (kept[i], similarity))
+            .collect::<Vec<_>>()
+    };
+    let Some(embeddings) = embeddings else {
+        let results = searches
+            .iter()
+            .zip(&kept)
+            .map(|((query, contents), kept)| {
+                let similarities = kept
+                    .iter()
+                    .map(|&i| lexical_similarity(query, &contents[i]))
+                    .collect::<Vec<f32>>();
+                rank(&similarities, kept)
+            })
+            .collect();
+        return (RankingMethod::Lexical, results);
+    };
+
+    let mut embeddings = embeddings.iter();
+    let results = kept
         .iter()
-        .map(|x| cos_similarity(&query_embedding, x))
-        .collect::<Vec<f32>>();
+        .map(|kept| {
+            if kept.is_empty() {
+                return vec![];
+            }
+            let query_embedding =
+                embeddings.next().expect("should have encoded query");
+            let similarities = embeddings
+                .by_ref()
+                .take(kept.len())
+                .map(|x| cos_similarity(query_embedding, x))
+                .collect::<Vec<f32>>();
+            rank(&similarities, kept)
+        })
+        .collect();
+    (RankingMethod::Semantic, results)
+}
+
+/// Parse a font size, which must be between 6 and 72 points
+fn parse_font_size(arg: &str) -> Result<f32, String> {
+    let size = arg
+        .parse::<f32>()
+        .map_err(|_| format!("{} is not a number", arg))?;
+    if !(6.0..=72.0).contains(&size) {
+        return Err(format!("{} is not between 6 and 72 points", size));
+    }
+    Ok(size)
+}
+
+/// Parse a cell margin, which must be between 0 and 5 cm
+fn parse_cell_margin(arg: &str) -> Result<f32, String> {
+    let margin = arg
+        .parse::<f32>()
+        .map_err(|_| format!("{} is not a number", arg))?;
+    if !(0.0..=5.0).contains(&margin) {
+        return Err(format!("{} is not between 0 and 5 cm", margin));
+    }
+    Ok(margin)
+}
+
+/// Parse a YAML file mapping words to the URLs or paths of their images, keyed by
+/// the lowercase words
+fn parse_image_overrides(
+    path: &str,
+) -> Result<HashMap<String, String>, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("failed to open {}: {}", path, e))?;
+    let overrides: HashMap<String, String> = serde_yaml::from_reader(file)
+        .map_err(|e| {
+            format!("{} is not a map of words to URLs: {}", path, e)
+        })?;
+    Ok(overrides
+        .into_iter()
+        .map(|(word, url)| (word.trim().to_lowercase(), url))
+        .collect())
+}
+
+/// Parse a similarity threshold, which must be between -1 and 1
+fn parse_threshold(arg: &str) -> Result<f32, String> {
+    let threshold = arg
+        .parse::<f32>()
+        .map_err(|_| format!("{} is not a number", arg))?;
+    if !(-1.0..=1.0).contains(&threshold) {
+        return Err(format!("{} is not between -1 and 1", threshold));
+    }
+    Ok(threshold)
+}
+
+/// Rank the indices of the similarities above `threshold`, returning at
+/// most `limit` results. If `limit` is 0, return all results. Equal
+/// similarities keep the order of their indices.
+fn rank_similarities(
+    similarities: &[f32],
+    limit: usize,
+    threshold: f32,
+) -> Vec<(usize, f32)> {
     let mut results = similarities
         .iter()
         .enumerate()
@@ -434,14 +2046,30 @@ async fn deep_search(
             }
         })
         .collect::<Vec<_>>();
-    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    if limit == 0 {
-        results
-    } else {
-        results[0..limit].to_vec()
+    results.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    if limit != 0 {
+        results.truncate(limit);
     }
+    results
 }
 
+/// Score the words shared by two strings, normalized like the cosine
+/// similarity of their sets of words
+fn lexical_similarity(a: &str, b: &str) -> f32 {
+    let tokenize = |x: &str| {
+        x.split(|c: char| !c.is_alphanumeric())
+            .filter(|x| !x.is_empty())
+            .map(str::to_lowercase)
+            .collect::<HashSet<_>>()
+    };
+    let (a, b) = (tokenize(a), tokenize(b));
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f32 / ((a.len() * b.len()) as f32).sqrt()
+}
+
+/// The cosine similarity of two embeddings, or 0 if either is all zeros
 fn cos_similarity(a: &[f32], b: &[f32]) -> f32 {
     let mut dot_product = 0.0;
     let mut a_norm = 0.0;
@@ -451,7 +2079,11 @@ fn cos_similarity(a: &[f32], b: &[f32]) -> f32 {
         a_norm += a[i] * a[i];
         b_norm += b[i] * b[i];
     }
-    dot_product / (a_norm * b_norm).sqrt()
+    let denominator = (a_norm * b_norm).sqrt();
+    if denominator == 0.0 {
+        return 0.0;
+    }
+    dot_product / denominator
 }
 
 #[cfg(test)]
@@ -486,7 +2118,10 @@ mod test {
             let contents = contents.clone();
             let task = tokio::spawn(async move {
                 let results =
-                    deep_search(query, contents.as_ref(), 0, 0.0).await;
+                    deep_search_batch(&[(query, contents.as_ref())], 0, 0.0)
+                        .await
+                        .1
+                        .remove(0);
                 assert_eq!(results.len(), 3);
                 assert_eq!(results[0].0, 0);
                 results
@@ -502,4 +2137,781 @@ mod test {
             assert_eq!(results[i], results[0]);
         }
     }
+
+    #[tokio::test]
+    async fn test_deep_search_empty_content() {
+        let query = "this is an example sentence";
+        let contents = [
+            "".to_string(),
+            "this example sentence is the first sentence".to_string(),
+            "  ".to_string(),
+        ];
+        let (_, mut results) =
+            deep_search_batch(&[(query, contents.as_ref())], 0, -1.0).await;
+        let results = results.remove(0);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, 1);
+        assert!(!results[0].1.is_nan());
+    }
+
+    #[tokio::test]
+    async fn test_deep_search_ties() {
+        let query = "this is an example sentence";
+        let contents = [
+            "each sentence is converted".to_string(),
+            "this example sentence is the first sentence".to_string(),
+            "each sentence is converted".to_string(),
+            "this example sentence is the first sentence".to_string(),
+        ];
+        let (_, mut results) =
+            deep_search_batch(&[(query, contents.as_ref())], 0, -1.0).await;
+        let indices = results
+            .remove(0)
+            .into_iter()
+            .map(|x| x.0)
+            .collect::<Vec<_>>();
+        assert_eq!(indices, [1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn test_cos_similarity() {
+        assert_eq!(cos_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cos_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cos_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+        assert_eq!(cos_similarity(&[0.0, 0.0], &[0.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn test_rank_similarities() {
+        let similarities = [0.1, 0.5, -0.2, 0.3];
+        assert_eq!(
+            rank_similarities(&similarities, 2, 0.0),
+            vec![(1, 0.5), (3, 0.3)]
+        );
+        assert_eq!(rank_similarities(&similarities, 0, 0.0).len(), 3);
+        assert_eq!(rank_similarities(&similarities, 5, 0.4), vec![(1, 0.5)]);
+        assert_eq!(
+            rank_similarities(&[0.2, f32::NAN, 0.2, 0.7], 0, -1.0),
+            vec![(3, 0.7), (0, 0.2), (2, 0.2)]
+        );
+    }
+
+    #[test]
+    fn test_lexical_similarity() {
+        assert_eq!(lexical_similarity("casa (noun)", "house (noun)"), 0.5);
+        assert_eq!(lexical_similarity("casa", "house"), 0.0);
+        assert_eq!(lexical_similarity("", "house"), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let pipeline = VisualVocabPipeline::new(1, 2, "Jane", "3", "vv.docx");
+        let card = |word: &str| Flashcard::new(word, "");
+        let input = PipelineIO::Flashcard(vec![card("casa"), card("perro")]);
+        let output = pipeline.dry_run(Some(input)).await.unwrap();
+        let PipelineIO::Document { name, content } = output else {
+            panic!("should have planned a document");
+        };
+        assert_eq!(name, "vv.docx");
+        assert!(content.is_empty());
+    }
+
+    #[test]
+    fn test_page_size() {
+        let mut pipeline = VisualVocabPipeline::new(3, 6, "Jane", "3", "a");
+        assert_eq!(pipeline.page_size(), (7_560_000, 10_692_000));
+        pipeline.paper = PaperSize::Letter;
+        pipeline.orientation = Orientation::Landscape;
+        assert_eq!(pipeline.page_size(), (10_058_400, 7_772_400));
+    }
+
+    #[test]
+    fn test_total() {
+        let mut pipeline = VisualVocabPipeline::new(3, 3, "Jane", "3", "a");
+        assert_eq!(pipeline.total().unwrap(), 9);
+        pipeline.total = Some(6);
+        assert_eq!(pipeline.total().unwrap(), 6);
+        pipeline.total = Some(10);
+        assert!(pipeline.total().is_err());
+    }
+
+    #[test]
+    fn test_image_query() {
+        let mut pipeline = VisualVocabPipeline::new(1, 1, "Jane", "3", "a");
+        assert_eq!(pipeline.image_query("libertad"), "libertad");
+        pipeline.image_query_suffix = " clip art ".to_string();
+        assert_eq!(pipeline.image_query("libertad"), "libertad clip art");
+    }
+
+    #[test]
+    fn test_instructions() {
+        let mut pipeline = VisualVocabPipeline::new(2, 3, "Jane", "3", "a");
+        assert!(pipeline
+            .instructions(6)
+            .starts_with("Escoge 6 palabras del vocabulario"));
+        pipeline.instructions = Some("Choose <COUNT> words.".to_string());
+        assert_eq!(pipeline.instructions(4), "Choose 4 words.");
+    }
+
+    #[test]
+    fn test_crop_to_aspect() {
+        let mut image = image::RgbImage::new(200, 100);
+        image.put_pixel(100, 50, image::Rgb([255, 0, 0]));
+        let image = DynamicImage::ImageRgb8(image);
+
+        let cropped = crop_to_aspect(&image, 1.0);
+        assert_eq!(cropped.dimensions(), (100, 100));
+        assert_eq!(cropped.get_pixel(50, 50), image::Rgba([255, 0, 0, 255]));
+
+        assert_eq!(crop_to_aspect(&image, 4.0).dimensions(), (200, 50));
+        assert_eq!(crop_to_aspect(&image, 2.0).dimensions(), (200, 100));
+    }
+
+    #[test]
+    fn test_to_html() {
+        let mut pipeline =
+            VisualVocabPipeline::new(1, 2, "Jane <3>", "3", "vv.docx");
+        pipeline.output_type = VisualOutputType::Html;
+        pipeline.attributions = true;
+        assert_eq!(pipeline.output_filename(), "vv.html");
+
+        let vocab = VisualFlashCard {
+            word: "casa".to_string(),
+            definition: String::new(),
+            image: DynamicImage::new_rgb8(2, 2),
+            examples: vec![VisualExample {
+                example: "Mi casa.".to_string(),
+                translation: None,
+                group: None,
+            }],
+            source: Some(ImageSource {
+                title: "A house".to_string(),
+                url: "https://example.com".to_string(),
+            }),
+        };
+        let html = pipeline
+            .to_html(&[vocab, VisualFlashCard::default()])
+            .unwrap();
+        assert!(html.contains("<p>Nombre: Jane &lt;3&gt;</p>"));
+        assert!(html.contains("<h2>Vocabulario: casa</h2>"));
+        assert!(html.contains("<p>Frase Completa: Mi casa.</p>"));
+        assert_eq!(
+            html.matches("<img src=\"data:image/png;base64,").count(),
+            1
+        );
+        assert!(html.contains("<li>casa → https://example.com (A house)</li>"));
+    }
+
+    #[tokio::test]
+    async fn test_interrupt() {
+        let words = ["casa", "perro"].map(|x| Flashcard::new(x, "")).to_vec();
+        let gathered = VisualVocabCandidate {
+            vocab: words[0].clone(),
+            image: DynamicImage::new_rgb8(3, 2),
+            source: ImageSource {
+                title: "A house".to_string(),
+                url: "https://example.com".to_string(),
+            },
+            query: "house".to_string(),
+            definitions: vec![],
+            examples: vec![],
+        };
+        let mut pipeline =
+            VisualVocabPipeline::parse_from(["visual_vocab", "Jane", "3"]);
+        pipeline.no_ml = true;
+        let interrupt = Interrupt::default();
+        pipeline.set_interrupt(interrupt.clone());
+        interrupt.interrupt();
+
+        // only the cards already gathered are kept, without searching for
+        // the others
+        let (cards, _) =
+            create_visual_vocabs(&pipeline, &words, vec![gathered], None)
+                .await
+                .unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].word, "casa");
+
+        // the interrupt is the run's own, the next run starting afresh
+        pipeline.set_interrupt(Interrupt::default());
+        assert!(!pipeline.interrupt.is_interrupted());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vv.checkpoint.jsonl");
+        let words = ["casa", "perro"].map(|x| Flashcard::new(x, "")).to_vec();
+        let candidate = VisualVocabCandidate {
+            vocab: words[0].clone(),
+            image: DynamicImage::new_rgb8(3, 2),
+            source: ImageSource {
+                title: "A house".to_string(),
+                url: "https://example.com".to_string(),
+            },
+            query: "house".to_string(),
+            definitions: vec!["house".to_string()],
+            examples: vec![VisualExample {
+                example: "Mi casa.".to_string(),
+                translation: Some("My house.".to_string()),
+                group: None,
+            }],
+        };
+
+        let checkpoint = Checkpoint::create(&path, &words).unwrap();
+        checkpoint
+            .write(&CheckpointEntry::Card(Box::new(candidate)))
+            .unwrap();
+        // a crash in the middle of a line leaves it cut short
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"card\":{\"vocab\":")
+            .unwrap();
+
+        let (read, cards) = Checkpoint::read(&path).unwrap();
+        assert_eq!(read, words);
+        assert_eq!(cards.len(), 1);
+
+        // resuming drops the line cut short rather than gluing onto it
+        let resumed = Checkpoint::open(&path).unwrap();
+        resumed
+            .write(&CheckpointEntry::Card(Box::new(cards[0].clone())))
+            .unwrap();
+        let (_, cards) = Checkpoint::read(&path).unwrap();
+        assert_eq!(cards.len(), 2);
+        assert_eq!(cards[0].vocab, words[0]);
+        assert_eq!(cards[0].image.dimensions(), (3, 2));
+        assert_eq!(cards[0].source.url, "https://example.com");
+        assert_eq!(
+            cards[0].examples[0].translation.as_deref(),
+            Some("My house.")
+        );
+
+        checkpoint.remove();
+        assert!(!path.exists());
+        assert!(Checkpoint::read(&path).is_err());
+    }
+
+    #[test]
+    fn test_qr_png() {
+        let png = qr_png("https://www.spanishdict.com/translate/casa").unwrap();
+        let image = image::load_from_memory(&png).unwrap().into_luma8();
+        let (width, _) = image.dimensions();
+        // the quiet zone is blank, and the finder pattern starts after it
+        let corner = QR_QUIET_ZONE * QR_MODULE_PX;
+        assert_eq!(image.get_pixel(0, 0), &Luma([255]));
+        assert_eq!(image.get_pixel(corner, corner), &Luma([0]));
+        assert_eq!(image.get_pixel(width - corner, corner), &Luma([255]));
+        assert_eq!(image.get_pixel(width - corner - 1, corner), &Luma([0]));
+    }
+
+    #[test]
+    fn test_example_lines() {
+        let example =
+            |example: &str, translation: Option<&str>| VisualExample {
+                example: example.to_string(),
+                translation: translation.map(str::to_string),
+                group: None,
+            };
+        let line = |text: &str, italic| (text.to_string(), italic);
+        let mut card = VisualFlashCard::default();
+        card.examples = vec![example("La casa es roja.", Some("It is red."))];
+        assert_eq!(
+            card.example_lines(false),
+            [line("Frase Completa: La casa es roja.", false)]
+        );
+        assert_eq!(
+            card.example_lines(true),
+            [
+                line("Frase Completa: La casa es roja.", false),
+                line("It is red.", true)
+            ]
+        );
+        card.examples.push(example("Mi casa es tu casa.", None));
+        assert_eq!(
+            card.example_lines(true),
+            [
+                line("Frases Completas:", false),
+                line("• La casa es roja.", false),
+                line("It is red.", true),
+                line("• Mi casa es tu casa.", false)
+            ]
+        );
+        card.examples[1].group = Some("feminine noun".to_string());
+        assert_eq!(
+            card.example_lines(false)[2],
+            line("• (feminine noun) Mi casa es tu casa.", false)
+        );
+    }
+
+    #[test]
+    fn test_best_per_group() {
+        let example = |group: &str| VisualExample {
+            example: String::new(),
+            translation: None,
+            group: Some(group.to_string()),
+        };
+        let examples = [
+            example("intransitive verb"),
+            example("masculine noun"),
+            example("Intransitive verb"),
+            example("pronominal verb"),
+        ];
+        let rank = [(2, 0.9), (0, 0.8), (3, 0.5), (1, 0.2)];
+        assert_eq!(
+            best_per_group(&rank, &examples, 5),
+            [(2, 0.9), (3, 0.5), (1, 0.2)]
+        );
+        assert_eq!(best_per_group(&rank, &examples, 2), [(2, 0.9), (3, 0.5)]);
+    }
+
+    #[test]
+    fn test_explain_rank() {
+        let example = |example: &str, group: Option<&str>| VisualExample {
+            example: example.to_string(),
+            translation: None,
+            group: group.map(str::to_string),
+        };
+        let examples = [
+            example("Corro cada mañana.", Some("intransitive verb")),
+            example("Corre el agua.", None),
+            example("El banco está cerrado.", None),
+        ];
+        let lines = explain_rank(
+            "correr",
+            RankingMethod::Semantic,
+            &examples,
+            &[(1, 0.8125), (0, 0.5)],
+            &[(1, 0.8125)],
+        );
+        assert_eq!(
+            lines,
+            [
+                "Ranked 2 of the 3 examples of correr by semantic similarity",
+                "* 0.812 Corre el agua.",
+                "  0.500 Corro cada mañana. (intransitive verb)",
+                "1 examples were not above the --example-threshold",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_example_limit() {
+        let parse = |args: &[&str]| {
+            let args = ["visual_vocab", "Jane", "3"].iter().chain(args);
+            VisualVocabPipeline::parse_from(args).example_limit()
+        };
+        assert_eq!(parse(&[]), 1);
+        assert_eq!(parse(&["-e", "3"]), 3);
+        // every part of speech gets a line unless capped
+        assert_eq!(parse(&["--example-per-group"]), usize::MAX);
+        assert_eq!(parse(&["--example-per-group", "-e", "2"]), 2);
+    }
+
+    #[test]
+    fn test_checkpoint_path() {
+        let parse = |args: &[&str]| {
+            let args = ["visual_vocab", "Jane", "3"].iter().chain(args);
+            VisualVocabPipeline::parse_from(args)
+        };
+        // the progress is only saved when asked
+        assert_eq!(parse(&[]).checkpoint_path(), None);
+
+        let mut pipeline = parse(&["--resume", "-f", "unit1.docx"]);
+        pipeline.set_out_dir(Path::new("out/default"));
+        assert_eq!(
+            pipeline.checkpoint_path(),
+            Some(PathBuf::from("out/default/unit1.checkpoint.jsonl"))
+        );
+
+        let pipeline = parse(&["--checkpoint", "progress.jsonl"]);
+        assert_eq!(
+            pipeline.checkpoint_path(),
+            Some(PathBuf::from("progress.jsonl"))
+        );
+    }
+
+    #[test]
+    fn test_resize_filter() {
+        let pipeline =
+            VisualVocabPipeline::parse_from(["visual_vocab", "Jane", "3"]);
+        assert_eq!(
+            FilterType::from(pipeline.resize_filter),
+            FilterType::Lanczos3
+        );
+        let pipeline = VisualVocabPipeline::parse_from([
+            "visual_vocab",
+            "Jane",
+            "3",
+            "--resize-filter",
+            "catmull",
+        ]);
+        assert_eq!(
+            FilterType::from(pipeline.resize_filter),
+            FilterType::CatmullRom
+        );
+    }
+
+    #[test]
+    fn test_search_attempts() {
+        let pipeline =
+            VisualVocabPipeline::parse_from(["visual_vocab", "Jane", "3"]);
+        assert_eq!(pipeline.search_attempts, SearchAttempts::default());
+        let pipeline = VisualVocabPipeline::parse_from([
+            "visual_vocab",
+            "Jane",
+            "3",
+            "--attempts",
+            "3",
+            "--direct-attempts",
+            "1",
+            "--keyword-attempts",
+            "4",
+        ]);
+        assert_eq!(pipeline.attempts, 3);
+        let strategy = pipeline.search_attempts.strategy(true, true);
+        assert_eq!(strategy.direct_attempts, 1);
+        assert_eq!(strategy.keyword_attempts, 4);
+        assert!(strategy.keyword_fallback && strategy.lemmatize);
+    }
+
+    #[test]
+    fn test_parse_font_size() {
+        assert_eq!(parse_font_size("12"), Ok(12.0));
+        assert_eq!(parse_font_size("10.5"), Ok(10.5));
+        assert!(parse_font_size("2").is_err());
+        assert!(parse_font_size("100").is_err());
+        assert!(parse_font_size("NaN").is_err());
+        assert!(parse_font_size("big").is_err());
+        assert_eq!(super::super::docx::half_points(10.5), 21);
+    }
+
+    #[test]
+    fn test_parse_cell_margin() {
+        assert_eq!(parse_cell_margin("0.25"), Ok(0.25));
+        assert_eq!(parse_cell_margin("0"), Ok(0.0));
+        assert!(parse_cell_margin("-1").is_err());
+        assert!(parse_cell_margin("NaN").is_err());
+        assert!(parse_cell_margin("wide").is_err());
+    }
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!(parse_threshold("0.5"), Ok(0.5));
+        assert_eq!(parse_threshold("-1"), Ok(-1.0));
+        assert!(parse_threshold("1.5").is_err());
+        assert!(parse_threshold("NaN").is_err());
+        assert!(parse_threshold("high").is_err());
+    }
+
+    #[test]
+    fn test_parse_image_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("overrides.yml");
+        std::fs::write(
+            &path,
+            "La Casa: https://example.com/casa.png\nperro: https://example.com/perro.jpg\n",
+        )
+        .unwrap();
+        let overrides = parse_image_overrides(path.to_str().unwrap()).unwrap();
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides["la casa"], "https://example.com/casa.png");
+
+        std::fs::write(&path, "- [casa, https://example.com/casa.png]\n")
+            .unwrap();
+        assert!(parse_image_overrides(path.to_str().unwrap()).is_err());
+        assert!(parse_image_overrides("missing.yml").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_override_image() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(3, 2)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let mut server = mockito::Server::new_async().await;
+        let _png = server
+            .mock("GET", "/casa.png")
+            .with_header("content-type", "image/png")
+            .with_body(png.into_inner())
+            .create_async()
+            .await;
+
+        let url = format!("{}/casa.png", server.url());
+        let (image, source) = override_image(&url, 1024).await.unwrap();
+        assert_eq!(image.dimensions(), (3, 2));
+        assert_eq!(source.url, url);
+        let missing = format!("{}/perro.png", server.url());
+        assert!(override_image(&missing, 1024).await.is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("casa.png");
+        DynamicImage::new_rgb8(4, 2).save(&path).unwrap();
+        let path = path.to_str().unwrap();
+        let (image, source) = override_image(path, 1024).await.unwrap();
+        assert_eq!(image.dimensions(), (4, 2));
+        assert_eq!(source.url, path);
+    }
+
+    #[test]
+    fn test_export_image() {
+        let mut taken = HashSet::new();
+        let mut name = |word: &str| export_name(word, &mut taken);
+        assert_eq!(name("la canción").as_deref(), Some("la canción.png"));
+        assert_eq!(name(" y/o ").as_deref(), Some("y_o.png"));
+        assert_eq!(name("y?o").as_deref(), Some("y_o (2).png"));
+        assert_eq!(name("Y/O").as_deref(), Some("Y_O (3).png"));
+        assert_eq!(name("  "), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("y_o.png");
+        export_image(&path, &DynamicImage::new_rgb8(3, 2)).unwrap();
+        let exported = image::open(path).unwrap();
+        assert_eq!(exported.dimensions(), (3, 2));
+    }
+
+    /// Make a search result whose full image is at `src`
+    fn google_image(src: &str, width: u32, height: u32) -> GoogleImage {
+        let image = |src: &str| Image {
+            src: src.to_string(),
+            alt: String::new(),
+            width,
+            height,
+        };
+        GoogleImage {
+            thumb: image(""),
+            full: image(src),
+            title: src.to_string(),
+            url: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_first_image() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        let mut server = mockito::Server::new_async().await;
+        let _html = server
+            .mock("GET", "/page")
+            .with_header("content-type", "text/html")
+            .with_body("<html></html>")
+            .create_async()
+            .await;
+        let _png = server
+            .mock("GET", "/cat.png")
+            .with_header("content-type", "image/png")
+            .with_body(&png)
+            .create_async()
+            .await;
+        let _missing = server
+            .mock("GET", "/missing.png")
+            .with_body("not found")
+            .create_async()
+            .await;
+        let slow_png = png.clone();
+        let _slow = server
+            .mock("GET", "/slow.png")
+            .with_header("content-type", "image/png")
+            .with_chunked_body(move |w| {
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                w.write_all(&slow_png)
+            })
+            .create_async()
+            .await;
+
+        let url = |path: &str| format!("{}{}", server.url(), path);
+        let images = ["/page", "/missing.png", "/page", "/cat.png"]
+            .map(|x| google_image(&url(x), 2, 2));
+        let (image, source) =
+            first_image(&images, 1024, ImageQuality::Full, &mut HashSet::new())
+                .await
+                .unwrap();
+        assert_eq!(image.dimensions(), (2, 2));
+        assert_eq!(source.title, url("/cat.png"));
+        // the image is skipped when over the size allowed
+        assert_eq!(
+            first_image(&images, 10, ImageQuality::Full, &mut HashSet::new())
+                .await
+                .unwrap_err(),
+            4
+        );
+
+        // the better ranked image wins over a faster one
+        let images = ["/missing.png", "/slow.png", "/cat.png"]
+            .map(|x| google_image(&url(x), 2, 2));
+        let (_, source) =
+            first_image(&images, 1024, ImageQuality::Full, &mut HashSet::new())
+                .await
+                .unwrap();
+        assert_eq!(source.title, url("/slow.png"));
+
+        let images =
+            ["/page", "/missing.png"].map(|x| google_image(&url(x), 2, 2));
+        assert_eq!(
+            first_image(&images, 1024, ImageQuality::Full, &mut HashSet::new())
+                .await
+                .unwrap_err(),
+            2
+        );
+        assert_eq!(
+            first_image(&[], 1024, ImageQuality::Full, &mut HashSet::new())
+                .await
+                .unwrap_err(),
+            0
+        );
+
+        // the thumbnail stands in for a dead full size image
+        let mut image = google_image(&url("/missing.png"), 2, 2);
+        image.thumb.src = url("/cat.png");
+        let images = [image];
+        for (quality, ok) in [
+            (ImageQuality::Full, false),
+            (ImageQuality::Thumb, true),
+            (ImageQuality::Auto, true),
+        ] {
+            let result =
+                first_image(&images, 1024, quality, &mut HashSet::new()).await;
+            assert_eq!(result.is_ok(), ok, "{:?}", quality);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_first_image_retry() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let mut server = mockito::Server::new_async().await;
+        let missing = server
+            .mock("GET", "/missing.png")
+            .with_body("not found")
+            .expect(1)
+            .create_async()
+            .await;
+        let _png = server
+            .mock("GET", "/cat.png")
+            .with_header("content-type", "image/png")
+            .with_body(png.into_inner())
+            .create_async()
+            .await;
+
+        let url = |path: &str| format!("{}{}", server.url(), path);
+        let mut failed = HashSet::new();
+        let images = [google_image(&url("/missing.png"), 2, 2)];
+        let result =
+            first_image(&images, 1024, ImageQuality::Full, &mut failed).await;
+        assert_eq!(result.unwrap_err(), 1);
+        assert!(failed.contains(&url("/missing.png")));
+
+        // the retry searches a larger pool, skipping the image that failed
+        let images =
+            ["/missing.png", "/cat.png"].map(|x| google_image(&url(x), 2, 2));
+        let (_, source) =
+            first_image(&images, 1024, ImageQuality::Full, &mut failed)
+                .await
+                .unwrap();
+        assert_eq!(source.title, url("/cat.png"));
+        missing.assert_async().await;
+        // nothing is left to try once every image failed
+        let images = [google_image(&url("/missing.png"), 2, 2)];
+        let result =
+            first_image(&images, 1024, ImageQuality::Full, &mut failed).await;
+        assert_eq!(result.unwrap_err(), 0);
+    }
+
+    #[test]
+    fn test_order_images() {
+        let image = |width, height| {
+            google_image(&format!("{}x{}", width, height), width, height)
+        };
+        let mut images = vec![
+            image(640, 480),
+            image(6000, 4000),
+            image(0, 0),
+            image(1600, 1200),
+            image(5000, 3000),
+        ];
+        order_images(
+            &mut images,
+            ImageStrategy::BestByResolution,
+            4000,
+            &mut StdRng::seed_from_u64(0),
+        );
+        let order = images
+            .iter()
+            .map(|x| x.full.src.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            ["1600x1200", "640x480", "0x0", "5000x3000", "6000x4000"]
+        );
+
+        order_images(
+            &mut images,
+            ImageStrategy::Random,
+            4000,
+            &mut StdRng::seed_from_u64(0),
+        );
+        assert_eq!(images.len(), 5);
+    }
+
+    #[test]
+    fn test_pick_words() {
+        let flashcards = (0..20)
+            .map(|i| Flashcard {
+                word: i.to_string(),
+                definition: String::new(),
+                tags: vec![format!("unit {}", i % 2)],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let words = |pipeline: &VisualVocabPipeline, flashcards| {
+            pipeline
+                .pick_words(flashcards)
+                .unwrap()
+                .into_iter()
+                .map(|x| x.word)
+                .collect::<Vec<_>>()
+        };
+
+        let mut pipeline = VisualVocabPipeline::new(2, 3, "Jane", "3", "a");
+        pipeline.seed = Some(42);
+        let picked = words(&pipeline, &flashcards);
+        assert_eq!(picked.len(), 6);
+        assert_eq!(picked.iter().collect::<HashSet<_>>().len(), 6);
+        assert_eq!(words(&pipeline, &flashcards), picked);
+
+        assert_eq!(words(&pipeline, &flashcards[..4]).len(), 4);
+        assert!(words(&pipeline, &[]).is_empty());
+
+        // only the tagged words are picked
+        pipeline.tag = Some("Unit 1".to_string());
+        let picked = words(&pipeline, &flashcards);
+        assert_eq!(picked.len(), 6);
+        assert!(picked.iter().all(|x| x.parse::<u32>().unwrap() % 2 == 1));
+
+        // the first words are taken in order without shuffling
+        pipeline.seed = None;
+        pipeline.no_shuffle = true;
+        assert_eq!(
+            words(&pipeline, &flashcards),
+            ["1", "3", "5", "7", "9", "11"]
+        );
+        pipeline.tag = None;
+        assert_eq!(words(&pipeline, &flashcards[..4]), ["0", "1", "2", "3"]);
+        assert!(VisualVocabPipeline::try_parse_from([
+            "visual_vocab",
+            "Jane",
+            "3",
+            "--no-shuffle",
+            "--seed",
+            "1"
+        ])
+        .is_err());
+    }
 }
@@ -1,18 +1,37 @@
 use core::fmt;
 
-use image::DynamicImage;
+use std::io::Cursor;
+
+use image::{
+    codecs::{gif::GifDecoder, webp::WebPDecoder},
+    error::{ParameterError, ParameterErrorKind},
+    AnimationDecoder, DynamicImage, Frames, ImageError, ImageFormat,
+    ImageResult,
+};
 use log::{debug, warn};
 use once_cell::sync::Lazy;
+use reqwest::header::CONTENT_TYPE;
 use scraper::{Html, Selector};
 use url::form_urlencoded;
 
-use super::{SpiderError, CLIENT};
+use super::{send_with_retry, SpiderError, SpiderErrorKind, CLIENT};
+
+/// The address of google
+const GOOGLE_URL: &str = "https://www.google.com";
+
+/// The number of times a search is sent while google is rate limiting or
+/// failing
+const SEARCH_ATTEMPTS: u32 = 3;
 
 /// Represents an image
 #[derive(Debug)]
 pub struct Image {
     pub src: String,
     pub alt: String,
+    /// The width reported by the search results, 0 if unknown
+    pub width: u32,
+    /// The height reported by the search results, 0 if unknown
+    pub height: u32,
 }
 
 impl fmt::Display for Image {
@@ -37,32 +56,185 @@ impl fmt::Display for GoogleImage {
 }
 
 impl Image {
-    /// Get the bytes of an image
-    pub async fn get_image(
+    /// Get the bytes of an image, along with the content type declared by the
+    /// server. Images larger than `max_bytes` are skipped, by their declared
+    /// length if any, or else as soon as the download exceeds it.
+    pub async fn get_bytes(
         &self,
-    ) -> Result<DynamicImage, Box<dyn std::error::Error>> {
-        let resp = CLIENT.get(&self.src).send().await.map_err(|e| {
-            SpiderError::new(&format!(
-                "failed to send response for image: {} because\n{}",
-                self, e
-            ))
+        max_bytes: u64,
+    ) -> Result<(Vec<u8>, Option<String>), SpiderError> {
+        let mut resp = CLIENT.get(&self.src).send().await.map_err(|e| {
+            SpiderError::with_kind(
+                SpiderErrorKind::Network,
+                &format!(
+                    "failed to send response for image: {} because\n{}",
+                    self, e
+                ),
+            )
         })?;
-        let bytes = resp.bytes().await.map_err(|e| {
-            SpiderError::new(&format!(
-                "failed to get bytes for image: {} because\n{}",
-                self, e
-            ))
-        })?;
-        let image = image::load_from_memory(&bytes).map_err(|e| {
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .map(str::to_string);
+        if let Some(content_type) = &content_type {
+            check_content_type(content_type).map_err(|e| {
+                SpiderError::with_kind(
+                    SpiderErrorKind::ContentType,
+                    &format!("skipped image: {} because\n{}", self, e),
+                )
+            })?;
+        }
+        let too_large = |length: u64| {
+            SpiderError::with_kind(
+                SpiderErrorKind::TooLarge,
+                &format!(
+                    "skipped image: {} because\nit is over {} bytes ({})",
+                    self, max_bytes, length
+                ),
+            )
+        };
+        if let Some(length) = resp.content_length() {
+            if length > max_bytes {
+                return Err(too_large(length));
+            }
+        }
+        let mut bytes = Vec::new();
+        while let Some(chunk) = resp.chunk().await.map_err(|e| {
+            SpiderError::with_kind(
+                SpiderErrorKind::Network,
+                &format!(
+                    "failed to get bytes for image: {} because\n{}",
+                    self, e
+                ),
+            )
+        })? {
+            bytes.extend_from_slice(&chunk);
+            if bytes.len() as u64 > max_bytes {
+                return Err(too_large(bytes.len() as u64));
+            }
+        }
+        Ok((bytes, content_type))
+    }
+
+    /// Get the decoded image, if not larger than `max_bytes`
+    pub async fn get_image(
+        &self,
+        max_bytes: u64,
+    ) -> Result<DynamicImage, SpiderError> {
+        let (bytes, content_type) = self.get_bytes(max_bytes).await?;
+        decode_image(&bytes, content_type.as_deref()).map_err(|e| {
             SpiderError::new(&format!(
                 "failed to parse image: {} because\n{}",
                 self, e
             ))
-        })?;
-        Ok(image)
+        })
+    }
+}
+
+impl GoogleImage {
+    /// Get the decoded full size image, if not larger than `max_bytes`
+    pub async fn get_image(
+        &self,
+        max_bytes: u64,
+    ) -> Result<DynamicImage, SpiderError> {
+        self.full.get_image(max_bytes).await
+    }
+}
+
+/// Check that a content type is an image that can be decoded, so that HTML
+/// interstitials and unsupported formats are not downloaded
+fn check_content_type(content_type: &str) -> Result<(), String> {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    if !mime.starts_with("image/") {
+        return Err(format!("{} is not an image", mime));
+    }
+    let readable = match ImageFormat::from_mime_type(&mime) {
+        Some(format) => format.can_read(),
+        None => mime != "image/svg+xml",
+    };
+    if !readable {
+        return Err(format!("{} is not a supported image format", mime));
+    }
+    Ok(())
+}
+
+/// Decode an image, falling back to the format of the content type when it
+/// cannot be guessed from the bytes. Animated WebPs and the GIFs that cannot
+/// be decoded as a still image are decoded as animations, keeping their
+/// first frame. Unsupported formats (e.g. SVG) and truncated images are
+/// errors.
+fn decode_image(
+    bytes: &[u8],
+    content_type: Option<&str>,
+) -> ImageResult<DynamicImage> {
+    let format = image::guess_format(bytes).ok().or_else(|| {
+        let mime = content_type?.split(';').next()?.trim();
+        ImageFormat::from_mime_type(mime)
+    });
+    match format {
+        Some(ImageFormat::Gif) => {
+            image::load_from_memory_with_format(bytes, ImageFormat::Gif)
+                .or_else(|err| {
+                    debug!(target: "image_search", "Decoding the first frame of an animation: {}", err);
+                    decode_first_frame(bytes, ImageFormat::Gif)
+                        .map_err(|_| err)
+                })
+        }
+        Some(ImageFormat::WebP) => {
+            // the still decoder takes the first frame of an animation as
+            // is, leaving the rest of the canvas blank
+            let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+            if decoder.has_animation() {
+                debug!(target: "image_search", "Decoding the first frame of an animated WebP");
+                first_frame(decoder.into_frames())
+            } else {
+                DynamicImage::from_decoder(decoder)
+            }
+        }
+        Some(format) => image::load_from_memory_with_format(bytes, format),
+        None => image::load_from_memory(bytes),
     }
 }
 
+/// Decode the first frame of an animated GIF or WebP, composed onto the
+/// canvas of the animation
+fn decode_first_frame(
+    bytes: &[u8],
+    format: ImageFormat,
+) -> ImageResult<DynamicImage> {
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+        ImageFormat::WebP => {
+            WebPDecoder::new(Cursor::new(bytes))?.into_frames()
+        }
+        _ => {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::Generic(format!(
+                    "{:?} is not an animation format",
+                    format
+                )),
+            )))
+        }
+    };
+    first_frame(frames)
+}
+
+/// Take the first of the frames of an animation
+fn first_frame(mut frames: Frames) -> ImageResult<DynamicImage> {
+    let frame = frames.next().ok_or_else(|| {
+        ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::NoMoreData,
+        ))
+    })??;
+    Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+}
+
 /**
 `parse_google_image` accept a json format that is returned by
 parsing json5 from a sfipt element on google image search results page.
@@ -99,18 +271,21 @@ fn parse_google_image(x: &serde_json::Value) -> Option<GoogleImage> {
     let url = src[2].as_str()?.to_string();
     let title = src[3].as_str()?.to_string();
 
+    let dimension =
+        |x: &serde_json::Value| x.as_u64().unwrap_or_default() as u32;
+
     let thumb = Image {
         src: l[1][2][0].as_str()?.to_string(),
         alt: title.clone(),
-        // width: l[1][2][1].as_u64()? as u32,
-        // height: l[1][2][2].as_u64()? as u32,
+        width: dimension(&l[1][2][1]),
+        height: dimension(&l[1][2][2]),
     };
 
     let full = Image {
         src: l[1][3][0].as_str()?.to_string(),
         alt: title.clone(),
-        // width: l[1][3][1].as_u64()? as u32,
-        // height: l[1][3][2].as_u64()? as u32,
+        width: dimension(&l[1][3][1]),
+        height: dimension(&l[1][3][2]),
     };
 
     Some(GoogleImage {
@@ -127,6 +302,15 @@ fn parse_google_image(x: &serde_json::Value) -> Option<GoogleImage> {
 pub async fn image_search(
     query: &str,
     offset: u32,
+) -> Result<Vec<GoogleImage>, Box<dyn std::error::Error>> {
+    image_search_inner(GOOGLE_URL, query, offset).await
+}
+
+/// Search for images on the google site at `base_url`
+async fn image_search_inner(
+    base_url: &str,
+    query: &str,
+    offset: u32,
 ) -> Result<Vec<GoogleImage>, Box<dyn std::error::Error>> {
     for _ in 0..5 {
         let params = form_urlencoded::Serializer::new(String::new())
@@ -135,11 +319,12 @@ pub async fn image_search(
             .append_pair("start", &offset.to_string())
             .append_pair("ijn", &(offset / 100).to_string())
             .finish();
-        let url = format!("https://www.google.com/search?{}", params);
+        let url = format!("{}/search?{}", base_url, params);
         debug!(target: "image_search", "url: {}", url);
-        let dom = Html::parse_document(
-            &CLIENT.get(&url).send().await.unwrap().text().await.unwrap(),
-        );
+        let response = send_with_retry(CLIENT.get(&url), SEARCH_ATTEMPTS)
+            .await?
+            .error_for_status()?;
+        let dom = Html::parse_document(&response.text().await?);
         let script_selector = Lazy::new(|| Selector::parse("script").unwrap());
         let json = dom
             .select(&script_selector)
@@ -172,10 +357,10 @@ pub async fn image_search(
             return Ok(images);
         }
     }
-    Err(Box::new(SpiderError::new(&format!(
-        "failed to get images for query: {}",
-        query
-    ))))?
+    Err(Box::new(SpiderError::with_kind(
+        SpiderErrorKind::NotFound,
+        &format!("failed to get images for query: {}", query),
+    )))?
 }
 
 /**
@@ -214,4 +399,131 @@ mod test {
         assert!(!result.is_empty());
         dbg!(result);
     }
+
+    #[tokio::test]
+    async fn test_search_fixture() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/search")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("tbm".into(), "isch".into()),
+                mockito::Matcher::UrlEncoded("q".into(), "cat".into()),
+            ]))
+            .with_body(include_str!("fixtures/google_image_cat.html"))
+            .create_async()
+            .await;
+
+        let result = image_search_inner(&server.url(), "cat", 0).await.unwrap();
+        mock.assert_async().await;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].title, "A cat on a sofa");
+        assert_eq!(result[0].url, "https://example.com/cat-on-sofa");
+        assert_eq!(result[0].thumb.src, "https://example.com/thumb/1.jpg");
+        assert_eq!(result[0].full.src, "https://example.com/full/1.jpg");
+        assert_eq!((result[0].full.width, result[0].full.height), (1600, 1200));
+        assert_eq!(result[1].title, "Kitten");
+    }
+
+    #[tokio::test]
+    async fn test_get_bytes_max() {
+        let mut server = mockito::Server::new_async().await;
+        let _sized = server
+            .mock("GET", "/sized.png")
+            .with_header("content-type", "image/png")
+            .with_body(vec![0; 100])
+            .create_async()
+            .await;
+        let _chunked = server
+            .mock("GET", "/chunked.png")
+            .with_header("content-type", "image/png")
+            .with_chunked_body(|w| w.write_all(&[0; 100]))
+            .create_async()
+            .await;
+
+        for path in ["/sized.png", "/chunked.png"] {
+            let image = Image {
+                src: format!("{}{}", server.url(), path),
+                alt: String::new(),
+                width: 0,
+                height: 0,
+            };
+            let (bytes, _) = image.get_bytes(100).await.unwrap();
+            assert_eq!(bytes.len(), 100);
+            let err = image.get_bytes(99).await.unwrap_err();
+            assert_eq!(err.kind(), SpiderErrorKind::TooLarge);
+        }
+    }
+
+    #[test]
+    fn test_check_content_type() {
+        assert!(check_content_type("image/jpeg").is_ok());
+        assert!(check_content_type("image/webp; charset=binary").is_ok());
+        assert!(check_content_type("text/html; charset=UTF-8").is_err());
+        assert!(check_content_type("image/svg+xml").is_err());
+    }
+
+    #[test]
+    fn test_decode_image() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        assert!(decode_image(&png, None).is_ok());
+        assert!(decode_image(&png, Some("image/png")).is_ok());
+        assert!(decode_image(&png[..png.len() / 2], None).is_err());
+
+        let svg = b"<svg xmlns='http://www.w3.org/2000/svg'></svg>";
+        assert!(decode_image(svg, Some("image/svg+xml")).is_err());
+    }
+
+    #[test]
+    fn test_decode_animation() {
+        use image::{codecs::gif::GifEncoder, Delay, Frame, Rgba, RgbaImage};
+
+        let frame = |color: [u8; 4]| {
+            Frame::from_parts(
+                RgbaImage::from_pixel(3, 2, Rgba(color)),
+                0,
+                0,
+                Delay::from_numer_denom_ms(100, 1),
+            )
+        };
+        let mut gif = Vec::new();
+        GifEncoder::new(&mut gif)
+            .encode_frames([frame([255, 0, 0, 255]), frame([0, 0, 255, 255])])
+            .unwrap();
+
+        for image in [
+            decode_image(&gif, Some("image/gif")).unwrap(),
+            decode_first_frame(&gif, ImageFormat::Gif).unwrap(),
+        ] {
+            let image = image.into_rgba8();
+            assert_eq!(image.dimensions(), (3, 2));
+            assert_eq!(image.get_pixel(1, 1), &Rgba([255, 0, 0, 255]));
+        }
+        assert!(decode_first_frame(&gif, ImageFormat::Png).is_err());
+    }
+
+    #[test]
+    fn test_decode_animated_webp() {
+        use image::Rgba;
+
+        // a 3x2 canvas on a blue background, with a red pixel drawn at the
+        // top left in the first frame and a green one in the second
+        let webp = include_bytes!("fixtures/animated.webp");
+        let still =
+            image::load_from_memory_with_format(webp, ImageFormat::WebP)
+                .unwrap()
+                .into_rgba8();
+        // the still decoder leaves out the background
+        assert_eq!(still.get_pixel(1, 1), &Rgba([0, 0, 0, 0]));
+
+        let image = decode_image(webp, Some("image/webp")).unwrap();
+        let image = image.into_rgba8();
+        assert_eq!(image.dimensions(), (3, 2));
+        assert_eq!(image.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(1, 1), &Rgba([0, 0, 255, 255]));
+        assert_eq!(image.get_pixel(2, 0), &Rgba([0, 0, 255, 255]));
+    }
 }
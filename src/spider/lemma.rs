@@ -0,0 +1,132 @@
+//! A small table of rules guessing the dictionary form of inflected Spanish
+//! words, e.g. "corriendo" to "correr" or "casas" to "casa".
+
+/// The endings of conjugated verbs and the endings of the infinitives they
+/// may come from, longest ending first. Only the longest ending of a word is
+/// used, so that e.g. "comió" is taken for a form of an -er or -ir verb and
+/// not also of "comiar" by its last letter.
+const VERB_RULES: [(&str, &[&str]); 27] = [
+    ("ábamos", &["ar"]),
+    ("íamos", &["er", "ir"]),
+    ("aron", &["ar"]),
+    ("ieron", &["er", "ir"]),
+    ("ando", &["ar"]),
+    ("iendo", &["er", "ir"]),
+    ("yendo", &["er", "ir"]),
+    ("abas", &["ar"]),
+    ("aban", &["ar"]),
+    ("ados", &["ar"]),
+    ("adas", &["ar"]),
+    ("idos", &["er", "ir"]),
+    ("idas", &["er", "ir"]),
+    ("aste", &["ar"]),
+    ("iste", &["er", "ir"]),
+    ("aba", &["ar"]),
+    ("ado", &["ar"]),
+    ("ada", &["ar"]),
+    ("ido", &["er", "ir"]),
+    ("ida", &["er", "ir"]),
+    ("ías", &["er", "ir"]),
+    ("ían", &["er", "ir"]),
+    ("ría", &["r"]),
+    ("rán", &["r"]),
+    ("ió", &["er", "ir"]),
+    ("ía", &["er", "ir"]),
+    ("ó", &["ar"]),
+];
+
+/// The plural endings of nouns and adjectives, and whether the singular they
+/// come from ends in a vowel, e.g. "casa-s" but "ciudad-es"
+const PLURAL_RULES: [(&str, bool); 2] = [("es", false), ("s", true)];
+
+/// The endings of plural participles, which are also the plurals of
+/// adjectives and nouns, e.g. "comidas" of "comida". They are the only verb
+/// forms also tried with the plural rules.
+const PARTICIPLE_PLURALS: [&str; 4] = ["ados", "adas", "idos", "idas"];
+
+/// The shortest stem left after removing an ending, so that short words such
+/// as "es" or "ida" are not reduced to nothing
+const MIN_STEM_LEN: usize = 2;
+
+/// Guess the possible dictionary forms of an inflected word, most likely
+/// first. The word itself is never included.
+pub fn lemmas(word: &str) -> Vec<String> {
+    let word = word.trim().to_lowercase();
+    let mut lemmas: Vec<String> = vec![];
+    let mut push = |lemma: String| {
+        if lemma != word && !lemmas.contains(&lemma) {
+            lemmas.push(lemma);
+        }
+    };
+
+    let verb = VERB_RULES.iter().find_map(|(ending, replacements)| {
+        let stem = word.strip_suffix(ending)?;
+        (stem.chars().count() >= MIN_STEM_LEN).then_some((
+            *ending,
+            stem,
+            *replacements,
+        ))
+    });
+    if let Some((_, stem, replacements)) = verb {
+        for replacement in replacements {
+            push(format!("{}{}", stem, replacement));
+        }
+    }
+    let plural = match verb {
+        Some((ending, ..)) => PARTICIPLE_PLURALS.contains(&ending),
+        None => true,
+    };
+    if plural {
+        for (ending, vowel) in PLURAL_RULES {
+            let Some(stem) = word.strip_suffix(ending) else {
+                continue;
+            };
+            if stem.chars().count() < MIN_STEM_LEN
+                || stem.chars().last().is_some_and(is_vowel) != vowel
+            {
+                continue;
+            }
+            push(stem.to_string());
+        }
+    }
+    lemmas
+}
+
+/// Check whether a letter is a vowel, accented or not
+fn is_vowel(c: char) -> bool {
+    "aeiouáéíóú".contains(c)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lemmas() {
+        assert_eq!(lemmas("corriendo"), ["correr", "corrir"]);
+        assert_eq!(lemmas("Hablaron"), ["hablar"]);
+        assert_eq!(lemmas("hablábamos"), ["hablar"]);
+        assert_eq!(lemmas("comió"), ["comer", "comir"]);
+        assert_eq!(lemmas("comería"), ["comer"]);
+        assert_eq!(lemmas("comidas"), ["comer", "comir", "comida"]);
+        assert_eq!(lemmas("casas"), ["casa"]);
+        assert_eq!(lemmas("ciudades"), ["ciudad", "ciudade"]);
+        assert_eq!(lemmas("leyes"), ["ley", "leye"]);
+        assert!(lemmas("luz").is_empty());
+        assert!(lemmas("es").is_empty());
+    }
+
+    #[test]
+    fn test_lemmas_reject() {
+        let rejects =
+            |word: &str, guess: &str| !lemmas(word).iter().any(|x| x == guess);
+        // a shorter ending of another verb class
+        assert!(rejects("comió", "comiar"));
+        assert!(rejects("comería", "comeer"));
+        // a conjugated verb taken for a plural
+        assert!(rejects("hablábamos", "hablábamo"));
+        assert!(rejects("comías", "comía"));
+        // a plural ending after the wrong kind of letter
+        assert!(rejects("bíceps", "bícep"));
+    }
+}
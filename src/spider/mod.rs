@@ -1,8 +1,12 @@
 pub mod google_image;
+pub mod lemma;
 pub mod spanish_dict;
 
-use log::info;
+use std::time::Duration;
+
+use log::{info, warn};
 use once_cell::sync::Lazy;
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
 
 /// The user agent used for all requests
 const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/113.0.0.0 Safari/537.36 Edg/113.0.1774.42";
@@ -20,19 +24,131 @@ pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
         .expect("should be able to create client")
 });
 
+/// How to get the machine learning models running, appended to the errors
+/// of the models
+pub const MODELS_HELP: &str = "the machine learning models need libtorch, \
+    see https://github.com/LaurentMazare/tch-rs#getting-started to install \
+    it, or pass --no-ml to the pipelines to skip the models";
+
+/// The delay before the first retry of `send_with_retry`, doubled on every
+/// subsequent attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// The delay before the first retry of `send_with_retry` when rate limited
+/// without a `Retry-After` header, doubled on every subsequent attempt
+const RATE_LIMIT_BASE_DELAY: Duration = Duration::from_secs(5);
+
+/// The longest `Retry-After` honored, so that a server cannot stall a run
+const RATE_LIMIT_MAX_DELAY: Duration = Duration::from_secs(120);
+
+/// Return the delay asked for by the `Retry-After` header of a response, in
+/// seconds, capped at `RATE_LIMIT_MAX_DELAY`
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(Duration::from_secs(seconds).min(RATE_LIMIT_MAX_DELAY))
+}
+
+/// Send a request, retrying up to `attempts` times with exponential backoff
+/// while the server is rate limiting (429) or failing (5xx). Rate limiting
+/// backs off longer, honoring the `Retry-After` header if present. The last
+/// response is returned as is once the attempts run out.
+pub async fn send_with_retry(
+    request: RequestBuilder,
+    attempts: u32,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let mut delay = RETRY_BASE_DELAY;
+    let mut rate_limit_delay = RATE_LIMIT_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        let response = request
+            .try_clone()
+            .ok_or_else(|| SpiderError::new("request cannot be retried"))?
+            .send()
+            .await?;
+        let status = response.status();
+        if attempt >= attempts
+            || !(status == StatusCode::TOO_MANY_REQUESTS
+                || status.is_server_error())
+        {
+            return Ok(response);
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let wait = retry_after(&response).unwrap_or(rate_limit_delay);
+            warn!(target: "retry", "Rate limited by {}, slowing down and retrying in {:?}", response.url().host_str().unwrap_or_default(), wait);
+            tokio::time::sleep(wait).await;
+            rate_limit_delay *= 2;
+        } else {
+            warn!(target: "retry", "{} returned {}, retrying in {:?}", response.url(), status, delay);
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+        attempt += 1;
+    }
+}
+
+/// The kind of a spider error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpiderErrorKind {
+    /// The response is not of a content type that can be handled
+    ContentType,
+    /// The response is larger than the size allowed
+    TooLarge,
+    /// A machine learning model failed to load
+    Model,
+    /// The word or the images searched for were not found
+    NotFound,
+    /// A request failed to be sent or its response to be read
+    Network,
+    /// Any other failure
+    Other,
+}
+
 /// Spider error
 #[derive(Debug)]
 pub struct SpiderError {
+    kind: SpiderErrorKind,
     message: String,
 }
 
 impl SpiderError {
     /// Create a new spider error
     pub fn new(message: &str) -> Self {
+        Self::with_kind(SpiderErrorKind::Other, message)
+    }
+
+    /// Create a new spider error of the given kind
+    pub fn with_kind(kind: SpiderErrorKind, message: &str) -> Self {
         Self {
+            kind,
             message: message.to_string(),
         }
     }
+
+    /// Return the kind of the error
+    pub fn kind(&self) -> SpiderErrorKind {
+        self.kind
+    }
+
+    /// Flatten an error into a spider error that can be sent between tasks,
+    /// keeping its kind if it is a spider error or a failed request
+    pub fn flatten(err: &(dyn std::error::Error + 'static)) -> Self {
+        if let Some(err) = err.downcast_ref::<SpiderError>() {
+            return Self::with_kind(err.kind, &err.message);
+        }
+        let kind = if err.is::<reqwest::Error>() {
+            SpiderErrorKind::Network
+        } else {
+            SpiderErrorKind::Other
+        };
+        Self::with_kind(kind, &err.to_string())
+    }
 }
 
 impl std::fmt::Display for SpiderError {
@@ -42,3 +158,43 @@ impl std::fmt::Display for SpiderError {
 }
 
 impl std::error::Error for SpiderError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flatten() {
+        let err: Box<dyn std::error::Error> = Box::new(SpiderError::with_kind(
+            SpiderErrorKind::NotFound,
+            "no such word",
+        ));
+        let flat = SpiderError::flatten(err.as_ref());
+        assert_eq!(flat.kind(), SpiderErrorKind::NotFound);
+        assert_eq!(flat.message, "no such word");
+        let flat = SpiderError::flatten(&std::fmt::Error);
+        assert_eq!(flat.kind(), SpiderErrorKind::Other);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_retry_after() {
+        let mut server = mockito::Server::new_async().await;
+        let limited = server
+            .mock("GET", "/search")
+            .with_status(429)
+            .with_header("retry-after", "0")
+            .expect(1)
+            .create_async()
+            .await;
+        let ok = server
+            .mock("GET", "/search")
+            .with_body("ok")
+            .create_async()
+            .await;
+        let request = CLIENT.get(format!("{}/search", server.url()));
+        let response = send_with_retry(request, 3).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        limited.assert_async().await;
+        ok.assert_async().await;
+    }
+}
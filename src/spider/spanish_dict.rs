@@ -1,284 +1,586 @@
-use ego_tree::NodeRef;
-use html5ever::tree_builder::QuirksMode;
-use log::{debug, info};
+use futures::{stream, StreamExt};
+use log::{debug, info, warn};
 use once_cell::sync::Lazy;
 use rust_bert::pipelines::keywords_extraction::KeywordExtractionModel;
-use scraper::{node::Node, ElementRef, Html, Selector};
-use tokio::{
-    sync::{Mutex, OnceCell},
-    task,
-};
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OnceCell};
 use url::form_urlencoded;
 
-use super::{SpiderError, CLIENT};
+use super::{lemma::lemmas, SpiderError, SpiderErrorKind, CLIENT, MODELS_HELP};
+use crate::pipeline::RunContext;
 
-/// Represents an example of a word in a dictionary
-#[derive(Debug)]
+/// Represents an example of a word in a dictionary. The variants with more
+/// fields come first, for deserializing to pick the most specific one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
 pub enum DictionaryExample {
-    Example {
-        example: String,
-    },
     ExampleAndTranslation {
         example: String,
         translation: String,
     },
+    Example {
+        example: String,
+    },
 }
 
-/// Represents a definition of a word in a dictionary
-#[derive(Debug)]
+/// Represents a definition of a word in a dictionary. The variants with
+/// more fields come first, for deserializing to pick the most specific one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
 pub enum DictionaryDefinition {
-    Definition {
+    DefinitionAndGroupWithExample {
+        group: String,
         definition: String,
+        examples: Vec<DictionaryExample>,
     },
     DefinitionAndGroup {
         group: String,
         definition: String,
     },
-    DefinitionAndGroupWithExample {
-        group: String,
+    Definition {
         definition: String,
-        examples: Vec<DictionaryExample>,
     },
 }
 
 /// Represents a word in a dictionary
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DictionaryEntry {
     pub word: String,
     pub definitions: Vec<DictionaryDefinition>,
+    /// The headword that the definitions are of, when the word itself was
+    /// not found, suggested by SpanishDict or guessed by lemmatization
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correction: Option<String>,
 }
 
-const LANG_EN: &str = "en";
-const LANG_ES: &str = "es";
+impl std::fmt::Display for DictionaryExample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DictionaryExample::Example { example } => write!(f, "{}", example),
+            DictionaryExample::ExampleAndTranslation {
+                example,
+                translation,
+            } => write!(f, "{} — {}", example, translation),
+        }
+    }
+}
+
+impl std::fmt::Display for DictionaryDefinition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DictionaryDefinition::Definition { definition } => {
+                write!(f, "{}", definition)
+            }
+            DictionaryDefinition::DefinitionAndGroup { group, definition } => {
+                write!(f, "({}) {}", group, definition)
+            }
+            DictionaryDefinition::DefinitionAndGroupWithExample {
+                group,
+                definition,
+                examples,
+            } => {
+                write!(f, "({}) {}", group, definition)?;
+                for example in examples {
+                    write!(f, "\n    {}", example)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
-static KEYWORD_MODEL: OnceCell<Mutex<KeywordExtractionModel>> =
+impl std::fmt::Display for DictionaryEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.word)?;
+        for definition in &self.definitions {
+            write!(f, "\n  {}", definition)?;
+        }
+        Ok(())
+    }
+}
+
+/// The address of SpanishDict.com
+const SPANISH_DICT_URL: &str = "https://www.spanishdict.com";
+
+/// Represents the language of the words searched and the language of their
+/// definitions, as the `lang` codes of the dictionary pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LanguagePair {
+    pub source: &'static str,
+    pub target: &'static str,
+}
+
+impl LanguagePair {
+    /// Spanish words defined in English, which SpanishDict is made for
+    pub const SPANISH_ENGLISH: Self = Self {
+        source: "es",
+        target: "en",
+    };
+}
+
+impl Default for LanguagePair {
+    fn default() -> Self {
+        Self::SPANISH_ENGLISH
+    }
+}
+
+static KEYWORD_MODEL: OnceCell<Result<Mutex<KeywordExtractionModel>, String>> =
     OnceCell::const_new();
 
-/**
-Perform a search of a word in SpanishDict.com
- */
-pub async fn search_vocab(
-    word: &str,
-) -> Result<DictionaryEntry, Box<dyn std::error::Error>> {
-    let _lock = KEYWORD_MODEL
+/// Load the keyword model ahead of the lookups, failing with guidance when
+/// it cannot run rather than on every word of a run
+pub async fn load_keyword_model() -> Result<(), SpiderError> {
+    keyword_model().await.map(|_| ())
+}
+
+/// Return the keyword model, loading it on first use. A model that failed
+/// to load is not retried.
+async fn keyword_model(
+) -> Result<&'static Mutex<KeywordExtractionModel<'static>>, SpiderError> {
+    KEYWORD_MODEL
         .get_or_init(|| async {
-            task::spawn_blocking(move || {
+            RunContext::spawn_blocking(move || {
                 info!(target: "spanish_dict", "Loading keyword model");
-                let model = KeywordExtractionModel::new(Default::default())
-                    .expect("should be able to load keyword model");
-                Mutex::new(model)
+                KeywordExtractionModel::new(Default::default())
+                    .map(Mutex::new)
+                    .map_err(|e| e.to_string())
             })
             .await
-            .expect("should be able to get model")
+            .map_err(|e| e.to_string())
+            .and_then(|x| x)
         })
         .await
-        .lock();
+        .as_ref()
+        .map_err(|e| {
+            SpiderError::with_kind(
+                SpiderErrorKind::Model,
+                &format!(
+                    "failed to load the keyword model: {}\n{}",
+                    e, MODELS_HELP
+                ),
+            )
+        })
+}
+
+/// Represents how hard a word is searched for before giving up, from the
+/// cheap searches of the word itself to the keyword fallback, which loads a
+/// machine learning model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchStrategy {
+    /// The number of searches of the word itself
+    pub direct_attempts: u32,
+    /// Retry with the dictionary forms of the word
+    pub lemmatize: bool,
+    /// Retry with the keywords extracted from the word
+    pub keyword_fallback: bool,
+    /// The number of keywords extracted from the word searched, best first
+    pub keyword_attempts: u32,
+    /// Only search the offline dictionary, without any network I/O
+    pub offline: bool,
+}
+
+impl Default for SearchStrategy {
+    fn default() -> Self {
+        Self {
+            direct_attempts: 2,
+            lemmatize: false,
+            keyword_fallback: true,
+            keyword_attempts: 2,
+            offline: false,
+        }
+    }
+}
+
+impl SearchStrategy {
+    /// Create the default strategy, retrying with the keywords of the word
+    /// if `keyword_fallback` is set and with its dictionary forms if
+    /// `lemmatize` is set
+    pub fn new(keyword_fallback: bool, lemmatize: bool) -> Self {
+        Self {
+            keyword_fallback,
+            lemmatize,
+            ..Self::default()
+        }
+    }
+}
+
+/// The common words bundled for searching without network, e.g. for demos
+/// and tests
+static OFFLINE_DICTIONARY: Lazy<Vec<DictionaryEntry>> = Lazy::new(|| {
+    serde_json::from_str(include_str!("offline_dictionary.json"))
+        .expect("should be a valid offline dictionary")
+});
+
+/// Search a word in the offline dictionary, ignoring case, then its
+/// dictionary forms if the `strategy` allows
+fn search_offline(
+    word: &str,
+    strategy: SearchStrategy,
+) -> Option<DictionaryEntry> {
+    let find = |word: &str| {
+        let word = word.trim().to_lowercase();
+        OFFLINE_DICTIONARY.iter().find(|x| x.word == word)
+    };
+    if let Some(entry) = find(word) {
+        return Some(DictionaryEntry {
+            word: word.to_string(),
+            ..entry.clone()
+        });
+    }
+    if !strategy.lemmatize {
+        return None;
+    }
+    lemmas(word).into_iter().find_map(|lemma| {
+        find(&lemma).map(|entry| DictionaryEntry {
+            word: word.to_string(),
+            correction: Some(lemma),
+            ..entry.clone()
+        })
+    })
+}
+
+/**
+Perform a search of a word in SpanishDict.com. When the word itself is not
+found, the "did you mean" suggestion of SpanishDict is followed. If there is
+none, retry with the dictionary forms of the word, then with the keywords
+extracted from it, as the `strategy` allows.
+
+The bundled offline dictionary is searched instead if the `strategy` is
+offline, or if SpanishDict cannot be reached.
+ */
+pub async fn search_vocab(
+    word: &str,
+    strategy: SearchStrategy,
+) -> Result<DictionaryEntry, Box<dyn std::error::Error>> {
+    search_vocab_in(word, LanguagePair::default(), strategy).await
+}
+
+/// Search a word of another language pair than Spanish to English in
+/// SpanishDict.com as [`search_vocab`] does
+pub async fn search_vocab_in(
+    word: &str,
+    languages: LanguagePair,
+    strategy: SearchStrategy,
+) -> Result<DictionaryEntry, Box<dyn std::error::Error>> {
+    search_vocab_with(SPANISH_DICT_URL, languages, word, strategy).await
+}
 
-    let model = _lock.await;
-    for _ in 0..2 {
-        if let Ok(entry) = search_vocab_inner(word).await {
+/// Search a word in the SpanishDict site at `base_url` as [`search_vocab`]
+/// does
+async fn search_vocab_with(
+    base_url: &str,
+    languages: LanguagePair,
+    word: &str,
+    strategy: SearchStrategy,
+) -> Result<DictionaryEntry, Box<dyn std::error::Error>> {
+    // the offline dictionary only has Spanish words defined in English
+    let offline = languages == LanguagePair::SPANISH_ENGLISH;
+    if strategy.offline {
+        return match search_offline(word, strategy).filter(|_| offline) {
+            Some(entry) => Ok(entry),
+            None => Err(Box::new(SpiderError::with_kind(
+                SpiderErrorKind::NotFound,
+                &format!("{} is not in the offline dictionary", word),
+            ))),
+        };
+    }
+    let mut unreachable = strategy.direct_attempts > 0;
+    for _ in 0..strategy.direct_attempts {
+        let entry = search_vocab_inner(base_url, languages, word).await;
+        unreachable &= entry.is_err();
+        if let Ok(entry) = entry {
             if entry.definitions.is_empty() {
                 info!(target: "spanish_dict", "failed to find any definitions for word: {}", word);
             } else {
+                if let Some(correction) = &entry.correction {
+                    info!(target: "spanish_dict", "corrected {} to {}", word, correction);
+                }
                 return Ok(entry);
             }
         }
     }
-    for _ in 0..2 {
-        let prediction = model.predict(&[word])?;
-        match prediction.get(0) {
-            Some(keyword) => {
-                let keyword = match keyword.get(0) {
-                    Some(keyword) => &keyword.text,
-                    None => return Err(Box::new(SpiderError::new(&format!(
-                        "failed to retry with keyword for word: {}, no keyword found",
-                        word
-                    )))),
-                };
-                info!(target: "spanish_dict", "retry with keyword: {}", keyword);
-                if let Ok(entry) = search_vocab_inner(keyword).await {
-                    if entry.definitions.is_empty() {
-                        info!(target: "spanish_dict", "failed to find any definitions for word: {}", keyword);
-                    } else {
-                        info!(target: "spanish_dict", "found definitions for word: {}", keyword);
-                        return Ok(entry);
-                    }
+    if unreachable && offline {
+        if let Some(entry) = search_offline(word, strategy) {
+            warn!(target: "spanish_dict", "SpanishDict is unreachable, using the offline dictionary for {}", word);
+            return Ok(entry);
+        }
+    }
+    // the dictionary forms are only guessed for Spanish words
+    if strategy.lemmatize
+        && languages.source == LanguagePair::SPANISH_ENGLISH.source
+    {
+        for lemma in lemmas(word) {
+            debug!(target: "spanish_dict", "retry with lemma: {}", lemma);
+            if let Ok(entry) =
+                search_vocab_inner(base_url, languages, &lemma).await
+            {
+                if !entry.definitions.is_empty() {
+                    info!(target: "spanish_dict", "using lemma {} for {}", lemma, word);
+                    return Ok(DictionaryEntry {
+                        word: word.to_string(),
+                        correction: Some(lemma),
+                        ..entry
+                    });
                 }
             }
-            None => {
-                return Err(Box::new(SpiderError::new(&format!(
+        }
+    }
+    // a word is only missing if SpanishDict could be asked for it
+    let kind = if unreachable {
+        SpiderErrorKind::Network
+    } else {
+        SpiderErrorKind::NotFound
+    };
+    if !strategy.keyword_fallback || strategy.keyword_attempts == 0 {
+        return Err(Box::new(SpiderError::with_kind(
+            kind,
+            &format!(
+                "failed to search for word: {}, keyword fallback is disabled",
+                word
+            ),
+        )));
+    }
+    // only hold the model while falling back, so that concurrent searches
+    // of found words do not wait on each other
+    let keywords = {
+        let model = keyword_model().await?.lock().await;
+        let prediction = model.predict(&[word])?;
+        let keywords = prediction.into_iter().next().unwrap_or_default();
+        best_keywords(
+            keywords.into_iter().map(|x| (x.text, x.score)).collect(),
+            strategy.keyword_attempts as usize,
+        )
+    };
+    if keywords.is_empty() {
+        return Err(Box::new(SpiderError::with_kind(
+            kind,
+            &format!(
                 "failed to retry with keyword for word: {}, no keyword found",
                 word
-            ))))
+            ),
+        )));
+    }
+    for keyword in &keywords {
+        info!(target: "spanish_dict", "retry with keyword: {}", keyword);
+        if let Ok(entry) =
+            search_vocab_inner(base_url, languages, keyword).await
+        {
+            if entry.definitions.is_empty() {
+                info!(target: "spanish_dict", "failed to find any definitions for word: {}", keyword);
+            } else {
+                info!(target: "spanish_dict", "found definitions for word: {}", keyword);
+                return Ok(entry);
             }
         }
     }
 
-    Err(Box::new(SpiderError::new(&format!(
-        "failed to search for word: {}",
-        word
-    ))))
+    Err(Box::new(SpiderError::with_kind(
+        kind,
+        &format!("failed to search for word: {}", word),
+    )))
 }
 
+/// Take the `n` distinct keywords with the highest scores, best first
+fn best_keywords(mut keywords: Vec<(String, f32)>, n: usize) -> Vec<String> {
+    keywords.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let mut best: Vec<String> = Vec::with_capacity(n);
+    for (text, _) in keywords {
+        if best.len() == n {
+            break;
+        }
+        if !best.contains(&text) {
+            best.push(text);
+        }
+    }
+    best
+}
+
+/**
+Search SpanishDict.com for each of the words as [`search_vocab`] does, with at
+most `concurrency` searches in flight. The results are in the order of the
+words, with the errors flattened into [`SpiderError`]s so that they can be
+sent between tasks.
+ */
+pub async fn search_vocab_batch(
+    words: &[String],
+    concurrency: usize,
+    strategy: SearchStrategy,
+) -> Vec<(String, Result<DictionaryEntry, SpiderError>)> {
+    search_vocab_batch_with(SPANISH_DICT_URL, words, concurrency, strategy)
+        .await
+}
+
+/// Search each of the words in the SpanishDict site at `base_url` as
+/// [`search_vocab_batch`] does
+async fn search_vocab_batch_with(
+    base_url: &str,
+    words: &[String],
+    concurrency: usize,
+    strategy: SearchStrategy,
+) -> Vec<(String, Result<DictionaryEntry, SpiderError>)> {
+    stream::iter(words.to_vec())
+        .map(|word| async move {
+            let result = search_vocab_with(
+                base_url,
+                LanguagePair::default(),
+                &word,
+                strategy,
+            )
+            .await
+            .map_err(|e| SpiderError::flatten(e.as_ref()));
+            (word, result)
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Search a word in the SpanishDict site at `base_url`, following the "did
+/// you mean" suggestion if the word has no definitions
 async fn search_vocab_inner(
+    base_url: &str,
+    languages: LanguagePair,
     word: &str,
 ) -> Result<DictionaryEntry, &'static str> {
+    let html = fetch_vocab_page(base_url, languages, word).await?;
+    let entry = parse_vocab_page(languages, word, &html);
+    if !entry.definitions.is_empty() {
+        return Ok(entry);
+    }
+    let Some(suggestion) = parse_suggestion(&html) else {
+        return Ok(entry);
+    };
+    if suggestion.to_lowercase() == word.to_lowercase() {
+        return Ok(entry);
+    }
+    debug!(target: "spanish_dict", "following suggestion {} for {}", suggestion, word);
+    let html = fetch_vocab_page(base_url, languages, &suggestion).await?;
+    Ok(DictionaryEntry {
+        correction: Some(suggestion),
+        ..parse_vocab_page(languages, word, &html)
+    })
+}
+
+/// Return the address of the translation page of a word on SpanishDict
+pub fn spanish_dict_url(word: &str) -> String {
+    translate_url(SPANISH_DICT_URL, LanguagePair::default(), word)
+}
+
+/// Return the address of the translation page of a word on the SpanishDict
+/// site at `base_url`, asking for the source language unless it is Spanish
+fn translate_url(
+    base_url: &str,
+    languages: LanguagePair,
+    word: &str,
+) -> String {
     let encoded = form_urlencoded::Serializer::new(String::new())
         .append_key_only(word)
         .finish();
-    let url = format!("https://www.spanishdict.com/translate/{encoded}");
+    if languages == LanguagePair::SPANISH_ENGLISH {
+        return format!("{base_url}/translate/{encoded}");
+    }
+    format!(
+        "{base_url}/translate/{encoded}?langFrom={}",
+        languages.source
+    )
+}
+
+/// Fetch the translation page of a word from the SpanishDict site at
+/// `base_url`
+async fn fetch_vocab_page(
+    base_url: &str,
+    languages: LanguagePair,
+    word: &str,
+) -> Result<String, &'static str> {
+    let url = translate_url(base_url, languages, word);
     debug!(target: "spanish_dict", "url: {}", url);
     let html = CLIENT
         .get(&url)
         .send()
         .await
-        .expect("should be able to send request")
+        .map_err(|_| "failed to send request")?
         .text()
         .await
-        .expect("should be able to get text");
-    let dom = Html::parse_document(&html);
+        .map_err(|_| "failed to get text")?;
+    Ok(html)
+}
+
+/// The phrases introducing a suggested headword on SpanishDict, in English
+/// and in Spanish
+const SUGGESTION_PHRASES: [&str; 3] =
+    ["did you mean", "quisiste decir", "quieres decir"];
+
+/// Parse the headword suggested by a SpanishDict translation page without
+/// definitions, e.g. "Did you mean luz?"
+fn parse_suggestion(html: &str) -> Option<String> {
+    let dom = Html::parse_document(html);
+    let selector = Lazy::new(|| {
+        Selector::parse("#main-container-video a[href^='/translate/']").unwrap()
+    });
+    dom.select(&selector)
+        .find(|link| {
+            link.parent().and_then(ElementRef::wrap).is_some_and(|x| {
+                let text = x.text().collect::<String>().to_lowercase();
+                SUGGESTION_PHRASES
+                    .iter()
+                    .any(|phrase| text.contains(phrase))
+            })
+        })
+        .map(|link| textify(&link))
+        .filter(|x| !x.is_empty())
+}
+
+/// Parse the definitions of `word` from a SpanishDict translation page. The
+/// definition blocks that do not have the expected markup are skipped.
+fn parse_vocab_page(
+    languages: LanguagePair,
+    word: &str,
+    html: &str,
+) -> DictionaryEntry {
+    let dom = Html::parse_document(html);
     let selector = Lazy::new(|| {
         Selector::parse("#main-container-video div[id^=dictionary]").unwrap()
     });
+    let neodict = format!("dictionary-neodict-{}", languages.source);
+    let neoharrap = format!("dictionary-neoharrap-{}", languages.source);
     let mut definitions: Vec<_> = vec![];
 
     for dictionary in dom.select(&selector) {
-        let id = dictionary.value().attr("id").unwrap();
+        let id = dictionary.value().attr("id").unwrap_or_default();
         match id {
-            "dictionary-neodict-es" => {
-                let selector = Lazy::new(|| {
-                    Selector::parse(&format!(
-                        "div[lang] div[lang^={}]",
-                        LANG_EN
-                    ))
-                    .unwrap()
-                });
+            _ if id == neodict => {
+                let selector = Selector::parse(&format!(
+                    "div[lang] div[lang^={}]",
+                    languages.target
+                ))
+                .unwrap();
                 for group in dictionary.select(&selector) {
-                    for definition in group.next_sibling().unwrap().children() {
-                        let dom = as_dom(definition);
-
-                        let definition_text =
-                            get_text_from_selector(&dom, "a", LANG_EN);
-                        let example_text =
-                            get_text_from_selector(&dom, "span", LANG_ES);
-                        let translation_text =
-                            get_text_from_selector(&dom, "span", LANG_EN);
-
-                        let selector = Lazy::new(|| {
-                            Selector::parse("span:last-child").unwrap()
-                        });
-                        let group_text =
-                            textify(&group.select(&selector).next().unwrap());
-
-                        definitions.push(
-                            DictionaryDefinition::DefinitionAndGroupWithExample {
-                                group: group_text,
-                                definition: definition_text,
-                                examples: vec![
-                                    DictionaryExample::ExampleAndTranslation {
-                                        example: example_text,
-                                        translation: translation_text,
-                                    },
-                                ],
-                            },
-                        );
+                    let parsed = parse_neodict_group(languages, group);
+                    if parsed.is_empty() {
+                        debug!(target: "spanish_dict", "skipping malformed neodict group of {}", word);
                     }
+                    definitions.extend(parsed);
                 }
             }
-            "dictionary-neoharrap-es" => {
-                let selector = Lazy::new(|| {
-                    Selector::parse(
-                        "#dictionary-neoharrap-es > div > div > div:nth-child(2) > div",
-                    )
-                    .unwrap()
-                });
-
-                for group in ElementRef::wrap(dictionary.parent().unwrap())
-                    .unwrap()
-                    .select(&selector)
-                {
-                    let intermediate = group
-                        .children()
-                        .nth(1)
-                        .unwrap()
-                        .first_child()
-                        .unwrap()
-                        .children()
-                        .filter_map(|e| {
-                            if let Some(e) = ElementRef::wrap(e) {
-                                return Some(e);
-                            }
-                            None
-                        })
-                        .collect::<Vec<_>>();
-
-                    let definition = if intermediate.len() == 3 {
-                        textify(&intermediate[1])
-                    } else {
-                        "".to_string()
-                    };
-
-                    let group = textify(
-                        &ElementRef::wrap(
-                            group
-                                .first_child()
-                                .unwrap()
-                                .children()
-                                .nth(2)
-                                .unwrap(),
-                        )
-                        .unwrap(),
-                    );
-
-                    let example = intermediate[intermediate.len() - 1];
-                    if example.children().next().is_none() {
-                        continue;
-                    }
+            _ if id == neoharrap => {
+                let selector = Selector::parse(&format!(
+                    "#{} > div > div > div:nth-child(2) > div",
+                    neoharrap
+                ))
+                .unwrap();
+                let Some(parent) =
+                    dictionary.parent().and_then(ElementRef::wrap)
+                else {
+                    continue;
+                };
 
-                    let result = example
-                        .children()
-                        .filter_map(|e| {
-                            if let Some(e) = ElementRef::wrap(e) {
-                                let collect = e
-                                    .children()
-                                    .filter_map(|e| {
-                                        if let Some(e) = ElementRef::wrap(e) {
-                                            return Some(e);
-                                        }
-                                        None
-                                    })
-                                    .collect::<Vec<_>>();
-
-                                if collect.len() == 3 {
-                                    let example = textify(&collect[0]);
-                                    let translation = textify(&collect[2]);
-                                    return Some(
-                                        DictionaryExample::ExampleAndTranslation {
-                                            example,
-                                            translation,
-                                        },
-                                    );
-                                }
-                            }
-                            None
-                        })
-                        .collect::<Vec<_>>();
-
-                    if result.is_empty() {
-                        definitions.push(
-                            DictionaryDefinition::DefinitionAndGroup {
-                                group,
-                                definition,
-                            },
-                        );
-                    } else {
-                        definitions.push(
-                            DictionaryDefinition::DefinitionAndGroupWithExample {
-                                group,
-                                definition,
-                                examples: result,
-                            },
-                        );
+                for group in parent.select(&selector) {
+                    match parse_neoharrap_group(group) {
+                        Some(definition) => definitions.push(definition),
+                        None => {
+                            debug!(target: "spanish_dict", "skipping neoharrap group of {}", word);
+                        }
                     }
                 }
             }
@@ -287,20 +589,114 @@ async fn search_vocab_inner(
             }
         }
     }
-    Ok(DictionaryEntry {
+    DictionaryEntry {
         word: word.to_string(),
         definitions,
-    })
+        correction: None,
+    }
 }
 
-/// Wrap a NodeRef into scraper HTML to enable CSS selectors
-fn as_dom(definition: NodeRef<Node>) -> Html {
-    let dom = Html {
-        errors: vec![],
-        tree: definition.tree().to_owned(),
-        quirks_mode: QuirksMode::NoQuirks,
+/// Parse the definitions of a group of the neodict dictionary, skipping the
+/// definitions without a gloss in the target language
+fn parse_neodict_group(
+    languages: LanguagePair,
+    group: ElementRef,
+) -> Vec<DictionaryDefinition> {
+    let selector = Lazy::new(|| Selector::parse("span:last-child").unwrap());
+    let Some(group_text) = group.select(&selector).next().map(|x| textify(&x))
+    else {
+        return vec![];
     };
-    dom
+    let Some(definitions) = group.next_sibling() else {
+        return vec![];
+    };
+
+    definitions
+        .children()
+        .filter_map(ElementRef::wrap)
+        .filter_map(|definition| {
+            let definition_text =
+                get_text_from_selector(&definition, "a", languages.target)?;
+            let example_text =
+                get_text_from_selector(&definition, "span", languages.source);
+            let translation_text =
+                get_text_from_selector(&definition, "span", languages.target);
+
+            Some(match (example_text, translation_text) {
+                (Some(example), Some(translation)) => {
+                    DictionaryDefinition::DefinitionAndGroupWithExample {
+                        group: group_text.clone(),
+                        definition: definition_text,
+                        examples: vec![
+                            DictionaryExample::ExampleAndTranslation {
+                                example,
+                                translation,
+                            },
+                        ],
+                    }
+                }
+                _ => DictionaryDefinition::DefinitionAndGroup {
+                    group: group_text.clone(),
+                    definition: definition_text,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Parse a group of the neoharrap dictionary, or `None` if the group is
+/// malformed or empty
+fn parse_neoharrap_group(group: ElementRef) -> Option<DictionaryDefinition> {
+    let intermediate = group
+        .children()
+        .nth(1)?
+        .first_child()?
+        .children()
+        .filter_map(ElementRef::wrap)
+        .collect::<Vec<_>>();
+
+    let definition = if intermediate.len() == 3 {
+        textify(&intermediate[1])
+    } else {
+        "".to_string()
+    };
+
+    let group =
+        textify(&ElementRef::wrap(group.first_child()?.children().nth(2)?)?);
+
+    let example = intermediate.last()?;
+    example.children().next()?;
+
+    let result = example
+        .children()
+        .filter_map(ElementRef::wrap)
+        .filter_map(|e| {
+            let collect = e
+                .children()
+                .filter_map(ElementRef::wrap)
+                .collect::<Vec<_>>();
+
+            if collect.len() == 3 {
+                let example = textify(&collect[0]);
+                let translation = textify(&collect[2]);
+                return Some(DictionaryExample::ExampleAndTranslation {
+                    example,
+                    translation,
+                });
+            }
+            None
+        })
+        .collect::<Vec<_>>();
+
+    if result.is_empty() {
+        Some(DictionaryDefinition::DefinitionAndGroup { group, definition })
+    } else {
+        Some(DictionaryDefinition::DefinitionAndGroupWithExample {
+            group,
+            definition,
+            examples: result,
+        })
+    }
 }
 
 /// Textify a ElementRef
@@ -315,16 +711,17 @@ fn textify(element: &ElementRef) -> String {
         .to_string()
 }
 
-/// Get the text from a selector, given a language & tag of the selector
+/// Get the text of the first descendant of `element` matching a tag and a
+/// language
 fn get_text_from_selector(
-    dom: &Html,
+    element: &ElementRef,
     selector_str: &str,
     lang: &str,
-) -> String {
+) -> Option<String> {
     let selector = Lazy::new(|| {
         Selector::parse(&format!("{}[lang={}]", selector_str, lang)).unwrap()
     });
-    textify(&dom.select(&selector).next().unwrap())
+    element.select(&selector).next().map(|x| textify(&x))
 }
 
 #[cfg(test)]
@@ -333,9 +730,320 @@ mod test {
 
     #[tokio::test]
     async fn search_light() {
-        let result = search_vocab_inner("luz").await.unwrap();
+        let result = search_vocab_inner(
+            SPANISH_DICT_URL,
+            LanguagePair::default(),
+            "luz",
+        )
+        .await
+        .unwrap();
         assert_eq!(result.word, "luz");
         assert!(!result.definitions.is_empty());
         dbg!(result);
     }
+
+    #[tokio::test]
+    async fn search_batch() {
+        let mut server = mockito::Server::new_async().await;
+        let mut mocks = vec![];
+        for word in ["luz", "casa"] {
+            let mock = server
+                .mock("GET", format!("/translate/{}", word).as_str())
+                .with_body(include_str!("fixtures/spanish_dict_luz.html"))
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+        let _empty = server
+            .mock("GET", "/translate/xyzzy")
+            .with_body("<html></html>")
+            .create_async()
+            .await;
+
+        let words = ["luz", "xyzzy", "casa"].map(String::from);
+        let results = search_vocab_batch_with(
+            &server.url(),
+            &words,
+            2,
+            SearchStrategy::new(false, false),
+        )
+        .await;
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        // the results are in the order of the words, failures included
+        let words_found = results.iter().map(|(x, _)| x).collect::<Vec<_>>();
+        assert_eq!(words_found, words.iter().collect::<Vec<_>>());
+        assert_eq!(results[0].1.as_ref().unwrap().definitions.len(), 1);
+        let err = results[1].1.as_ref().unwrap_err();
+        assert_eq!(err.kind(), SpiderErrorKind::NotFound);
+        assert_eq!(results[2].1.as_ref().unwrap().word, "casa");
+    }
+
+    #[tokio::test]
+    async fn search_fixture() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/translate/luz")
+            .with_body(include_str!("fixtures/spanish_dict_luz.html"))
+            .create_async()
+            .await;
+
+        let result =
+            search_vocab_inner(&server.url(), LanguagePair::default(), "luz")
+                .await
+                .unwrap();
+        mock.assert_async().await;
+        assert_eq!(result.word, "luz");
+        assert_eq!(
+            result.to_string(),
+            "luz\n  (feminine noun) light\n    Apaga la luz. — Turn off the \
+             light."
+        );
+    }
+
+    #[tokio::test]
+    async fn search_suggestion() {
+        let mut server = mockito::Server::new_async().await;
+        let misspelled = server
+            .mock("GET", "/translate/lus")
+            .with_body(include_str!("fixtures/spanish_dict_suggestion.html"))
+            .create_async()
+            .await;
+        let suggested = server
+            .mock("GET", "/translate/luz")
+            .with_body(include_str!("fixtures/spanish_dict_luz.html"))
+            .create_async()
+            .await;
+
+        let result =
+            search_vocab_inner(&server.url(), LanguagePair::default(), "lus")
+                .await
+                .unwrap();
+        misspelled.assert_async().await;
+        suggested.assert_async().await;
+        assert_eq!(result.word, "lus");
+        assert_eq!(result.correction.as_deref(), Some("luz"));
+        assert_eq!(result.definitions.len(), 1);
+    }
+
+    fn error_kind(
+        result: Result<DictionaryEntry, Box<dyn std::error::Error>>,
+    ) -> SpiderErrorKind {
+        let err = result.unwrap_err();
+        err.downcast_ref::<SpiderError>().unwrap().kind()
+    }
+
+    #[tokio::test]
+    async fn search_strategy() {
+        assert_eq!(SearchStrategy::new(true, false), SearchStrategy::default());
+
+        let mut server = mockito::Server::new_async().await;
+        let empty = server
+            .mock("GET", "/translate/xyzzy")
+            .with_body("<html></html>")
+            .expect(3)
+            .create_async()
+            .await;
+        let strategy = SearchStrategy {
+            direct_attempts: 3,
+            ..SearchStrategy::new(false, false)
+        };
+        let languages = LanguagePair::default();
+        let result =
+            search_vocab_with(&server.url(), languages, "xyzzy", strategy)
+                .await;
+        assert_eq!(error_kind(result), SpiderErrorKind::NotFound);
+        empty.assert_async().await;
+
+        // an unreachable SpanishDict is a network failure, not a missing word
+        let result = search_vocab_with(
+            "http://127.0.0.1:9",
+            languages,
+            "xyzzy",
+            strategy,
+        )
+        .await;
+        assert_eq!(error_kind(result), SpiderErrorKind::Network);
+
+        let found = server
+            .mock("GET", "/translate/luz")
+            .with_body(include_str!("fixtures/spanish_dict_luz.html"))
+            .expect(1)
+            .create_async()
+            .await;
+        let result =
+            search_vocab_with(&server.url(), languages, "luz", strategy).await;
+        assert!(!result.unwrap().definitions.is_empty());
+        found.assert_async().await;
+    }
+
+    #[test]
+    fn test_best_keywords() {
+        let keywords =
+            [("luz", 0.4), ("casa", 0.9), ("luz", 0.6), ("sol", 0.5)]
+                .map(|(text, score)| (text.to_string(), score))
+                .to_vec();
+        assert_eq!(best_keywords(keywords.clone(), 2), ["casa", "luz"]);
+        assert_eq!(best_keywords(keywords.clone(), 5), ["casa", "luz", "sol"]);
+        assert!(best_keywords(keywords, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_offline_dictionary() {
+        let strategy = SearchStrategy {
+            offline: true,
+            ..SearchStrategy::new(false, true)
+        };
+        // no request is sent when offline, so any address does
+        let entry = search_vocab_with(
+            "http://0.0.0.0:9",
+            LanguagePair::default(),
+            "Luz",
+            strategy,
+        )
+        .await
+        .unwrap();
+        assert_eq!(entry.word, "Luz");
+        assert_eq!(
+            entry.to_string(),
+            "Luz\n  (feminine noun) light\n    Apaga la luz. — Turn off the \
+             light."
+        );
+        let entry = search_offline("perros", strategy).unwrap();
+        assert_eq!(entry.correction.as_deref(), Some("perro"));
+        assert!(search_offline("perros", SearchStrategy::default()).is_none());
+        let result = search_vocab_with(
+            "http://0.0.0.0:9",
+            LanguagePair::default(),
+            "xyzzy",
+            strategy,
+        )
+        .await;
+        assert_eq!(error_kind(result), SpiderErrorKind::NotFound);
+
+        // every bundled entry round trips through the dictionary types
+        for entry in OFFLINE_DICTIONARY.iter() {
+            assert!(!entry.definitions.is_empty());
+            let json = serde_json::to_string(entry).unwrap();
+            let parsed: DictionaryEntry = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.to_string(), entry.to_string());
+        }
+
+        // SpanishDict being unreachable falls back to the offline dictionary
+        let strategy = SearchStrategy::new(false, false);
+        let entry = search_vocab_with(
+            "http://127.0.0.1:9",
+            LanguagePair::default(),
+            "sol",
+            strategy,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            entry.to_string().lines().nth(1),
+            Some("  (masculine noun) sun")
+        );
+    }
+
+    #[test]
+    fn parse_suggestion_phrases() {
+        assert_eq!(
+            parse_suggestion(include_str!(
+                "fixtures/spanish_dict_suggestion.html"
+            )),
+            Some("luz".to_string())
+        );
+        assert_eq!(
+            parse_suggestion(include_str!("fixtures/spanish_dict_luz.html")),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_truncated_page() {
+        let page = include_str!("fixtures/spanish_dict_luz.html");
+        for end in (0..page.len()).filter(|x| page.is_char_boundary(*x)) {
+            let entry =
+                parse_vocab_page(LanguagePair::default(), "luz", &page[..end]);
+            assert_eq!(entry.word, "luz");
+        }
+
+        let truncated = include_str!("fixtures/spanish_dict_truncated.html");
+        let entry = parse_vocab_page(LanguagePair::default(), "luz", truncated);
+        assert_eq!(
+            entry.to_string(),
+            "luz\n  (feminine noun) light\n  (feminine noun) electricity"
+        );
+    }
+
+    #[test]
+    fn test_spanish_dict_url() {
+        assert_eq!(
+            spanish_dict_url("la casa"),
+            "https://www.spanishdict.com/translate/la+casa"
+        );
+        assert_eq!(
+            spanish_dict_url("niño"),
+            "https://www.spanishdict.com/translate/ni%C3%B1o"
+        );
+    }
+
+    #[test]
+    fn language_pair() {
+        let french = LanguagePair {
+            source: "fr",
+            target: "en",
+        };
+        assert_eq!(
+            translate_url(SPANISH_DICT_URL, french, "lumière"),
+            "https://www.spanishdict.com/translate/lumi%C3%A8re?langFrom=fr"
+        );
+
+        let page = "<div id=\"main-container-video\">\
+            <div id=\"dictionary-neodict-fr\"><div lang=\"fr\">\
+            <div lang=\"en\"><span>feminine noun</span></div>\
+            <div><div><a lang=\"en\">light</a>\
+            <span lang=\"fr\">Éteins la lumière.</span>\
+            <span lang=\"en\">Turn off the light.</span></div></div>\
+            </div></div></div>";
+        let entry = parse_vocab_page(french, "lumière", page);
+        assert_eq!(
+            entry.to_string(),
+            "lumière\n  (feminine noun) light\n    Éteins la lumière. — Turn \
+             off the light."
+        );
+        assert!(parse_vocab_page(LanguagePair::default(), "lumière", page)
+            .definitions
+            .is_empty());
+    }
+
+    #[test]
+    fn display_entry() {
+        let entry = DictionaryEntry {
+            word: "luz".to_string(),
+            definitions: vec![
+                DictionaryDefinition::DefinitionAndGroupWithExample {
+                    group: "feminine noun".to_string(),
+                    definition: "light".to_string(),
+                    examples: vec![DictionaryExample::ExampleAndTranslation {
+                        example: "Apaga la luz.".to_string(),
+                        translation: "Turn off the light.".to_string(),
+                    }],
+                },
+                DictionaryDefinition::Definition {
+                    definition: "electricity".to_string(),
+                },
+            ],
+            correction: None,
+        };
+        assert_eq!(
+            entry.to_string(),
+            "luz\n  (feminine noun) light\n    Apaga la luz. — Turn off the \
+             light.\n  electricity"
+        );
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["definitions"][1]["definition"], "electricity");
+        assert!(json.get("correction").is_none());
+    }
 }
@@ -3,6 +3,9 @@
 #let fontsize = <FONT_SIZE>
 
 #set page(margin: 0.5in)
+// long content wraps within the cards, breaking words when needed
+#set text(hyphenate: true)
+#set par(justify: false)
 #let card = rect.with(
     inset: 8pt,
     stroke: (
@@ -13,7 +16,7 @@
     height: 100%,
 )
 #let front_counter = counter("front")
-#let front(content) = {
+#let front(content, scale: 1) = {
     front_counter.step()
     card[
         #place(
@@ -26,20 +29,29 @@
         #align(center + horizon)[
             #text(
                 fill: rgb("#0a0a0a"), 
-                size: fontsize,
+                size: fontsize * scale,
                 content
             )
         ]
     ]
 }
-#let back(content) = {
+#let back(content, note: none, scale: 1) = {
     card[
         #align(center + horizon)[
             #text(
                 fill: rgb("#737373"),
-                size: fontsize,
+                size: fontsize * scale,
                 content
             )
+            #if note != none {
+                linebreak()
+                text(
+                    fill: rgb("#a3a3a3"),
+                    size: fontsize * scale * 0.75,
+                    style: "italic",
+                    note
+                )
+            }
         ]
     ]
 }
@@ -48,3 +60,7 @@
     rows: range(0, row).map(i => 1fr)
 )
 
+#let section(title) = block(
+    below: 8pt,
+    text(size: fontsize * 1.5, weight: "bold", fill: rgb("#0a0a0a"), title)
+)
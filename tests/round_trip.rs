@@ -0,0 +1,106 @@
+//! Round trips of a deck through the load and transform pipelines
+
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use spanish_pipeline::pipeline::{
+    load::{LoadPipeline, VocabFileType},
+    transform::{TransformOutputType, TransformPipeline},
+    Flashcard, Pipeline, PipelineIO,
+};
+
+/// The path of a file under tests/fixtures
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
+
+/// Load a deck through the load pipeline
+async fn load(path: PathBuf) -> Vec<Flashcard> {
+    let output = LoadPipeline::new(Some(path), None).run(None).await.unwrap();
+    let PipelineIO::Flashcard(flashcards) = output else {
+        panic!("should have loaded flashcards");
+    };
+    flashcards
+}
+
+/// Transform a deck, returning the name and content of the document made
+async fn transform(
+    output_type: TransformOutputType,
+    flashcards: Vec<Flashcard>,
+) -> (String, Vec<u8>) {
+    let pipeline = TransformPipeline::new(None, output_type, 6, 2, "14pt");
+    let input = Some(PipelineIO::Flashcard(flashcards));
+    // a pdf needs typst, so only its plan is checked
+    let output = if output_type == TransformOutputType::Pdf {
+        pipeline.dry_run(input).await
+    } else {
+        pipeline.run(input).await
+    };
+    let PipelineIO::Document { name, content } = output.unwrap() else {
+        panic!("should have made a document");
+    };
+    (name, content)
+}
+
+#[tokio::test]
+async fn test_round_trip() {
+    let deck = load(fixture("deck.yml")).await;
+    assert_eq!(deck.len(), 6);
+    assert_eq!(deck[2].word, "¿Qué tal?");
+    assert_eq!(deck[4].definition, "he said \"hi\"");
+    assert_eq!(deck[5].definition, "");
+
+    let dir = tempfile::tempdir().unwrap();
+    for output_type in TransformOutputType::value_variants() {
+        let (name, content) = transform(*output_type, deck.clone()).await;
+        match output_type {
+            TransformOutputType::Yaml | TransformOutputType::Json => {
+                let path = dir.path().join(&name);
+                std::fs::write(&path, content).unwrap();
+                assert_eq!(load(path).await, deck, "{} round trip", name);
+            }
+            TransformOutputType::Pdf => assert_eq!(name, "flashcard.pdf"),
+            TransformOutputType::Xlsx => {
+                assert_eq!(name, "flashcard.xlsx");
+                assert!(content.starts_with(b"PK"));
+            }
+            TransformOutputType::Csv | TransformOutputType::Tsv => {
+                let content = String::from_utf8(content).unwrap();
+                assert_eq!(content.lines().count(), deck.len());
+            }
+            TransformOutputType::Html => {
+                assert_eq!(name, "flashcard.html");
+                let content = String::from_utf8(content).unwrap();
+                assert_eq!(content.matches("<tr><td>").count(), deck.len());
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_serialization_shape() {
+    let deck = vec![Flashcard::new("la casa", "the house")];
+
+    // each flashcard is a [word, definition] pair
+    let (_, yaml) = transform(TransformOutputType::Yaml, deck.clone()).await;
+    assert_eq!(
+        String::from_utf8(yaml).unwrap(),
+        "- - la casa\n  - the house\n"
+    );
+    let (_, json) = transform(TransformOutputType::Json, deck).await;
+    assert_eq!(
+        String::from_utf8(json).unwrap(),
+        r#"[["la casa","the house"]]"#
+    );
+
+    // the file type can also be given explicitly
+    let output =
+        LoadPipeline::new(Some(fixture("deck.yml")), Some(VocabFileType::Yaml))
+            .run(None)
+            .await
+            .unwrap();
+    assert!(matches!(output, PipelineIO::Flashcard(x) if x.len() == 6));
+}